@@ -2,7 +2,7 @@
     let INSERT = Symbol("insert");
     let DELETE = Symbol("delete");
 
-    let socket = new WebSocket("ws://" + window.location.host + "/ws");
+    let socket = new WebSocket("ws://" + window.location.host + "/ws", "avian.v1");
 
     let footer = document.querySelector("footer");
     let editor = document.getElementById("editor");
@@ -10,7 +10,8 @@
     let text = "";
     let rev = 0;
     let init = false;
-    let myEdit = false;
+    // author id of our edit that is waiting to be broadcast, if any
+    let myEdit = null;
     let queue = [];
 
     function setStatus(status, editable) {
@@ -27,27 +28,48 @@
     socket.onmessage = function (event) {
         setStatus("online", true);
         if (!init) {
-            [rev, text] = JSON.parse(event.data);
+            let status = JSON.parse(event.data);
+            rev = status.rev;
+            text = status.buffer;
             editor.value = text;
             init = true;
             queueReady();
-        } else if (myEdit) {
-            rev = JSON.parse(event.data).rev;
-            queueReady();
-            myEdit = false;
         } else {
             let msg = JSON.parse(event.data);
-            if (msg.success === true) {
-                myEdit = true;
+            if (Array.isArray(msg)) {
+                // edits batched by the server, in order
+                msg.forEach(receiveEdit);
+            } else if (msg.success === true) {
+                myEdit = msg.author;
+            } else if (msg.type === "resync") {
+                // the full document, sent instead of edits because we lagged behind
+                rev = msg.rev;
+                text = msg.buffer;
+                editor.value = text;
+                myEdit = null;
+                // queued edits refer to the replaced text, so they are dropped like the local changes
+                queue.length = 0;
+                queueReady();
             } else if (msg.success === false) {
                 setStatus("desync (" + msg.reason + ")", false);
                 socket.onmessage = console.log;
             } else {
-                applyEdit(msg);
+                receiveEdit(msg);
             }
         }
     };
 
+    function receiveEdit(edit) {
+        if (myEdit !== null && edit.author === myEdit) {
+            // already applied locally, only the revision is new
+            rev = edit.rev;
+            queueReady();
+            myEdit = null;
+        } else {
+            applyEdit(edit);
+        }
+    }
+
     socket.onopen = function () {
         setStatus("waiting for data");
     };
@@ -114,31 +136,49 @@
     }
 
     function applyEdit(edit) {
+        if (edit.action.InsertMany !== undefined) {
+            // going backwards keeps the positions of the remaining inserts valid
+            edit.action.InsertMany.slice().reverse().forEach(function ([pos, ins]) {
+                applyAction(pos, {Insert: ins});
+            });
+        } else if (edit.action.Replace !== undefined) {
+            // the text is inserted first, like the server records it
+            let [len, ins] = edit.action.Replace;
+            applyAction(edit.pos, {Insert: ins});
+            if (len > 0) applyAction(edit.pos + countUtf8Bytes(ins), {Delete: len});
+        } else {
+            applyAction(edit.pos, edit.action);
+        }
+        rev = edit.rev;
+        queueReady();
+    }
+
+    function applyAction(pos, action) {
         let unicode = toUTF8(text);
         let selStart = editor.selectionStart, selEnd = editor.selectionEnd;
-        let preText = fromUTF8(unicode.substr(0, edit.pos));
+        let preText = fromUTF8(unicode.substr(0, pos));
         let oldI, newI;
-        if (edit.action.Insert !== undefined) {
-            oldI = edit.pos;
-            newI = edit.pos + countUtf8Bytes(edit.action.Insert);
-            text = preText + fromUTF8(toUTF8(edit.action.Insert) + unicode.substr(edit.pos));
+        if (action.Insert !== undefined) {
+            oldI = pos;
+            newI = pos + countUtf8Bytes(action.Insert);
+            text = preText + fromUTF8(toUTF8(action.Insert) + unicode.substr(pos));
             if (preText.length < selStart) {
-                selStart += edit.action.Insert.length;
+                selStart += action.Insert.length;
             }
             if (preText.length < selEnd) {
-                selEnd += edit.action.Insert.length;
+                selEnd += action.Insert.length;
             }
         } else {
-            oldI = edit.pos + edit.action.Delete;
-            newI = edit.pos;
-            text = preText + fromUTF8(unicode.substr(edit.pos + edit.action.Delete));
-            if (preText.length + edit.action.Delete < selStart) {
-                selStart -= edit.action.Delete;
+            oldI = pos + action.Delete;
+            newI = pos;
+            text = preText + fromUTF8(unicode.substr(pos + action.Delete));
+            if (preText.length + action.Delete < selStart) {
+                selStart -= action.Delete;
             } else if (preText.length < selStart) {
                 selStart = preText.length;
             }
-            if (preText.length + edit.action.Delete < selEnd) {
-                selEnd -= edit.action.Delete;
+            if (preText.length + action.Delete < selEnd) {
+                selEnd -= action.Delete;
             } else if (preText.length < selEnd) {
                 selEnd = preText.length;
             }
@@ -146,9 +186,7 @@
         editor.value = text;
         editor.selectionStart = selStart;
         editor.selectionEnd = selEnd;
-        rev = edit.rev;
         queue.forEach(transformEdit.bind(oldI, newI));
-        queueReady();
     }
 
     let lastEvent = null;
@@ -1,12 +1,33 @@
+// ws handlers return ws::Result, whose foreign error type is large; boxing it is not an option.
+#![allow(clippy::result_large_err)]
+
 extern crate avian;
 #[macro_use]
 extern crate clap;
+extern crate rand;
 extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
 extern crate serde_json;
+extern crate url;
 extern crate ws;
 
-use avian::{Edit, EditAction};
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, Instant};
+
+use avian::{
+    apply_remote_edit, checksum, diff, ConnectStatus, Edit, EditAction, EditError, PieceTable,
+};
 use clap::{App, AppSettings, Arg, SubCommand};
+use rand::Rng;
 use serde_json::Value as Json;
 
 fn main() {
@@ -22,8 +43,11 @@ fn main() {
                     .help("Byte position at which to insert")
                     .required(true))
                 .arg(Arg::with_name("text")
-                    .help("Text to insert")
-                    .required(true)))
+                    .help("Text to insert, or - to read it from stdin")
+                    .required(true))
+                .arg(Arg::with_name("once")
+                    .long("once")
+                    .help("Print the resulting document once the edit is applied")))
             .subcommand(SubCommand::with_name("delete")
                 .alias("d")
                 .arg(Arg::with_name("position")
@@ -31,23 +55,95 @@ fn main() {
                     .required(true))
                 .arg(Arg::with_name("length")
                     .help("Number of bytes to delete")
-                    .required(true)))
+                    .required(true))
+                .arg(Arg::with_name("once")
+                    .long("once")
+                    .help("Print the resulting document once the edit is applied")))
             .subcommand(SubCommand::with_name("read")
-                .alias("r"))
+                .alias("r")
+                .arg(Arg::with_name("pretty")
+                    .long("pretty")
+                    .short("p")
+                    .help("Show line numbers and the size of the document"))
+                .arg(Arg::with_name("since")
+                    .long("since")
+                    .help("Only print the edits applied after this revision")
+                    .takes_value(true)
+                    .conflicts_with("pretty")))
+            .subcommand(SubCommand::with_name("ping")
+                .arg(Arg::with_name("count")
+                    .long("count")
+                    .short("c")
+                    .help("Number of pings to send")
+                    .takes_value(true)
+                    .default_value("4")))
             .subcommand(SubCommand::with_name("wait")
-                .alias("w"))
+                .alias("w")
+                .arg(Arg::with_name("context")
+                    .long("context")
+                    .short("c")
+                    .help("Show the changed line for every edit"))
+                .arg(Arg::with_name("until")
+                    .long("until")
+                    .short("u")
+                    .help("Exit once the document reaches this revision")
+                    .takes_value(true))
+                .arg(Arg::with_name("timeout")
+                    .long("timeout")
+                    .short("t")
+                    .help("Seconds to wait for --until before failing")
+                    .takes_value(true)
+                    .requires("until"))
+                .arg(Arg::with_name("log-edits")
+                    .long("log-edits")
+                    .help("Write edits as JSON lines to this file (- for stdout) instead")
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("verify")
+                .about("Checks the received text against the server's checksum"))
+            .subcommand(SubCommand::with_name("echo")
+                .about("Sends a fixed sequence of edits and checks that the server applied them \
+                        correctly. Nobody else may edit in the meantime"))
+            .subcommand(SubCommand::with_name("watch")
+                .about("Uploads changes of a local file to the document. Nobody else may edit in \
+                        the meantime")
+                .arg(Arg::with_name("path")
+                    .help("File to watch")
+                    .required(true))
+                .arg(Arg::with_name("interval")
+                    .long("interval")
+                    .value_name("ms")
+                    .help("How often to check the file for changes")
+                    .default_value("500"))
+                .arg(Arg::with_name("debounce")
+                    .long("debounce")
+                    .value_name("ms")
+                    .help("Only uploads a change once the file stayed the same for this long")
+                    .default_value("1000")))
+            .subcommand(SubCommand::with_name("stress")
+                .about("Opens several connections that each send random edits, and reports the \
+                        throughput and rejected edits")
+                .arg(Arg::with_name("clients")
+                    .long("clients")
+                    .short("c")
+                    .help("Number of connections")
+                    .takes_value(true)
+                    .default_value("10"))
+                .arg(Arg::with_name("edits")
+                    .long("edits")
+                    .short("e")
+                    .help("Number of edits each connection sends")
+                    .takes_value(true)
+                    .default_value("100")))
             .arg(Arg::with_name("host")
                 .long("host")
                 .short("H")
-                .help("Hostname of the server")
-                .takes_value(true)
-                .default_value("localhost"))
+                .help("Hostname of the server [default: localhost]")
+                .takes_value(true))
             .arg(Arg::with_name("port")
                 .long("port")
                 .short("p")
-                .help("Port of the server")
-                .takes_value(true)
-                .default_value("8080"))
+                .help("Port of the server [default: 8080]")
+                .takes_value(true))
             .arg(Arg::with_name("secure")
                 .long("secure")
                 .short("s")
@@ -55,32 +151,75 @@ fn main() {
             .arg(Arg::with_name("revisions")
                 .long("rev")
                 .short("r")
-                .help("Show revision numbers received"))
+                .help("Show revision numbers received, for --format text"))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .help("Output format of read and wait")
+                .takes_value(true)
+                .possible_values(&["text", "json", "ndjson"])
+                .default_value("text"))
+            .arg(Arg::with_name("pretty-json")
+                .long("pretty-json")
+                .help("Indents the output of --format json"))
+            .arg(Arg::with_name("quiet")
+                .long("quiet")
+                .short("q")
+                .help("Only print the requested result, no status messages"))
+            .arg(Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Show the edit insert or delete would send and the text it was checked \
+                       against, without sending it"))
+            .arg(Arg::with_name("config")
+                .long("config")
+                .help("Config file with connection defaults [default: ~/.config/avian/client.json]")
+                .takes_value(true))
             .get_matches()
     };
 
-    let protocol = if matches.is_present("secure") {
-        "wss"
-    } else {
-        "ws"
+    let config = Config::load(matches.value_of("config")).unwrap_or_else(|err| {
+        eprintln!("Could not load config file: {}", err);
+        process::exit(1);
+    });
+    let secure = setting(
+        Some(true).filter(|_| matches.is_present("secure")),
+        config.secure,
+        false,
+    );
+    let protocol = if secure { "wss" } else { "ws" };
+    let output = Output {
+        show_rev: matches.is_present("revisions"),
+        quiet: matches.is_present("quiet"),
+        format: Format::parse(matches.value_of("format").unwrap()).unwrap(),
+        pretty_json: matches.is_present("pretty-json"),
     };
-    let show_rev = matches.is_present("revisions");
-    let host = matches.value_of("host").unwrap();
-    let port = matches.value_of("port").unwrap();
+    let host = setting(
+        matches.value_of("host").map(str::to_string),
+        config.host,
+        "localhost".to_string(),
+    );
+    let port = setting(
+        matches.value_of("port").map(str::to_string),
+        config.port.map(|port| port.to_string()),
+        "8080".to_string(),
+    );
     let url = format!("{}://{}:{}/ws", protocol, host, port);
+    let dry_run = matches.is_present("dry-run");
 
     match matches.subcommand_name().unwrap() {
         "read" => {
+            let sub_matches = matches.subcommand_matches("read").unwrap();
+            if let Some(since) = sub_matches.value_of("since") {
+                let since = since.parse::<u32>().expect("revision must be a number");
+                read_since(&url, output, since);
+                return;
+            }
+            let pretty = sub_matches.is_present("pretty");
             ws::connect(url, |out| {
-                move |msg: ws::Message| {
-                    let (rev, buffer) = serde_json::from_str::<(u32, String)>(msg.as_text()?)
-                        .expect("TODO: graceful shutdown.");
-                    if show_rev {
-                        println!("Rev {}", rev);
-                    }
-                    println!("{}", buffer);
+                Versioned(move |msg: ws::Message| {
+                    let ConnectStatus { rev, buffer, .. } = connect_status(msg.as_text()?);
+                    println!("{}", read_output(output, pretty, rev, &buffer));
                     out.close(ws::CloseCode::Normal)
-                }
+                })
             }).unwrap();
         }
         "insert" => {
@@ -90,13 +229,16 @@ fn main() {
                 .unwrap()
                 .parse::<usize>()
                 .expect("position must be a number");
-            let text = sub_matches.value_of("text").unwrap();
+            let action = insert_action(sub_matches.value_of("text").unwrap(), io::stdin())
+                .expect("failed to read text from stdin");
             ws::connect(url, move |out| ActionClient {
-                show_rev,
+                output,
                 out,
                 pos,
-                action: EditAction::Insert(text.to_string()),
+                action: action.clone(),
                 init_received: false,
+                dry_run,
+                once: sub_matches.is_present("once"),
             }).unwrap();
         }
         "delete" => {
@@ -112,37 +254,575 @@ fn main() {
                 .parse::<usize>()
                 .expect("length must be a number");
             ws::connect(url, |out| ActionClient {
-                show_rev,
+                output,
                 out,
                 pos,
                 action: EditAction::Delete(len),
                 init_received: false,
+                dry_run,
+                once: sub_matches.is_present("once"),
             }).unwrap();
         }
         "wait" => {
-            ws::connect(url, |_| WaitClient {
-                show_rev,
+            let sub_matches = matches.subcommand_matches("wait").unwrap();
+            // changed lines would break up the JSON output
+            let context = sub_matches.is_present("context") && output.format == Format::Text;
+            let until = sub_matches
+                .value_of("until")
+                .map(|rev| rev.parse::<u32>().expect("revision must be a number"));
+            let timeout = sub_matches
+                .value_of("timeout")
+                .map(|secs| secs.parse::<u64>().expect("timeout must be a number"));
+            let mut log = sub_matches
+                .value_of("log-edits")
+                .map(|path| -> Box<dyn Write> {
+                    if path == "-" {
+                        Box::new(io::stdout())
+                    } else {
+                        let file = OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open(path)
+                            .expect("could not open edit log");
+                        Box::new(file)
+                    }
+                });
+            ws::connect(url, |out| WaitClient {
+                output,
+                out,
                 init_received: false,
+                context,
+                mirror: PieceTable::new(),
+                until,
+                timeout,
+                log: log.take(),
+                document: None,
+                seq: None,
+            }).unwrap();
+        }
+        "ping" => {
+            let sub_matches = matches.subcommand_matches("ping").unwrap();
+            let count = sub_matches
+                .value_of("count")
+                .unwrap()
+                .parse::<u32>()
+                .expect("count must be a number");
+            ws::connect(url, |out| PingClient {
+                out,
+                count,
+                sent: None,
+                pings: Vec::new(),
+            }).unwrap();
+        }
+        "verify" => {
+            let status = RefCell::new(None);
+            ws::connect(url, |out| {
+                let status = &status;
+                Versioned(move |msg: ws::Message| {
+                    let received = serde_json::from_str::<ConnectStatus>(msg.as_text()?)
+                        .expect("TODO: graceful shutdown.");
+                    *status.borrow_mut() = Some(received);
+                    out.close(ws::CloseCode::Normal)
+                })
+            }).unwrap();
+            let status = status.into_inner().expect("connection closed early");
+            match verify_output(output, &status) {
+                Ok(Some(report)) => println!("{}", report),
+                Ok(None) => {}
+                Err(report) => {
+                    eprintln!("{}", report);
+                    process::exit(1);
+                }
+            }
+        }
+        "echo" => {
+            if dry_run {
+                eprintln!("echo does not support --dry-run.");
+                process::exit(1);
+            }
+            let expected = RefCell::new(None);
+            ws::connect(url.as_str(), |out| EchoClient {
+                out,
+                edits: echo_edits(),
+                next: 0,
+                rev: None,
+                author: None,
+                expected: &expected,
+            }).unwrap();
+            let actual = RefCell::new(None);
+            ws::connect(url.as_str(), |out| {
+                let actual = &actual;
+                Versioned(move |msg: ws::Message| {
+                    let ConnectStatus { buffer, .. } = connect_status(msg.as_text()?);
+                    *actual.borrow_mut() = Some(buffer);
+                    out.close(ws::CloseCode::Normal)
+                })
+            }).unwrap();
+            let expected = expected.into_inner().expect("connection closed early");
+            let actual = actual.into_inner().expect("connection closed early");
+            match echo_output(output, &expected, &actual) {
+                Ok(Some(message)) => println!("{}", message),
+                Ok(None) => {}
+                Err(mismatch) => {
+                    eprintln!("{}", mismatch);
+                    process::exit(1);
+                }
+            }
+        }
+        "watch" => {
+            if dry_run {
+                eprintln!("watch does not support --dry-run.");
+                process::exit(1);
+            }
+            let sub_matches = matches.subcommand_matches("watch").unwrap();
+            let path = PathBuf::from(sub_matches.value_of("path").unwrap());
+            let interval = sub_matches
+                .value_of("interval")
+                .unwrap()
+                .parse::<u64>()
+                .expect("interval must be a number");
+            let debounce = sub_matches
+                .value_of("debounce")
+                .unwrap()
+                .parse::<u64>()
+                .expect("debounce must be a number");
+            ws::connect(url, |out| WatchClient {
+                output,
+                out,
+                path: path.clone(),
+                interval,
+                debounce: Duration::from_millis(debounce),
+                upload: None,
+                rev: None,
+                queue: Vec::new(),
+                author: None,
+                in_flight: false,
             }).unwrap();
         }
+        "stress" => {
+            if dry_run {
+                eprintln!("stress does not support --dry-run.");
+                process::exit(1);
+            }
+            let sub_matches = matches.subcommand_matches("stress").unwrap();
+            let clients = sub_matches
+                .value_of("clients")
+                .unwrap()
+                .parse::<u32>()
+                .expect("clients must be a number");
+            let edits = sub_matches
+                .value_of("edits")
+                .unwrap()
+                .parse::<u32>()
+                .expect("edits must be a number");
+            let url = url::Url::parse(&url).expect("invalid server address");
+            let stats = StressStats::default();
+            let settings = ws::Settings {
+                max_connections: cmp::max(clients as usize, 1),
+                ..ws::Settings::default()
+            };
+            let start = Instant::now();
+            {
+                let mut socket = ws::Builder::new()
+                    .with_settings(settings)
+                    .build(|out| StressClient {
+                        out,
+                        remaining: edits,
+                        mirror: None,
+                        rng: rand::thread_rng(),
+                        stats: &stats,
+                    }).unwrap();
+                for _ in 0..clients {
+                    socket.connect(url.clone()).unwrap();
+                }
+                socket.run().unwrap();
+            }
+            println!("{}", stress_report(&stats, start.elapsed()));
+        }
         _ => panic!("Unknown subcommand not handled by clap."),
     }
 }
 
-struct ActionClient {
+/// Connection defaults read from the config file. Every field is optional; flags given on the
+/// command line take precedence.
+#[derive(Deserialize, Default)]
+struct Config {
+    host: Option<String>,
+    port: Option<u16>,
+    secure: Option<bool>,
+}
+
+impl Config {
+    /// Loads the config from `path`, or from `~/.config/avian/client.json` if no path is given.
+    /// A missing default config file is not an error.
+    fn load(path: Option<&str>) -> Result<Config, String> {
+        let (path, required) = match path {
+            Some(path) => (PathBuf::from(path), true),
+            None => match env::var_os("HOME") {
+                Some(home) => (PathBuf::from(home).join(".config/avian/client.json"), false),
+                None => return Ok(Config::default()),
+            },
+        };
+        match fs::read_to_string(&path) {
+            Ok(content) => {
+                Config::parse(&content).map_err(|e| format!("{}: {}", path.display(), e))
+            }
+            Err(ref e) if !required && e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    fn parse(content: &str) -> Result<Config, serde_json::Error> {
+        serde_json::from_str(content)
+    }
+}
+
+/// Picks a setting: a command line flag beats the config file, which beats the built-in default.
+fn setting<T>(cli: Option<T>, config: Option<T>, default: T) -> T {
+    cli.or(config).unwrap_or(default)
+}
+
+/// Output settings shared by all subcommands. Decides which of the non-essential messages get
+/// printed; the requested result itself (e.g. the buffer for `read`) and errors are always shown.
+#[derive(Clone, Copy)]
+struct Output {
     show_rev: bool,
+    quiet: bool,
+    format: Format,
+    /// Indent `Format::Json` output.
+    pretty_json: bool,
+}
+
+/// How `read` and `wait` print what they received.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    /// Human-readable, details depending on the other output options.
+    Text,
+    /// A single JSON document, indented with `--pretty-json`.
+    Json,
+    /// One compact JSON object per line.
+    Ndjson,
+}
+
+impl Format {
+    fn parse(name: &str) -> Option<Format> {
+        match name {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            "ndjson" => Some(Format::Ndjson),
+            _ => None,
+        }
+    }
+}
+
+impl Output {
+    /// Whether to print received revision numbers.
+    fn rev(&self) -> bool {
+        self.show_rev && !self.quiet
+    }
+
+    /// Whether to print status messages, like the buffer size in `wait`.
+    fn status(&self) -> bool {
+        !self.quiet
+    }
+
+    /// Formats a JSON document for `Format::Json`.
+    fn json(&self, document: &Json) -> String {
+        if self.pretty_json {
+            serde_json::to_string_pretty(document).unwrap()
+        } else {
+            document.to_string()
+        }
+    }
+}
+
+/// Exit code of `read --since` if the server no longer has the requested edits.
+const RESYNC_EXIT: i32 = 3;
+
+/// Implements `read --since`: asks the server for the edits after revision *since* and prints
+/// them. Exits with `RESYNC_EXIT` if the client needs to read the full buffer instead.
+fn read_since(url: &str, output: Output, since: u32) {
+    let response = RefCell::new(None);
+    ws::connect(url, |out| {
+        let response = &response;
+        let init_received = Cell::new(false);
+        Versioned(move |msg: ws::Message| {
+            if !init_received.replace(true) {
+                return out.send(since_request(since));
+            }
+            let json =
+                serde_json::from_str::<Json>(msg.as_text()?).expect("TODO: graceful shutdown.");
+            // Edits broadcast until then come after the requested ones, so they are skipped.
+            if json["type"] == "edits" {
+                *response.borrow_mut() = Some(json);
+                out.close(ws::CloseCode::Normal)?;
+            }
+            Ok(())
+        })
+    }).unwrap();
+    let response = response.into_inner().expect("connection closed early");
+    match since_edits(&response) {
+        Some(edits) => println!("{}", since_output(output, since, &edits)),
+        None => {
+            eprintln!("Revision {} is no longer available, must resync.", since);
+            process::exit(RESYNC_EXIT);
+        }
+    }
+}
+
+/// The request for `read --since`.
+fn since_request(rev: u32) -> String {
+    json!({"type": "edits_since", "rev": rev}).to_string()
+}
+
+/// Extracts the edits from the server's answer to `since_request`, `None` if it asks for a
+/// resync.
+fn since_edits(response: &Json) -> Option<Vec<Edit>> {
+    if response["resync"] == true {
+        return None;
+    }
+    serde_json::from_value(response["edits"].clone()).ok()
+}
+
+/// The edits as `read --since` prints them.
+fn since_output(output: Output, since: u32, edits: &[Edit]) -> String {
+    match output.format {
+        Format::Json => output.json(&json!({"rev": since, "edits": edits})),
+        Format::Ndjson | Format::Text => {
+            let lines: Vec<_> = edits
+                .iter()
+                .map(|edit| match output.format {
+                    Format::Text if output.rev() => {
+                        format!("Rev {}: {}", edit.rev, edit_text(edit.pos, &edit.action))
+                    }
+                    Format::Text => edit_text(edit.pos, &edit.action),
+                    _ => serde_json::to_string(edit).unwrap(),
+                })
+                .collect();
+            lines.join("\n")
+        }
+    }
+}
+
+/// The document as `read` prints it. JSON formats always include the revision and ignore
+/// *pretty*.
+fn read_output(output: Output, pretty: bool, rev: u32, buffer: &str) -> String {
+    let document = json!({"rev": rev, "buffer": buffer});
+    match output.format {
+        Format::Json => output.json(&document),
+        Format::Ndjson => document.to_string(),
+        Format::Text => {
+            let text = if pretty {
+                pretty_buffer(buffer)
+            } else {
+                buffer.to_string()
+            };
+            if output.rev() {
+                format!("Rev {}\n{}", rev, text)
+            } else {
+                text
+            }
+        }
+    }
+}
+
+/// Parses the status the server sends on connect. Warns if the buffer does not match the
+/// server's checksum, meaning it was corrupted on the way.
+fn connect_status(text: &str) -> ConnectStatus {
+    let status = serde_json::from_str::<ConnectStatus>(text).expect("TODO: graceful shutdown.");
+    if !checksum_matches(&status) {
+        eprintln!("Warning: buffer does not match the server's checksum.");
+    }
+    status
+}
+
+fn checksum_matches(status: &ConnectStatus) -> bool {
+    checksum(status.buffer.bytes()) == status.checksum
+}
+
+/// Compares the server's checksum with one computed over the received text, for `verify`.
+fn verify_report(status: &ConnectStatus) -> Result<String, String> {
+    let local = checksum(status.buffer.bytes());
+    if local == status.checksum {
+        Ok(format!(
+            "Checksum {:016x} matches, {} bytes at revision {}.",
+            local,
+            status.buffer.len(),
+            status.rev
+        ))
+    } else {
+        Err(format!(
+            "Checksum mismatch: server {:016x}, local {:016x}.",
+            status.checksum, local
+        ))
+    }
+}
+
+/// What `verify` prints: the report if the checksums match, which `--quiet` hides, or the
+/// mismatch as the error.
+fn verify_output(output: Output, status: &ConnectStatus) -> Result<Option<String>, String> {
+    verify_report(status).map(|report| Some(report).filter(|_| output.status()))
+}
+
+/// Compares the document the `echo` edits produced on the server with the expected one. Returns
+/// the message for a passed check, unless `--quiet`, or the mismatch as the error.
+fn echo_output(output: Output, expected: &str, actual: &str) -> Result<Option<String>, String> {
+    if actual != expected {
+        return Err(format!(
+            "Mismatch. Expected:\n{}\nActual:\n{}",
+            expected, actual
+        ));
+    }
+    Ok(Some("Echo check passed.".to_string()).filter(|_| output.status()))
+}
+
+/// The message `watch` prints when it uploads a change of the file at *path*, unless `--quiet`.
+fn upload_status(output: Output, path: &Path) -> Option<String> {
+    Some(format!("Uploading a change of {}.", path.display())).filter(|_| output.status())
+}
+
+/// What `wait --format text` prints for the document it received: the revision if requested, the
+/// size unless `--quiet`, and the buffer.
+fn wait_text(output: Output, rev: u32, buffer: &str) -> String {
+    let mut lines = Vec::new();
+    if output.rev() {
+        lines.push(format!("Rev {}", rev));
+    }
+    if output.status() {
+        lines.push(format!("Text: {} bytes.", buffer.len()));
+    }
+    lines.push(buffer.to_string());
+    lines.join("\n")
+}
+
+/// Formats a buffer with line numbers, followed by its size. Lines are counted like
+/// `PieceTable::line_count` does, so a trailing newline starts an empty last line.
+fn pretty_buffer(buffer: &str) -> String {
+    let line_count = PieceTable::from(buffer).line_count();
+    let width = line_count.to_string().len();
+    let lines: Vec<String> = buffer
+        .split('\n')
+        .enumerate()
+        .map(|(i, line)| format!("{:>width$} | {}", i + 1, line, width = width))
+        .collect();
+    format!(
+        "{}\n{} bytes, {} line{}",
+        lines.join("\n"),
+        buffer.len(),
+        line_count,
+        if line_count == 1 { "" } else { "s" }
+    )
+}
+
+/// Builds the handshake request, asking for the protocol version this client speaks.
+fn versioned_request(url: &url::Url) -> ws::Result<ws::Request> {
+    let mut req = ws::Request::from_url(url)?;
+    req.add_protocol(avian::PROTOCOL);
+    Ok(req)
+}
+
+/// Handles each message with a closure, like the handler `ws` provides for closures, but asks
+/// for the protocol version in the handshake like the other clients.
+struct Versioned<F>(F);
+
+impl<F: FnMut(ws::Message) -> ws::Result<()>> ws::Handler for Versioned<F> {
+    fn build_request(&mut self, url: &url::Url) -> ws::Result<ws::Request> {
+        versioned_request(url)
+    }
+
+    fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+        (self.0)(msg)
+    }
+}
+
+/// Builds the insert action for the given text argument. An argument of `-` reads the text from
+/// `stdin` instead, keeping it byte-for-byte (including any trailing newline).
+fn insert_action<R: Read>(text: &str, mut stdin: R) -> io::Result<EditAction> {
+    if text == "-" {
+        let mut buf = String::new();
+        stdin.read_to_string(&mut buf)?;
+        Ok(EditAction::Insert(buf))
+    } else {
+        Ok(EditAction::Insert(text.to_string()))
+    }
+}
+
+struct ActionClient {
+    output: Output,
     out: ws::Sender,
     pos: usize,
     action: EditAction,
     init_received: bool,
+    /// Only show the edit instead of sending it.
+    dry_run: bool,
+    /// Read and print the document after the edit was applied.
+    once: bool,
+}
+
+/// What an insert or delete client does with a message after sending its edit.
+#[derive(Debug, PartialEq)]
+enum AfterEdit {
+    /// Not meant for us, e.g. a broadcast.
+    Ignore,
+    /// The edit was rejected for this reason.
+    Failed(String),
+    Done,
+    /// The edit was applied and the document needs to be printed, see `--once`.
+    ReadBuffer,
+    /// The document after the edit, as revision and buffer.
+    PrintBuffer(u32, String),
+}
+
+fn after_edit(once: bool, json: &Json) -> AfterEdit {
+    if json["type"] == "buffer" {
+        if let (Some(rev), Some(buffer)) = (json["rev"].as_u64(), json["buffer"].as_str()) {
+            return AfterEdit::PrintBuffer(rev as u32, buffer.to_string());
+        }
+    }
+    match json["success"] {
+        Json::Bool(true) if once => AfterEdit::ReadBuffer,
+        Json::Bool(true) => AfterEdit::Done,
+        Json::Bool(false) => AfterEdit::Failed(json["reason"].to_string()),
+        _ => AfterEdit::Ignore,
+    }
+}
+
+/// What an insert or delete client does with its edit once it received the document.
+#[derive(Debug, PartialEq)]
+enum FirstMessage {
+    /// This frame goes to the server.
+    Send(String),
+    /// Nothing is sent, this is printed instead, see `--dry-run`.
+    Report(String),
+}
+
+/// Decides what to do with *edit*, which is based on *buffer*. With *dry_run* nothing is sent.
+fn first_message(dry_run: bool, buffer: &str, edit: &Edit) -> FirstMessage {
+    if dry_run {
+        FirstMessage::Report(dry_run_report(buffer, edit))
+    } else {
+        FirstMessage::Send(serde_json::to_string(edit).unwrap())
+    }
+}
+
+/// Describes what sending *edit* would do to *buffer*, for `--dry-run`.
+fn dry_run_report(buffer: &str, edit: &Edit) -> String {
+    let verdict = match PieceTable::from(buffer).check_edit(&edit.action, edit.pos) {
+        Ok(()) => format!("Would send: {}", serde_json::to_string(edit).unwrap()),
+        Err(e) => format!("Would be rejected: {}", e),
+    };
+    format!("{}\n{}", buffer, verdict)
 }
 
 impl ws::Handler for ActionClient {
+    fn build_request(&mut self, url: &url::Url) -> ws::Result<ws::Request> {
+        versioned_request(url)
+    }
+
     fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
         if !self.init_received {
-            let (rev, _) = serde_json::from_str::<(u32, String)>(msg.as_text()?)
-                .expect("TODO: graceful shutdown.");
-            if self.show_rev {
+            let ConnectStatus { rev, buffer, .. } = connect_status(msg.as_text()?);
+            if self.output.rev() {
                 println!("Rev {}", rev);
             }
             self.init_received = true;
@@ -151,54 +831,1151 @@ impl ws::Handler for ActionClient {
                 rev,
                 action: self.action.clone(),
             };
-            self.out.send(serde_json::to_string(&edit).unwrap())
+            match first_message(self.dry_run, &buffer, &edit) {
+                FirstMessage::Send(frame) => self.out.send(frame),
+                FirstMessage::Report(report) => {
+                    println!("{}", report);
+                    self.out.close(ws::CloseCode::Normal)
+                }
+            }
         } else {
             // wait to receive success
             let json =
                 serde_json::from_str::<Json>(msg.as_text()?).expect("TODO: graceful shutdown.");
-            if let Json::Object(map) = json {
-                if map.contains_key("success") {
-                    if Json::Bool(true) != map["success"] {
-                        eprintln!("Failed action. Reason: {}", map["reason"]);
-                    }
-                    self.out.close(ws::CloseCode::Normal)?;
+            match after_edit(self.once, &json) {
+                AfterEdit::Ignore => Ok(()),
+                AfterEdit::Failed(reason) => {
+                    eprintln!("Failed action. Reason: {}", reason);
+                    self.out.close(ws::CloseCode::Normal)
+                }
+                AfterEdit::Done => self.out.close(ws::CloseCode::Normal),
+                AfterEdit::ReadBuffer => self.out.send(json!({"type": "read"}).to_string()),
+                AfterEdit::PrintBuffer(rev, buffer) => {
+                    println!("{}", read_output(self.output, false, rev, &buffer));
+                    self.out.close(ws::CloseCode::Normal)
                 }
             }
-            Ok(())
         }
     }
 }
 
 struct WaitClient {
-    show_rev: bool,
+    output: Output,
+    out: ws::Sender,
     init_received: bool,
+    /// Whether to print the changed line for each edit.
+    context: bool,
+    /// Local copy of the document, kept up to date with the received edits if `context` is set.
+    mirror: PieceTable,
+    /// Revision after which to exit.
+    until: Option<u32>,
+    /// Seconds after which to give up waiting for `until`.
+    timeout: Option<u64>,
+    /// Where to write edits as JSON lines, replacing the human-readable output.
+    log: Option<Box<dyn Write>>,
+    /// The initial document and the edits received so far, printed on close for `--format json`.
+    document: Option<Json>,
+    /// Sequence number of the last broadcast received.
+    seq: Option<u64>,
+}
+
+/// A broadcast edit with the fields `wait` reports, in the JSON formats and `--log-edits`.
+fn edit_json(edit: &serde_json::Map<String, Json>) -> Json {
+    json!({
+        "rev": edit["rev"],
+        "author": edit.get("author").unwrap_or(&Json::Null),
+        "pos": edit["pos"],
+        "action": edit["action"],
+    })
+}
+
+/// Formats a broadcast edit as a line of JSON, for `wait --log-edits`.
+fn edit_log_line(edit: &serde_json::Map<String, Json>) -> String {
+    edit_json(edit).to_string()
+}
+
+/// Describes an edit for the text output of `wait` and `read --since`.
+fn edit_text(pos: usize, action: &EditAction) -> String {
+    match *action {
+        EditAction::Insert(ref txt) => format!("insert({}, {:?})", pos, txt),
+        EditAction::Delete(len) => format!("delete({}, {})", pos, len),
+        EditAction::DeleteExpecting(ref txt) => format!("delete({}, {:?})", pos, txt),
+        EditAction::InsertMany(ref inserts) => format!("insert_many({:?})", inserts),
+        EditAction::Replace(len, ref txt) => format!("replace({}, {}, {:?})", pos, len, txt),
+        EditAction::Noop => format!("noop({})", pos),
+    }
+}
+
+/// Checks the sequence number *seq* of a broadcast following the one numbered *last*. Returns the
+/// range of missed numbers if there is a gap, or the message arrived out of order.
+fn sequence_gap(last: Option<u64>, seq: u64) -> Option<(u64, u64)> {
+    match last {
+        Some(last) if seq != last + 1 => Some((last + 1, seq)),
+        _ => None,
+    }
+}
+
+/// Checks whether `wait --until` is done after observing revision `rev`.
+fn until_reached(until: Option<u32>, rev: u32) -> bool {
+    until.is_some_and(|until| rev >= until)
+}
+
+impl WaitClient {
+    /// Handles the document sent on connect, or again in full because we lagged behind. For
+    /// `--format json`, a resync replaces the collected document.
+    fn on_status(&mut self, text: &str) -> ws::Result<()> {
+        let ConnectStatus { rev, buffer, .. } = connect_status(text);
+        match self.output.format {
+            Format::Text => println!("{}", wait_text(self.output, rev, &buffer)),
+            Format::Json => {
+                self.document = Some(json!({"rev": rev, "buffer": buffer, "edits": []}));
+            }
+            Format::Ndjson => println!("{}", json!({"rev": rev, "buffer": buffer})),
+        }
+        if self.context {
+            self.mirror = PieceTable::from(buffer);
+        }
+        if until_reached(self.until, rev) {
+            self.out.close(ws::CloseCode::Normal)?;
+        }
+        Ok(())
+    }
+
+    /// Handles one broadcast edit.
+    fn on_edit(&mut self, map: &serde_json::Map<String, Json>) -> ws::Result<()> {
+        let pos = map["pos"].as_u64().unwrap() as usize;
+        let action: EditAction = serde_json::from_value(map["action"].clone()).unwrap();
+        let rev = map["rev"].as_u64().unwrap() as u32;
+        if let Some(seq) = map.get("seq").and_then(Json::as_u64) {
+            if let Some((first, next)) = sequence_gap(self.seq, seq) {
+                eprintln!(
+                    "Warning: expected broadcast {} but got {}, the document may be out of sync.",
+                    first, next
+                );
+            }
+            self.seq = Some(seq);
+        }
+        if let Some(ref mut log) = self.log {
+            writeln!(log, "{}", edit_log_line(map))
+                .and_then(|_| log.flush())
+                .expect("could not write edit log");
+        } else if let Some(ref mut document) = self.document {
+            document["edits"]
+                .as_array_mut()
+                .unwrap()
+                .push(edit_json(map));
+        } else if self.output.format == Format::Ndjson {
+            println!("{}", edit_log_line(map));
+        } else {
+            if self.output.rev() {
+                print!("Rev {}: ", rev);
+            }
+            println!("{}", edit_text(pos, &action));
+        }
+        if self.context {
+            let edit = Edit {
+                pos,
+                rev: 0,
+                action,
+            };
+            match apply_edit(&mut self.mirror, &edit) {
+                Ok(line) => println!("{}", line),
+                Err(reason) => eprintln!("Local copy out of sync: {}", reason),
+            }
+        }
+        // lets the server trim the history, we never send edits that would do so
+        self.out
+            .send(json!({"type": "ack", "rev": rev}).to_string())?;
+        if until_reached(self.until, rev) {
+            self.out.close(ws::CloseCode::Normal)?;
+        }
+        Ok(())
+    }
 }
 
 impl ws::Handler for WaitClient {
+    fn build_request(&mut self, url: &url::Url) -> ws::Result<ws::Request> {
+        versioned_request(url)
+    }
+
+    fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
+        if let Some(secs) = self.timeout {
+            self.out.timeout(secs * 1000, ws::util::Token(0))?;
+        }
+        Ok(())
+    }
+
+    fn on_timeout(&mut self, _: ws::util::Token) -> ws::Result<()> {
+        eprintln!(
+            "Timed out waiting for revision {}.",
+            self.until.unwrap_or_default()
+        );
+        process::exit(1);
+    }
+
     fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
         if !self.init_received {
-            let (rev, buffer) = serde_json::from_str::<(u32, String)>(msg.as_text()?)
-                .expect("TODO: graceful shutdown.");
-            if self.show_rev {
-                println!("Rev {}", rev);
-            }
-            println!("Text: {} bytes.\n{}", buffer.len(), buffer);
             self.init_received = true;
+            return self.on_status(msg.as_text()?);
+        }
+        let json = serde_json::from_str::<Json>(msg.as_text()?).expect("TODO: graceful shutdown.");
+        if json["type"] == "resync" {
+            // sent instead of the next edits because we lagged behind
+            self.seq = json["seq"].as_u64();
+            return self.on_status(msg.as_text()?);
+        }
+        match json {
+            // edits batched by the server, in order
+            Json::Array(edits) => {
+                for edit in edits {
+                    self.on_edit(edit.as_object().unwrap())?;
+                }
+            }
+            _ => self.on_edit(json.as_object().unwrap())?,
+        }
+        Ok(())
+    }
+
+    fn on_close(&mut self, _: ws::CloseCode, _: &str) {
+        if let Some(ref document) = self.document {
+            println!("{}", self.output.json(document));
+        }
+    }
+}
+
+/// The edits sent by `echo`, in order. Positions are at the start of the document, so they are
+/// valid for any content.
+fn echo_edits() -> Vec<(usize, EditAction)> {
+    vec![
+        (0, EditAction::Insert("avian echo\n".to_string())),
+        ("avian".len(), EditAction::Insert("-client".to_string())),
+        (0, EditAction::Delete("avian-".len())),
+        (0, EditAction::Insert("ä ".to_string())),
+    ]
+}
+
+/// Computes the document after applying *edits* to *buffer*, to compare with the server's.
+fn echo_expected(buffer: &str, edits: &[(usize, EditAction)]) -> Result<String, EditError> {
+    let mut expected = PieceTable::from(buffer);
+    for &(pos, ref action) in edits {
+        expected.apply_edit(action, pos)?;
+    }
+    Ok(expected.to_string())
+}
+
+/// Sends the edits one after another, each based on the revision of the previous one.
+struct EchoClient<'a> {
+    out: ws::Sender,
+    edits: Vec<(usize, EditAction)>,
+    /// Index of the next edit to send.
+    next: usize,
+    /// Revision to base the next edit on, `None` before the connect status.
+    rev: Option<u32>,
+    /// Author id of the edit waiting to be broadcast, from its success message.
+    author: Option<Json>,
+    /// Where to store the expected document.
+    expected: &'a RefCell<Option<String>>,
+}
+
+impl<'a> EchoClient<'a> {
+    fn send_next(&mut self) -> ws::Result<()> {
+        match self.edits.get(self.next) {
+            Some(&(pos, ref action)) => {
+                let edit = Edit {
+                    pos,
+                    rev: self.rev.unwrap(),
+                    action: action.clone(),
+                };
+                self.out.send(serde_json::to_string(&edit).unwrap())
+            }
+            None => self.out.close(ws::CloseCode::Normal),
+        }
+    }
+
+    /// Handles one broadcast edit, which may be our own.
+    fn on_edit(&mut self, edit: &Json) -> ws::Result<()> {
+        if self.author.is_some() && self.author.as_ref() == edit.get("author") {
+            self.author = None;
+            self.rev = edit["rev"].as_u64().map(|rev| rev as u32);
+            self.next += 1;
+            self.send_next()
         } else {
-            // wait to receive success
-            let json =
-                serde_json::from_str::<Json>(msg.as_text()?).expect("TODO: graceful shutdown.");
-            let map = json.as_object().unwrap();
-            let pos = map["pos"].as_u64().unwrap() as usize;
-            let action: EditAction = serde_json::from_value(map["action"].clone()).unwrap();
-            if self.show_rev {
-                print!("Rev {}: ", map["rev"].as_u64().unwrap());
+            eprintln!("Warning: someone else edited the document, the check will fail.");
+            Ok(())
+        }
+    }
+}
+
+impl<'a> ws::Handler for EchoClient<'a> {
+    fn build_request(&mut self, url: &url::Url) -> ws::Result<ws::Request> {
+        versioned_request(url)
+    }
+
+    fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+        if self.rev.is_none() {
+            let ConnectStatus { rev, buffer, .. } = connect_status(msg.as_text()?);
+            let expected = echo_expected(&buffer, &self.edits).expect("invalid echo edits");
+            *self.expected.borrow_mut() = Some(expected);
+            self.rev = Some(rev);
+            return self.send_next();
+        }
+        let json = serde_json::from_str::<Json>(msg.as_text()?).expect("TODO: graceful shutdown.");
+        if json["success"] == Json::Bool(false) {
+            eprintln!("Edit {} failed. Reason: {}", self.next, json["reason"]);
+            process::exit(1);
+        } else if json["success"] == Json::Bool(true) {
+            self.author = Some(json["author"].clone());
+            Ok(())
+        } else if let Json::Array(ref edits) = json {
+            for edit in edits {
+                self.on_edit(edit)?;
+            }
+            Ok(())
+        } else {
+            self.on_edit(&json)
+        }
+    }
+}
+
+/// Decides what a watched file uploads. A change is only uploaded once the file stayed the same
+/// for the debounce time, so rapid saves become one upload.
+struct Upload {
+    /// Content the document has after the last upload.
+    sent: String,
+    /// Changed content not uploaded yet, and since when the file has it.
+    pending: Option<(String, Instant)>,
+    debounce: Duration,
+}
+
+impl Upload {
+    fn new(document: String, debounce: Duration) -> Self {
+        Upload {
+            sent: document,
+            pending: None,
+            debounce,
+        }
+    }
+
+    /// Takes the file's *content* at time *now*. Returns the edits to upload, in order, or none
+    /// while the content is unchanged or still changing.
+    fn poll(&mut self, content: String, now: Instant) -> Vec<(usize, EditAction)> {
+        let since = match self.pending {
+            Some((ref pending, since)) if *pending == content => since,
+            _ => now,
+        };
+        if content == self.sent {
+            self.pending = None;
+            return Vec::new();
+        }
+        if now - since < self.debounce {
+            self.pending = Some((content, since));
+            return Vec::new();
+        }
+        self.pending = None;
+        let edits = diff(&self.sent, &content);
+        self.sent = content;
+        edits
+    }
+}
+
+/// Polls a file and uploads its changes, one edit at a time like `EchoClient`.
+struct WatchClient {
+    output: Output,
+    out: ws::Sender,
+    path: PathBuf,
+    /// Poll interval in milliseconds.
+    interval: u64,
+    debounce: Duration,
+    /// `None` before the connect status.
+    upload: Option<Upload>,
+    /// Revision to base the next edit on.
+    rev: Option<u32>,
+    /// Edits waiting to be sent, the next one last.
+    queue: Vec<(usize, EditAction)>,
+    /// Author id of the edit waiting to be broadcast, from its success message.
+    author: Option<Json>,
+    /// Whether an edit was sent and not broadcast yet.
+    in_flight: bool,
+}
+
+impl WatchClient {
+    fn send_next(&mut self) -> ws::Result<()> {
+        if self.in_flight {
+            return Ok(());
+        }
+        match (self.queue.pop(), self.rev) {
+            (Some((pos, action)), Some(rev)) => {
+                self.in_flight = true;
+                let edit = Edit { pos, rev, action };
+                self.out.send(serde_json::to_string(&edit).unwrap())
             }
-            match action {
-                EditAction::Insert(txt) => println!("insert({}, {:?})", pos, txt),
-                EditAction::Delete(len) => println!("delete({}, {})", pos, len),
+            _ => Ok(()),
+        }
+    }
+
+    /// Handles one broadcast edit, which may be our own.
+    fn on_edit(&mut self, edit: &Json) -> ws::Result<()> {
+        self.rev = edit["rev"].as_u64().map(|rev| rev as u32);
+        if self.author.is_some() && self.author.as_ref() == edit.get("author") {
+            self.author = None;
+            self.in_flight = false;
+            self.send_next()
+        } else {
+            eprintln!("Warning: someone else edited the document, it no longer matches the file.");
+            Ok(())
+        }
+    }
+}
+
+impl ws::Handler for WatchClient {
+    fn build_request(&mut self, url: &url::Url) -> ws::Result<ws::Request> {
+        versioned_request(url)
+    }
+
+    fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
+        self.out.timeout(self.interval, ws::util::Token(0))
+    }
+
+    fn on_timeout(&mut self, _: ws::util::Token) -> ws::Result<()> {
+        // A file that cannot be read, e.g. while an editor replaces it, is checked again later.
+        if let (Some(upload), Ok(content)) = (self.upload.as_mut(), fs::read_to_string(&self.path))
+        {
+            let edits = upload.poll(content, Instant::now());
+            if !edits.is_empty() {
+                if let Some(message) = upload_status(self.output, &self.path) {
+                    println!("{}", message);
+                }
             }
+            // the queue is sent from the back
+            self.queue.splice(0..0, edits.into_iter().rev());
         }
-        Ok(())
+        self.send_next()?;
+        self.out.timeout(self.interval, ws::util::Token(0))
+    }
+
+    fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+        if self.upload.is_none() {
+            let ConnectStatus { rev, buffer, .. } = connect_status(msg.as_text()?);
+            self.upload = Some(Upload::new(buffer, self.debounce));
+            self.rev = Some(rev);
+            return Ok(());
+        }
+        let json = serde_json::from_str::<Json>(msg.as_text()?).expect("TODO: graceful shutdown.");
+        if json["success"] == Json::Bool(false) {
+            eprintln!("Upload failed. Reason: {}", json["reason"]);
+            process::exit(1);
+        } else if json["success"] == Json::Bool(true) {
+            self.author = Some(json["author"].clone());
+            Ok(())
+        } else if let Json::Array(ref edits) = json {
+            for edit in edits {
+                self.on_edit(edit)?;
+            }
+            Ok(())
+        } else {
+            self.on_edit(&json)
+        }
+    }
+}
+
+/// Generates a random edit that is valid for *buffer*: an insert of a few letters and digits, or
+/// a delete of a few characters, at char boundaries.
+fn random_edit<R: Rng>(rng: &mut R, buffer: &PieceTable, rev: u32) -> Edit {
+    let boundaries: Vec<usize> = buffer
+        .char_indices()
+        .map(|(i, _)| i)
+        .chain(Some(buffer.len()))
+        .collect();
+    let start = rng.gen_range(0, boundaries.len());
+    let action = if start + 1 < boundaries.len() && rng.gen_weighted_bool(3) {
+        let end = rng.gen_range(start + 1, cmp::min(start + 5, boundaries.len()));
+        EditAction::Delete(boundaries[end] - boundaries[start])
+    } else {
+        let len = rng.gen_range(1, 6);
+        EditAction::Insert(rng.gen_ascii_chars().take(len).collect())
+    };
+    Edit {
+        pos: boundaries[start],
+        rev,
+        action,
+    }
+}
+
+/// Results of all `stress` connections together.
+#[derive(Default)]
+struct StressStats {
+    accepted: Cell<u32>,
+    /// Number of rejected edits by reason.
+    rejected: RefCell<BTreeMap<String, u32>>,
+}
+
+fn stress_report(stats: &StressStats, elapsed: Duration) -> String {
+    let rejected = stats.rejected.borrow();
+    let total = stats.accepted.get() + rejected.values().sum::<u32>();
+    let mut report = format!(
+        "{} edits in {:.3} s ({:.1} edits/s), {} accepted.",
+        total,
+        elapsed.as_secs_f64(),
+        total as f64 / elapsed.as_secs_f64(),
+        stats.accepted.get()
+    );
+    for (reason, count) in rejected.iter() {
+        report.push_str(&format!("\nRejected: {} x {}", count, reason));
+    }
+    report
+}
+
+/// One `stress` connection. Keeps a copy of the document to generate valid edits, and sends the
+/// next edit once the previous one was answered.
+struct StressClient<'a> {
+    out: ws::Sender,
+    /// Number of edits left to send.
+    remaining: u32,
+    /// The document and its revision, `None` before the connect status.
+    mirror: Option<(PieceTable, u32)>,
+    rng: rand::ThreadRng,
+    stats: &'a StressStats,
+}
+
+impl<'a> StressClient<'a> {
+    fn send_next(&mut self) -> ws::Result<()> {
+        if self.remaining == 0 {
+            return self.out.close(ws::CloseCode::Normal);
+        }
+        self.remaining -= 1;
+        let (ref buffer, rev) = *self.mirror.as_ref().unwrap();
+        let edit = random_edit(&mut self.rng, buffer, rev);
+        self.out.send(serde_json::to_string(&edit).unwrap())
+    }
+
+    /// Handles one broadcast edit, which may be our own.
+    fn on_edit(&mut self, edit: Json) {
+        let edit: Edit = serde_json::from_value(edit).expect("TODO: graceful shutdown.");
+        let (ref mut buffer, ref mut rev) = *self.mirror.as_mut().unwrap();
+        if let Err(err) = apply_remote_edit(buffer, &edit) {
+            eprintln!("Local copy out of sync: {}", err);
+        }
+        *rev = edit.rev;
+    }
+}
+
+impl<'a> ws::Handler for StressClient<'a> {
+    fn build_request(&mut self, url: &url::Url) -> ws::Result<ws::Request> {
+        versioned_request(url)
+    }
+
+    fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+        let json = serde_json::from_str::<Json>(msg.as_text()?).expect("TODO: graceful shutdown.");
+        if self.mirror.is_none() || json["type"] == "resync" {
+            let ConnectStatus { rev, buffer, .. } = connect_status(msg.as_text()?);
+            let first = self.mirror.is_none();
+            self.mirror = Some((PieceTable::from(buffer), rev));
+            return if first { self.send_next() } else { Ok(()) };
+        }
+        match json {
+            Json::Array(edits) => {
+                for edit in edits {
+                    self.on_edit(edit);
+                }
+                Ok(())
+            }
+            ref answer if answer.get("success").is_some() => {
+                if answer["success"] == Json::Bool(true) {
+                    self.stats.accepted.set(self.stats.accepted.get() + 1);
+                } else {
+                    let reason = answer["reason"].as_str().unwrap_or("unknown").to_string();
+                    *self.stats.rejected.borrow_mut().entry(reason).or_insert(0) += 1;
+                }
+                self.send_next()
+            }
+            edit => {
+                self.on_edit(edit);
+                Ok(())
+            }
+        }
+    }
+}
+
+struct PingClient {
+    out: ws::Sender,
+    count: u32,
+    /// Time at which the current ping was sent.
+    sent: Option<Instant>,
+    /// Send and receive times of all answered pings.
+    pings: Vec<(Instant, Instant)>,
+}
+
+impl PingClient {
+    fn ping(&mut self) -> ws::Result<()> {
+        let nonce = self.pings.len();
+        self.sent = Some(Instant::now());
+        self.out
+            .send(json!({"type": "ping", "nonce": nonce}).to_string())
+    }
+}
+
+impl ws::Handler for PingClient {
+    fn build_request(&mut self, url: &url::Url) -> ws::Result<ws::Request> {
+        versioned_request(url)
+    }
+
+    fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+        let received = Instant::now();
+        let json = serde_json::from_str::<Json>(msg.as_text()?).expect("TODO: graceful shutdown.");
+        let sent = match ping_reply(self.sent, self.pings.len(), &json) {
+            PingReply::Start => return self.ping(),
+            PingReply::Pong(sent) => sent,
+            PingReply::Ignore => return Ok(()),
+        };
+        self.pings.push((sent, received));
+        println!(
+            "Pong {}: {:.3} ms",
+            self.pings.len(),
+            millis(received - sent)
+        );
+        if self.pings.len() < self.count as usize {
+            self.ping()
+        } else {
+            if let Some((min, avg, max)) = rtt_stats(&self.pings) {
+                println!(
+                    "Round-trip min/avg/max = {:.3}/{:.3}/{:.3} ms",
+                    millis(min),
+                    millis(avg),
+                    millis(max)
+                );
+            }
+            self.out.close(ws::CloseCode::Normal)
+        }
+    }
+}
+
+/// What the ping client does with a message.
+#[derive(Debug, PartialEq)]
+enum PingReply {
+    /// The initial status message arrived, can start pinging.
+    Start,
+    /// The answer to the ping sent at this time.
+    Pong(Instant),
+    /// Not meant for us, e.g. other clients' edits.
+    Ignore,
+}
+
+/// Classifies a message given the time the current ping was *sent*, if any, and the number of
+/// pings *answered* so far. The server sends the connect status before anything else, so the
+/// first message always starts pinging.
+fn ping_reply(sent: Option<Instant>, answered: usize, json: &Json) -> PingReply {
+    match sent {
+        None => PingReply::Start,
+        Some(sent) if json["type"] == "pong" && json["nonce"] == answered => PingReply::Pong(sent),
+        Some(_) => PingReply::Ignore,
+    }
+}
+
+/// Computes the minimum, average and maximum round-trip time from pairs of send and receive times.
+fn rtt_stats(pings: &[(Instant, Instant)]) -> Option<(Duration, Duration, Duration)> {
+    let rtts = pings.iter().map(|&(sent, received)| received - sent);
+    let min = rtts.clone().min()?;
+    let max = rtts.clone().max()?;
+    let avg = rtts.sum::<Duration>() / pings.len() as u32;
+    Some((min, avg, max))
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Applies a broadcast edit to a local copy of the document. Returns the changed line(s), with
+/// the inserted text highlighted or the deleted text struck through.
+fn apply_edit(mirror: &mut PieceTable, edit: &Edit) -> Result<String, &'static str> {
+    let before = mirror.to_string();
+    apply_remote_edit(mirror, edit).map_err(|e| e.reason())?;
+    Ok(match edit.action {
+        EditAction::Insert(ref content) => highlight(
+            &mirror.to_string(),
+            edit.pos,
+            edit.pos + content.len(),
+            "\x1b[7m",
+        ),
+        EditAction::Delete(len) => highlight(&before, edit.pos, edit.pos + len, "\x1b[9m"),
+        EditAction::DeleteExpecting(ref text) => {
+            highlight(&before, edit.pos, edit.pos + text.len(), "\x1b[9m")
+        }
+        EditAction::InsertMany(ref inserts) => {
+            // from the first insert to the end of the last one, after all were inserted
+            let added: usize = inserts.iter().map(|(_, content)| content.len()).sum();
+            let start = inserts.first().map_or(edit.pos, |&(pos, _)| pos);
+            let end = inserts.last().map_or(edit.pos, |&(pos, _)| pos + added);
+            highlight(&mirror.to_string(), start, end, "\x1b[7m")
+        }
+        EditAction::Replace(_, ref content) => highlight(
+            &mirror.to_string(),
+            edit.pos,
+            edit.pos + content.len(),
+            "\x1b[7m",
+        ),
+        EditAction::Noop => highlight(&before, edit.pos, edit.pos, ""),
+    })
+}
+
+/// Returns the lines of `text` touched by the byte range `start..end`, with that range wrapped in
+/// the given ANSI style.
+fn highlight(text: &str, start: usize, end: usize, style: &str) -> String {
+    let line_start = text[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = text[end..].find('\n').map_or(text.len(), |i| end + i);
+    format!(
+        "{}{}{}\x1b[0m{}",
+        &text[line_start..start],
+        style,
+        &text[start..end],
+        &text[end..line_end]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use avian::Editor;
+
+    #[test]
+    fn mirror_follows_broadcasts() {
+        let editor = Editor::new();
+        editor.connect(0u32);
+        editor.connect(1u32);
+        // (client, base revision, position, action). The third edit is based on an old revision.
+        let edits = vec![
+            (0, 0, 0, EditAction::Insert("Hello World".to_string())),
+            (
+                1,
+                1,
+                "Hello".len(),
+                EditAction::Insert(",\nbrave new".to_string()),
+            ),
+            (0, 1, 1, EditAction::Delete("ello".len())),
+            (1, 3, 1, EditAction::Insert("i".to_string())),
+        ];
+        let mut mirror = PieceTable::new();
+        for (id, rev, pos, action) in edits {
+            let broadcast = editor.edit(id, Edit { pos, rev, action }).unwrap();
+            apply_edit(&mut mirror, &broadcast).unwrap();
+        }
+        assert_eq!(mirror.to_string(), editor.buffer());
+        assert_eq!(editor.buffer(), "Hi,\nbrave new World");
+    }
+
+    #[test]
+    fn config_precedence() {
+        let config = Config::parse(r#"{"host": "example.org", "secure": true}"#).unwrap();
+        assert_eq!(
+            setting(Some("cli"), config.host.as_deref(), "default"),
+            "cli"
+        );
+        assert_eq!(
+            setting(None, config.host.as_deref(), "default"),
+            "example.org"
+        );
+        assert_eq!(setting(None, config.port, 8080), 8080);
+        assert_eq!(setting(Some(9000), config.port, 8080), 9000);
+        assert!(setting(None, config.secure, false));
+        assert!(Config::parse(r#"{"port": "not a number"}"#).is_err());
+    }
+
+    #[test]
+    fn round_trip_times() {
+        assert_eq!(rtt_stats(&[]), None);
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let pings = [(at(0), at(10)), (at(20), at(24)), (at(30), at(46))];
+        let (min, avg, max) = rtt_stats(&pings).unwrap();
+        assert_eq!(min, Duration::from_millis(4));
+        assert_eq!(avg, Duration::from_millis(10));
+        assert_eq!(max, Duration::from_millis(16));
+    }
+
+    #[test]
+    fn edit_log_lines() {
+        let insert = json!({"pos": 3, "rev": 7, "author": 2, "action": {"Insert": "a\nb"}});
+        assert_eq!(
+            edit_log_line(insert.as_object().unwrap()),
+            r#"{"action":{"Insert":"a\nb"},"author":2,"pos":3,"rev":7}"#
+        );
+        let delete = json!({"pos": 0, "rev": 8, "action": {"Delete": 4}});
+        assert_eq!(
+            edit_log_line(delete.as_object().unwrap()),
+            r#"{"action":{"Delete":4},"author":null,"pos":0,"rev":8}"#
+        );
+    }
+
+    #[test]
+    fn broadcast_gaps() {
+        assert_eq!(sequence_gap(None, 5), None);
+        assert_eq!(sequence_gap(Some(4), 5), None);
+        assert_eq!(sequence_gap(Some(4), 7), Some((5, 7)));
+        assert_eq!(sequence_gap(Some(4), 3), Some((5, 3)));
+    }
+
+    #[test]
+    fn once_sequence() {
+        let broadcast = json!({"pos": 0, "rev": 1, "action": {"Insert": "a"}, "author": 3});
+        let ack = json!({"success": true, "author": 3});
+        let buffer = json!({"type": "buffer", "rev": 1, "buffer": "ab"});
+        // with --once, the ack leads to reading the document, which is printed before closing
+        assert_eq!(after_edit(true, &broadcast), AfterEdit::Ignore);
+        assert_eq!(after_edit(true, &ack), AfterEdit::ReadBuffer);
+        assert_eq!(after_edit(true, &json!([broadcast])), AfterEdit::Ignore);
+        assert_eq!(
+            after_edit(true, &buffer),
+            AfterEdit::PrintBuffer(1, "ab".to_string())
+        );
+        assert_eq!(after_edit(false, &ack), AfterEdit::Done);
+        let failure = json!({"success": false, "code": "invalid_index", "reason": "invalid index"});
+        assert_eq!(
+            after_edit(true, &failure),
+            AfterEdit::Failed("\"invalid index\"".to_string())
+        );
+    }
+
+    #[test]
+    fn ping_sequence() {
+        let status = ConnectStatus {
+            rev: 4,
+            buffer: "pinged".to_string(),
+            checksum: checksum("pinged".bytes()),
+            clients: 2,
+        };
+        let status = serde_json::to_value(status).unwrap();
+        assert_eq!(ping_reply(None, 0, &status), PingReply::Start);
+
+        let sent = Instant::now();
+        let broadcast = json!({"pos": 0, "rev": 5, "action": {"Delete": 1}, "author": 1});
+        assert_eq!(ping_reply(Some(sent), 0, &broadcast), PingReply::Ignore);
+        let pong = json!({"type": "pong", "nonce": 0});
+        assert_eq!(ping_reply(Some(sent), 0, &pong), PingReply::Pong(sent));
+        // a late answer to an earlier ping
+        assert_eq!(ping_reply(Some(sent), 1, &pong), PingReply::Ignore);
+    }
+
+    #[test]
+    fn wait_until() {
+        let revs = [3, 4, 5, 7, 8];
+        let exit_at = |until| revs.iter().position(|&rev| until_reached(until, rev));
+        assert_eq!(exit_at(None), None);
+        assert_eq!(exit_at(Some(2)), Some(0));
+        assert_eq!(exit_at(Some(5)), Some(2));
+        // revision 6 is skipped, e.g. because it was not broadcast before we connected
+        assert_eq!(exit_at(Some(6)), Some(3));
+        assert_eq!(exit_at(Some(9)), None);
+    }
+
+    #[test]
+    fn highlight_line() {
+        let text = "first\nsecond line\nthird";
+        assert_eq!(highlight(text, 13, 17, "<"), "second <line\x1b[0m");
+        assert_eq!(highlight(text, 0, 1, "<"), "<f\x1b[0mirst");
+    }
+
+    #[test]
+    fn read_formats() {
+        let output = |show_rev, format| Output {
+            show_rev,
+            quiet: false,
+            format,
+            pretty_json: false,
+        };
+        let text = output(true, Format::Text);
+        assert_eq!(read_output(text, false, 3, "a\nb"), "Rev 3\na\nb");
+        assert_eq!(read_output(output(false, Format::Text), false, 3, "a"), "a");
+        assert_eq!(
+            read_output(output(false, Format::Ndjson), false, 3, "a\nb"),
+            r#"{"buffer":"a\nb","rev":3}"#
+        );
+        // JSON is parseable as a whole, with the revision even without --rev
+        let json = read_output(output(false, Format::Json), true, 3, "a\nb");
+        assert_eq!(json.lines().count(), 1);
+        let parsed: Json = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, json!({"rev": 3, "buffer": "a\nb"}));
+        let pretty = Output {
+            pretty_json: true,
+            ..output(false, Format::Json)
+        };
+        let json = read_output(pretty, true, 3, "a\nb");
+        assert_eq!(json, "{\n  \"buffer\": \"a\\nb\",\n  \"rev\": 3\n}");
+        assert_eq!(serde_json::from_str::<Json>(&json).unwrap(), parsed);
+        // one object per line, whatever --pretty-json says
+        let ndjson = Output {
+            pretty_json: true,
+            ..output(false, Format::Ndjson)
+        };
+        assert_eq!(read_output(ndjson, false, 3, "a").lines().count(), 1);
+        assert_eq!(Format::parse("ndjson"), Some(Format::Ndjson));
+        assert_eq!(Format::parse("yaml"), None);
+    }
+
+    #[test]
+    fn read_since() {
+        let request: Json = serde_json::from_str(&since_request(4)).unwrap();
+        assert_eq!(request, json!({"type": "edits_since", "rev": 4}));
+
+        let edit = Edit {
+            pos: 2,
+            rev: 5,
+            action: EditAction::Insert("x".to_string()),
+        };
+        let response = json!({"type": "edits", "rev": 4, "edits": [edit]});
+        let edits = since_edits(&response).unwrap();
+        assert_eq!(edits, vec![edit]);
+        let output = |show_rev, format| Output {
+            show_rev,
+            quiet: false,
+            format,
+            pretty_json: false,
+        };
+        assert_eq!(
+            since_output(output(true, Format::Text), 4, &edits),
+            r#"Rev 5: insert(2, "x")"#
+        );
+        assert_eq!(
+            since_output(output(false, Format::Ndjson), 4, &edits),
+            r#"{"pos":2,"rev":5,"action":{"Insert":"x"}}"#
+        );
+
+        // trimmed past the requested revision
+        let too_old = json!({"type": "edits", "rev": 4, "resync": true});
+        assert_eq!(since_edits(&too_old), None);
+    }
+
+    #[test]
+    fn quiet_output() {
+        let output = |show_rev, quiet| Output {
+            show_rev,
+            quiet,
+            format: Format::Text,
+            pretty_json: false,
+        };
+        // read, insert and delete only print revisions on request
+        assert!(!output(false, false).rev());
+        assert!(output(true, false).rev());
+        assert!(!output(true, true).rev());
+        // wait additionally prints a status line with the buffer size
+        assert!(output(false, false).status());
+        assert!(output(true, false).status());
+        assert!(!output(false, true).status());
+
+        // each subcommand hides its status messages, but not errors or the requested result
+        let status = ConnectStatus {
+            rev: 2,
+            buffer: "ok".to_string(),
+            clients: 1,
+            checksum: checksum("ok".bytes()),
+        };
+        assert!(verify_output(output(false, false), &status)
+            .unwrap()
+            .is_some());
+        assert_eq!(verify_output(output(false, true), &status), Ok(None));
+        let corrupted = ConnectStatus {
+            checksum: 0,
+            ..status
+        };
+        assert!(verify_output(output(false, true), &corrupted).is_err());
+
+        assert_eq!(
+            echo_output(output(false, false), "ab", "ab"),
+            Ok(Some("Echo check passed.".to_string()))
+        );
+        assert_eq!(echo_output(output(false, true), "ab", "ab"), Ok(None));
+        assert!(echo_output(output(false, true), "ab", "a").is_err());
+
+        let path = Path::new("notes.txt");
+        assert_eq!(
+            upload_status(output(false, false), path),
+            Some("Uploading a change of notes.txt.".to_string())
+        );
+        assert_eq!(upload_status(output(false, true), path), None);
+
+        assert_eq!(
+            wait_text(output(true, false), 2, "ok"),
+            "Rev 2\nText: 2 bytes.\nok"
+        );
+        assert_eq!(
+            wait_text(output(false, false), 2, "ok"),
+            "Text: 2 bytes.\nok"
+        );
+        assert_eq!(wait_text(output(true, true), 2, "ok"), "ok");
+    }
+
+    #[test]
+    fn insert_from_stdin() {
+        let piped = "first line\nsecond ä line\n";
+        match insert_action("-", piped.as_bytes()).unwrap() {
+            EditAction::Insert(text) => assert_eq!(text, piped),
+            other => panic!("unexpected action {:?}", other),
+        }
+        match insert_action("literal", "ignored".as_bytes()).unwrap() {
+            EditAction::Insert(text) => assert_eq!(text, "literal"),
+            other => panic!("unexpected action {:?}", other),
+        }
+    }
+
+    #[test]
+    fn connect_checksum() {
+        let editor = avian::Editor::<u32>::from("some text");
+        let json = serde_json::to_string(&editor.connect(0)).unwrap();
+        let status = connect_status(&json);
+        assert_eq!(status.buffer, "some text");
+        assert!(checksum_matches(&status));
+        let corrupted = ConnectStatus {
+            buffer: "some test".to_string(),
+            ..status
+        };
+        assert!(!checksum_matches(&corrupted));
+    }
+
+    #[test]
+    fn pretty_read() {
+        let buffer = (1..=10)
+            .map(|i| format!("line {}\n", i))
+            .collect::<String>();
+        let pretty = pretty_buffer(&buffer);
+        let lines: Vec<_> = pretty.lines().collect();
+        assert_eq!(lines.len(), 12);
+        assert_eq!(lines[0], " 1 | line 1");
+        assert_eq!(lines[9], "10 | line 10");
+        assert_eq!(lines[10], "11 | ");
+        assert_eq!(lines[11], "71 bytes, 11 lines");
+        assert_eq!(pretty_buffer("só"), "1 | só\n3 bytes, 1 line");
+    }
+
+    #[test]
+    fn verify_checksum() {
+        let editor = avian::Editor::<u32>::from("häve some text");
+        let status = editor.connect(0);
+        assert_eq!(checksum(status.buffer.bytes()), editor.checksum());
+        assert_eq!(
+            verify_report(&status),
+            Ok(format!(
+                "Checksum {:016x} matches, 15 bytes at revision 0.",
+                status.checksum
+            ))
+        );
+        let corrupted = ConnectStatus {
+            buffer: "have some text".to_string(),
+            ..status
+        };
+        assert!(verify_report(&corrupted).is_err());
+    }
+
+    #[test]
+    fn echo_expected_buffer() {
+        let edits = echo_edits();
+        assert_eq!(
+            echo_expected("existing text", &edits),
+            Ok("ä client echo\nexisting text".to_string())
+        );
+        assert_eq!(echo_expected("", &edits), Ok("ä client echo\n".to_string()));
+        let invalid = [(1, EditAction::Delete(1))];
+        assert_eq!(echo_expected("", &invalid), Err(EditError::OutOfRange));
+    }
+
+    #[test]
+    fn watch_uploads() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut upload = Upload::new("first line\n".to_string(), Duration::from_millis(100));
+        let mut document = PieceTable::from("first line\n");
+        let snapshots = [
+            (0, "first line\n", false),
+            // saved twice in a row, only the second one is uploaded once it settled
+            (50, "first line\nsec", false),
+            (100, "first line\nsecond line\n", false),
+            (150, "first line\nsecond line\n", false),
+            (200, "first line\nsecond line\n", true),
+            (250, "first line\nsecond line\n", false),
+            (300, "1st line\nsecond line\n", false),
+            // changed back before the debounce time passed
+            (350, "first line\nsecond line\n", false),
+            (500, "first line\nsecond line\n", false),
+            (600, "first lïne\n", false),
+            (700, "first lïne\n", true),
+        ];
+        for &(ms, content, uploads) in &snapshots {
+            let edits = upload.poll(content.to_string(), at(ms));
+            assert_eq!(!edits.is_empty(), uploads, "at {} ms", ms);
+            for (pos, action) in edits {
+                document.apply_edit(&action, pos).unwrap();
+            }
+            if uploads {
+                assert_eq!(document.to_string(), content);
+            }
+        }
+        assert_eq!(document.to_string(), "first lïne\n");
+
+        let mut upload = Upload::new(String::new(), Duration::from_millis(0));
+        assert_eq!(
+            upload.poll("now".to_string(), at(0)),
+            [(0, EditAction::Insert("now".to_string()))]
+        );
+    }
+
+    #[test]
+    fn random_edits_valid() {
+        use rand::{SeedableRng, XorShiftRng};
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let mut buffer = PieceTable::from("ä stress tëst\n");
+        for rev in 0..500 {
+            let edit = random_edit(&mut rng, &buffer, rev);
+            assert_eq!(edit.rev, rev);
+            assert_eq!(
+                buffer.check_edit(&edit.action, edit.pos),
+                Ok(()),
+                "{:?}",
+                edit
+            );
+            buffer.apply_edit(&edit.action, edit.pos).unwrap();
+        }
+        // also valid for an empty document, where only inserts are possible
+        let empty = PieceTable::new();
+        for _ in 0..20 {
+            let edit = random_edit(&mut rng, &empty, 0);
+            assert_eq!(edit.pos, 0);
+            assert_eq!(edit.action.deleted_len(), None, "{:?}", edit);
+        }
+    }
+
+    #[test]
+    fn dry_run_output() {
+        let edit = Edit {
+            pos: 5,
+            rev: 3,
+            action: EditAction::Insert(" there".to_string()),
+        };
+        let frame = r#"{"pos":5,"rev":3,"action":{"Insert":" there"}}"#;
+        assert_eq!(
+            first_message(true, "hello", &edit),
+            FirstMessage::Report(format!("hello\nWould send: {}", frame))
+        );
+        assert_eq!(
+            first_message(false, "hello", &edit),
+            FirstMessage::Send(frame.to_string())
+        );
+        let edit = Edit {
+            pos: 1,
+            rev: 3,
+            action: EditAction::Delete(1),
+        };
+        // rejected edits are reported, not sent
+        assert_eq!(
+            first_message(true, "ä", &edit),
+            FirstMessage::Report("ä\nWould be rejected: not a char boundary".to_string())
+        );
+    }
+
+    #[test]
+    fn protocol_request() {
+        let url = url::Url::parse("ws://localhost:8080/ws").unwrap();
+        let req = versioned_request(&url).unwrap();
+        assert_eq!(req.protocols().unwrap(), [avian::PROTOCOL]);
+        // also for the clients that only handle messages with a closure
+        let mut handler = Versioned(|_: ws::Message| Ok(()));
+        let req = ws::Handler::build_request(&mut handler, &url).unwrap();
+        assert_eq!(req.protocols().unwrap(), [avian::PROTOCOL]);
     }
 }
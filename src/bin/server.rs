@@ -1,3 +1,6 @@
+// ws handlers return ws::Result, whose foreign error type is large; boxing it is not an option.
+#![allow(clippy::result_large_err)]
+
 extern crate avian;
 extern crate env_logger;
 extern crate serde;
@@ -7,58 +10,777 @@ extern crate serde_json;
 #[macro_use]
 extern crate clap;
 
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Write};
+use std::mem;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
 use clap::{App, Arg};
+use serde::Deserialize;
+use serde_json::Value as Json;
+use ws::util::Token;
 use ws::{listen, Handler, Message, Request, Response, Sender};
 
-use avian::{Edit, Editor};
+use avian::journal::{self, Journal};
+use avian::{ConnectStatus, Edit, EditAction, EditError, Editor};
 
 const EDITOR_HTML: &str = include_str!("../../public/editor.html");
 const EDITOR_JS: &str = include_str!("../../public/editor.js");
 
+/// Subprotocols the server speaks, preferred first.
+const PROTOCOLS: &[&str] = &[avian::PROTOCOL];
+
+/// Timeout token for sending the pending batch of edits.
+const BATCH_TOKEN: Token = Token(1);
+/// Timeout token for printing the `--profile` statistics.
+const PROFILE_TOKEN: Token = Token(2);
+
 struct Server<'a> {
     out: Sender,
     editor: &'a Editor<u32>,
+    batch: &'a Batch,
+    journal: Option<&'a RefCell<Journal>>,
+    limit: &'a ClientLimit,
+    merge: &'a MergeSchedule,
+    resync: &'a Resync,
+    sequence: &'a Sequence,
+    profile: &'a Profile,
+    access_log: Option<&'a AccessLog>,
+    /// Set once this connection opened and took one of the limited places.
+    admitted: bool,
+    snapshot_mode: bool,
+    /// Token required for the debug endpoints, which are disabled without one.
+    admin_token: Option<&'a str>,
+}
+
+/// Reasons for rejecting a message from a client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Failure {
+    InvalidMessage,
+    InvalidJson,
+    Edit(EditError),
+}
+
+impl Failure {
+    /// Stable code for clients to branch on.
+    fn code(&self) -> &'static str {
+        match *self {
+            Failure::InvalidMessage => "invalid_message",
+            Failure::InvalidJson => "invalid_json",
+            Failure::Edit(e) => e.code(),
+        }
+    }
+
+    /// Human-readable reason.
+    fn reason(&self) -> &'static str {
+        match *self {
+            Failure::InvalidMessage => "invalid message",
+            Failure::InvalidJson => "invalid json",
+            Failure::Edit(e) => e.reason(),
+        }
+    }
+
+    fn to_json(self) -> Json {
+        json!({"success": false, "code": self.code(), "reason": self.reason()})
+    }
+}
+
+/// Edits collected to be broadcast together in one frame, as a JSON array.
+struct Batch {
+    /// Time in milliseconds to collect edits for. 0 disables batching.
+    window: u64,
+    pending: RefCell<Vec<Json>>,
+}
+
+impl Batch {
+    fn new(window: u64) -> Self {
+        Batch {
+            window,
+            pending: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Adds an edit. Returns true if it is the first one, so sending the batch needs to be
+    /// scheduled.
+    fn push(&self, edit: Json) -> bool {
+        let mut pending = self.pending.borrow_mut();
+        pending.push(edit);
+        pending.len() == 1
+    }
+
+    /// Takes all pending edits as one frame. Returns `None` if there are none.
+    fn take(&self) -> Option<String> {
+        let pending = mem::take(&mut *self.pending.borrow_mut());
+        if pending.is_empty() {
+            None
+        } else {
+            Some(Json::Array(pending).to_string())
+        }
+    }
+}
+
+/// Bounds the number of connected clients.
+struct ClientLimit {
+    max: Option<usize>,
+    connected: Cell<usize>,
+}
+
+impl ClientLimit {
+    fn new(max: Option<usize>) -> Self {
+        ClientLimit {
+            max,
+            connected: Cell::new(0),
+        }
+    }
+
+    /// Whether all places are taken.
+    fn is_full(&self) -> bool {
+        self.max.is_some_and(|max| self.connected.get() >= max)
+    }
+
+    /// Takes a place for a new client. Returns false if all places are taken.
+    fn admit(&self) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.connected.set(self.connected.get() + 1);
+        true
+    }
+
+    /// Frees the place of a client that left.
+    fn leave(&self) {
+        self.connected.set(self.connected.get() - 1);
+    }
+}
+
+/// Sends the whole document instead of further edits to clients that lag too far behind, so the
+/// history does not have to keep the edits they miss. Needs a sender per connection, so
+/// broadcasts go through here once it is enabled.
+struct Resync {
+    /// Revisions a client may lag behind, `None` disables resyncing.
+    max_lag: Option<u32>,
+    senders: RefCell<HashMap<u32, Sender>>,
+}
+
+impl Resync {
+    fn new(max_lag: Option<u32>) -> Self {
+        Resync {
+            max_lag,
+            senders: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn register(&self, out: &Sender) {
+        if self.max_lag.is_some() {
+            self.senders
+                .borrow_mut()
+                .insert(out.connection_id(), out.clone());
+        }
+    }
+
+    fn unregister(&self, id: u32) {
+        self.senders.borrow_mut().remove(&id);
+    }
+
+    /// Sends *frame* to every client, except that lagging clients get a resync message with the
+    /// current document instead, and continue from its revision.
+    /// *seq* is the sequence number of the last broadcast in *frame*.
+    fn broadcast(
+        &self,
+        out: &Sender,
+        editor: &Editor<u32>,
+        frame: String,
+        seq: u64,
+    ) -> ws::Result<()> {
+        let max_lag = match self.max_lag {
+            Some(max_lag) => max_lag,
+            None => return out.broadcast(frame),
+        };
+        let lagging = lagging_clients(&editor.clients_at_revision(), editor.rev(), max_lag);
+        for (id, sender) in self.senders.borrow().iter() {
+            if lagging.contains(id) {
+                sender.send(resync_json(&editor.connect(*id), seq).to_string())?;
+            } else {
+                sender.send(frame.clone())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The clients among *revs* that are more than *max_lag* revisions behind revision *rev*.
+fn lagging_clients(revs: &HashMap<u32, u32>, rev: u32, max_lag: u32) -> Vec<u32> {
+    revs.iter()
+        .filter(|&(_, &client_rev)| rev.saturating_sub(client_rev) > max_lag)
+        .map(|(&id, _)| id)
+        .collect()
+}
+
+/// The message replacing a lagging client's document, the connect status marked as a resync.
+/// It carries the sequence number of the broadcasts it replaces.
+fn resync_json(status: &ConnectStatus, seq: u64) -> Json {
+    let mut json = serde_json::to_value(status).unwrap();
+    json["type"] = json!("resync");
+    json["seq"] = json!(seq);
+    json
+}
+
+/// Numbers broadcast messages in the order they are sent, starting at 1, so clients can detect
+/// a dropped or reordered message. Unlike revisions, every broadcast gets a number, also within
+/// a batch.
+struct Sequence {
+    last: Cell<u64>,
+}
+
+impl Sequence {
+    fn new() -> Self {
+        Sequence { last: Cell::new(0) }
+    }
+
+    /// Adds the next sequence number to a message as `seq`.
+    fn stamp(&self, mut message: Json) -> Json {
+        let seq = self.last.get() + 1;
+        self.last.set(seq);
+        message["seq"] = json!(seq);
+        message
+    }
+
+    /// Number of the latest broadcast, 0 before the first.
+    fn last(&self) -> u64 {
+        self.last.get()
+    }
+}
+
+/// Decides when to merge the pieces of the table, which splits and deletes leave behind.
+/// Merging happens after an edit, at most once per interval.
+struct MergeSchedule {
+    /// `None` disables merging.
+    interval: Option<Duration>,
+    last: Cell<Instant>,
+}
+
+impl MergeSchedule {
+    fn new(interval: Option<Duration>, now: Instant) -> Self {
+        MergeSchedule {
+            interval,
+            last: Cell::new(now),
+        }
+    }
+
+    /// Returns true if the pieces should be merged now, and starts the next interval if so.
+    fn due(&self, now: Instant) -> bool {
+        match self.interval {
+            Some(interval) if now - self.last.get() >= interval => {
+                self.last.set(now);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Counts applied edits for `--profile`, which prints statistics once per interval.
+///
+/// The statistics are printed from a timeout of one connection, the owner of the timer. When it
+/// closes, the next connection that opens or sends a message takes the timer over.
+struct Profile {
+    /// `None` disables profiling.
+    interval: Option<Duration>,
+    /// Start of the current window and the edits applied in it.
+    window: Cell<(Instant, u32)>,
+    /// Connection whose timeout prints the statistics.
+    owner: Cell<Option<u32>>,
+}
+
+impl Profile {
+    fn new(interval: Option<Duration>, now: Instant) -> Self {
+        Profile {
+            interval,
+            window: Cell::new((now, 0)),
+            owner: Cell::new(None),
+        }
+    }
+
+    /// Counts an applied edit.
+    fn edit(&self) {
+        let (start, edits) = self.window.get();
+        self.window.set((start, edits + 1));
+    }
+
+    /// Returns the edits per second of the window that ends at *now*, and starts the next one.
+    fn report(&self, now: Instant) -> f64 {
+        let (start, edits) = self.window.get();
+        self.window.set((now, 0));
+        let elapsed = (now - start).as_secs_f64();
+        if elapsed > 0.0 {
+            f64::from(edits) / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    /// Makes connection *id* the owner of the timer if profiling is enabled and there is none.
+    /// Returns the interval in milliseconds if it needs to schedule the timeout.
+    fn claim(&self, id: u32) -> Option<u64> {
+        let interval = self.interval?;
+        if self.owner.get().is_some() {
+            return None;
+        }
+        self.owner.set(Some(id));
+        Some(interval.as_millis() as u64)
+    }
+
+    /// Lets another connection take the timer over once connection *id* closes.
+    fn release(&self, id: u32) {
+        if self.owner.get() == Some(id) {
+            self.owner.set(None);
+        }
+    }
+}
+
+/// The line `--profile` prints, with the edit rate of the last window.
+fn profile_line(edits_per_sec: f64, editor: &Editor<u32>) -> String {
+    let stats = editor.stats();
+    format!(
+        "{:.1} edits/s, rev {}, {} clients, {} pieces, {} bytes of buffer overhead",
+        edits_per_sec, stats.rev, stats.clients, stats.pieces, stats.buffer_overhead
+    )
+}
+
+/// Applies an edit from a client. In snapshot mode, the edit is acknowledged like one cancelled by
+/// a concurrent edit, so the document never changes.
+fn apply_edit(
+    editor: &Editor<u32>,
+    id: u32,
+    edit: Edit,
+    snapshot_mode: bool,
+) -> Result<Edit, EditError> {
+    if snapshot_mode {
+        editor.edit(
+            id,
+            Edit {
+                action: EditAction::Noop,
+                ..edit
+            },
+        )
+    } else {
+        editor.edit(id, edit)
+    }
+}
+
+/// Structured access log with one JSON object per line for every connect, edit and disconnect,
+/// see `--json-lines`. It never contains document content.
+struct AccessLog {
+    out: RefCell<Box<dyn Write>>,
+}
+
+impl AccessLog {
+    /// Opens the log at *path*, appending to an existing file, or stdout for `-`.
+    fn open(path: &str) -> io::Result<Self> {
+        let out: Box<dyn Write> = if path == "-" {
+            Box::new(io::stdout())
+        } else {
+            Box::new(
+                fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?,
+            )
+        };
+        Ok(AccessLog {
+            out: RefCell::new(out),
+        })
+    }
+
+    fn write(&self, connection: u32, event: Json) {
+        let line = access_line(SystemTime::now(), connection, event);
+        let mut out = self.out.borrow_mut();
+        if let Err(e) = writeln!(out, "{}", line).and_then(|_| out.flush()) {
+            eprintln!("Could not write the access log: {}", e);
+        }
+    }
+}
+
+/// Adds the time in seconds since the Unix epoch and the connection id to a log *event*.
+fn access_line(time: SystemTime, connection: u32, mut event: Json) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    event["time"] = json!(since_epoch.as_secs_f64());
+    event["connection"] = json!(connection);
+    event.to_string()
+}
+
+/// The kind of an edit action for the access log, without its content.
+fn action_kind(action: &EditAction) -> &'static str {
+    match *action {
+        EditAction::Insert(_) => "insert",
+        EditAction::InsertMany(_) => "insert_many",
+        EditAction::Delete(_) => "delete",
+        EditAction::DeleteExpecting(_) => "delete_expecting",
+        EditAction::Replace(..) => "replace",
+        EditAction::Noop => "noop",
+    }
+}
+
+/// Log event for an edit message. *received* is the edit as sent, `None` if it could not be
+/// parsed. The result is `ok` or the failure code.
+fn edit_event(received: Option<&Edit>, result: Result<(), Failure>) -> Json {
+    let mut event = json!({
+        "event": "edit",
+        "result": result.err().map_or("ok", |failure| failure.code()),
+    });
+    if let Some(edit) = received {
+        event["pos"] = json!(edit.pos);
+        event["rev"] = json!(edit.rev);
+        event["action"] = json!(action_kind(&edit.action));
+    }
+    event
+}
+
+/// Parses a message from a client, which is dispatched on its `"type"`. Messages without one are
+/// edits.
+fn parse_message(msg: &Message) -> Result<Json, Failure> {
+    let text = msg.as_text().or(Err(Failure::InvalidMessage))?;
+    serde_json::from_str(text).or(Err(Failure::InvalidJson))
+}
+
+/// Answers `{"type":"ping","nonce":...}` messages with a pong carrying the same nonce,
+/// so clients can measure the round-trip time.
+fn pong(json: &Json) -> String {
+    json!({"type": "pong", "nonce": json["nonce"]}).to_string()
+}
+
+/// Answers `{"type":"read_range","start":...,"end":...}` messages with the text between the two
+/// byte positions, so clients can fetch just the part they show.
+fn read_range(json: &Json, editor: &Editor<u32>) -> String {
+    let (start, end) = match (json["start"].as_u64(), json["end"].as_u64()) {
+        (Some(start), Some(end)) => (start as usize, end as usize),
+        _ => return Failure::InvalidMessage.to_json().to_string(),
+    };
+    let response = match editor.buffer_range(start, end) {
+        Ok(text) => json!({"type": "range", "start": start, "end": end, "text": text}),
+        Err(e) => Failure::Edit(e).to_json(),
+    };
+    response.to_string()
+}
+
+/// Handles `{"type":"ack","rev":...}` messages, which clients send to acknowledge a revision
+/// without editing, see `Editor::acknowledge_public`.
+fn client_ack(json: &Json, editor: &Editor<u32>, id: u32) -> Result<(), Failure> {
+    match json["rev"].as_u64() {
+        Some(rev) => editor
+            .acknowledge_public(id, rev as u32)
+            .map_err(Failure::Edit),
+        None => Err(Failure::InvalidMessage),
+    }
+}
+
+/// Answers `{"type":"read"}` messages with the whole document as
+/// `{"type":"buffer","rev":...,"buffer":...}`, e.g. for clients that just made an edit.
+fn read_buffer(editor: &Editor<u32>) -> String {
+    json!({"type": "buffer", "rev": editor.rev(), "buffer": editor.buffer()}).to_string()
+}
+
+/// Answers `{"type":"edits_since","rev":...}` messages with the edits applied after that
+/// revision, for clients that sync incrementally. If the history no longer reaches back that far,
+/// the answer has `"resync": true` instead, and the client needs the full buffer.
+fn edits_since(json: &Json, editor: &Editor<u32>) -> String {
+    let rev = match json["rev"].as_u64() {
+        Some(rev) => rev as u32,
+        None => return Failure::InvalidMessage.to_json().to_string(),
+    };
+    let response = match editor.pending_edits(rev) {
+        Some(edits) => json!({"type": "edits", "rev": rev, "edits": edits}),
+        None => json!({"type": "edits", "rev": rev, "resync": true}),
+    };
+    response.to_string()
+}
+
+/// Picks the subprotocol for a connection. Clients that do not ask for one get the current format
+/// without a protocol header. Fails if none of the requested protocols is supported.
+fn negotiate(req: &Request) -> Result<Option<&'static str>, String> {
+    let requested = req
+        .protocols()
+        .map_err(|_| "invalid protocol header".to_string())?;
+    if requested.is_empty() {
+        return Ok(None);
+    }
+    PROTOCOLS
+        .iter()
+        .find(|protocol| requested.contains(protocol))
+        .map(|&protocol| Some(protocol))
+        .ok_or_else(|| {
+            format!(
+                "unsupported protocol {}, supported: {}",
+                requested.join(", "),
+                PROTOCOLS.join(", ")
+            )
+        })
+}
+
+/// Answers the handshake of a new connection, see `negotiate`, or rejects it with 503 if all
+/// places are taken. The place is only taken in `on_open`, because ws does not call `on_close`
+/// for connections that fail or close during the handshake.
+fn ws_handshake(req: &Request, limit: &ClientLimit) -> ws::Result<Response> {
+    match negotiate(req) {
+        Ok(_) if limit.is_full() => Ok(Response::new(
+            503,
+            "Service Unavailable",
+            Vec::from("503 - too many clients"),
+        )),
+        Ok(protocol) => {
+            let mut response = Response::from_request(req)?;
+            if let Some(protocol) = protocol {
+                response.set_protocol(protocol);
+            }
+            Ok(response)
+        }
+        Err(reason) => Ok(Response::new(400, "Bad Request", Vec::from(reason))),
+    }
+}
+
+/// Checks that a request to one of the `/debug` endpoints has an
+/// `Authorization: Bearer <admin token>` header. Otherwise returns the error response, which is
+/// a 404 if the endpoints are disabled.
+fn authorize(req: &Request, admin_token: Option<&str>) -> Result<(), Response> {
+    let token = match admin_token {
+        Some(token) => token,
+        None => {
+            return Err(Response::new(
+                404,
+                "Not Found",
+                Vec::from("404 - not found"),
+            ))
+        }
+    };
+    let expected = format!("Bearer {}", token);
+    if req.header("authorization").map(Vec::as_slice) != Some(expected.as_bytes()) {
+        return Err(Response::new(
+            403,
+            "Forbidden",
+            Vec::from("403 - forbidden"),
+        ));
+    }
+    Ok(())
+}
+
+fn json_response(body: Vec<u8>) -> Response {
+    let mut response = Response::new(200, "OK", body);
+    response
+        .headers_mut()
+        .push(("Content-Type".to_string(), Vec::from("application/json")));
+    response
+}
+
+/// Answers `GET /debug/pt` with the piece table statistics as JSON, see `authorize`.
+fn debug_pt(req: &Request, editor: &Editor<u32>, admin_token: Option<&str>) -> Response {
+    if let Err(response) = authorize(req, admin_token) {
+        return response;
+    }
+    json_response(serde_json::to_vec(&editor.table_stats()).unwrap())
+}
+
+/// Answers `GET /debug/stats` with `Editor::stats` as JSON, for monitoring. See `authorize`.
+fn debug_stats(req: &Request, editor: &Editor<u32>, admin_token: Option<&str>) -> Response {
+    if let Err(response) = authorize(req, admin_token) {
+        return response;
+    }
+    json_response(serde_json::to_vec(&editor.stats()).unwrap())
+}
+
+/// Answers `GET /debug/dump` with the whole editor state, which `--load` restores on startup.
+/// See `authorize`.
+fn debug_dump(req: &Request, editor: &Editor<u32>, admin_token: Option<&str>) -> Response {
+    if let Err(response) = authorize(req, admin_token) {
+        return response;
+    }
+    let mut body = Vec::new();
+    editor.dump(&mut body).unwrap();
+    json_response(body)
 }
 
 impl<'a> Server<'a> {
-    fn handle_edit(&mut self, msg: &Message) -> Result<String, &'static str> {
-        let edit: Edit = serde_json::from_str(msg.as_text().or(Err("invalid message"))?)
-            .or(Err("invalid json"))?;
-        self.editor
-            .edit(self.out.connection_id(), edit)
-            .map(|e| serde_json::to_string(&e).unwrap())
+    fn handle_edit(&mut self, json: &Json) -> Result<Edit, Failure> {
+        let edit = match Edit::deserialize(json).or(Err(Failure::InvalidJson)) {
+            Ok(edit) => edit,
+            Err(failure) => {
+                self.log(edit_event(None, Err(failure)));
+                return Err(failure);
+            }
+        };
+        let received = self.access_log.map(|_| edit.clone());
+        let result = apply_edit(
+            self.editor,
+            self.out.connection_id(),
+            edit,
+            self.snapshot_mode,
+        )
+        .map_err(Failure::Edit);
+        if let Some(received) = received {
+            let outcome = result.as_ref().map(|_| ()).map_err(|&failure| failure);
+            self.log(edit_event(Some(&received), outcome));
+        }
+        result
+    }
+
+    /// Schedules printing the `--profile` statistics from this connection, unless another
+    /// connection already does.
+    fn claim_profile(&self) -> ws::Result<()> {
+        match self.profile.claim(self.out.connection_id()) {
+            Some(ms) => self.out.timeout(ms, PROFILE_TOKEN),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes an event to the access log, if there is one.
+    fn log(&self, event: Json) {
+        if let Some(log) = self.access_log {
+            log.write(self.out.connection_id(), event);
+        }
+    }
+
+    /// The edit as sent to clients, with the sender as author.
+    fn edit_json(&self, edit: &Edit) -> Json {
+        let mut json = serde_json::to_value(edit).unwrap();
+        json["author"] = json!(self.out.connection_id());
+        json
+    }
+
+    /// Sends an edit to all clients, right away or as part of the next batch.
+    fn broadcast(&self, edit: Json) -> ws::Result<()> {
+        let edit = self.sequence.stamp(edit);
+        if self.batch.window == 0 {
+            let seq = self.sequence.last();
+            self.resync
+                .broadcast(&self.out, self.editor, edit.to_string(), seq)
+        } else if self.batch.push(edit) {
+            self.out.timeout(self.batch.window, BATCH_TOKEN)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Writes an applied edit to the journal, if there is one.
+    fn journal(&self, edit: &Edit) {
+        if let Some(journal) = self.journal {
+            if let Err(e) = journal.borrow_mut().append(edit, self.editor) {
+                eprintln!("Could not write to the journal: {}", e);
+            }
+        }
+    }
+
+    fn flush_batch(&self) -> ws::Result<()> {
+        match self.batch.take() {
+            Some(frame) => {
+                let seq = self.sequence.last();
+                self.resync.broadcast(&self.out, self.editor, frame, seq)
+            }
+            None => Ok(()),
+        }
     }
 }
 
 impl<'a> Handler for Server<'a> {
     fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
+        // The last place may have been taken since the handshake was answered.
+        if !self.limit.admit() {
+            return self
+                .out
+                .close_with_reason(ws::CloseCode::Again, "too many clients");
+        }
+        self.admitted = true;
         let status = self.editor.connect(self.out.connection_id());
+        self.log(json!({"event": "connect", "rev": status.rev, "clients": status.clients}));
+        self.resync.register(&self.out);
+        self.claim_profile()?;
         self.out.send(serde_json::to_string(&status).unwrap())
     }
 
     fn on_message(&mut self, msg: Message) -> ws::Result<()> {
-        match self.handle_edit(&msg) {
-            Ok(response) => {
-                let json = json!({"success": true});
-                self.out.send(json.to_string())?;
-                self.out.broadcast(response)
+        self.claim_profile()?;
+        let json = match parse_message(&msg) {
+            Ok(json) => json,
+            Err(failure) => {
+                self.log(edit_event(None, Err(failure)));
+                return self.out.send(failure.to_json().to_string());
             }
-            Err(reason) => {
-                let json = json!({"success": false,"reason": reason});
-                self.out.send(json.to_string())
+        };
+        match json["type"].as_str() {
+            Some("ping") => return self.out.send(pong(&json)),
+            Some("read_range") => return self.out.send(read_range(&json, self.editor)),
+            Some("edits_since") => return self.out.send(edits_since(&json, self.editor)),
+            Some("read") => return self.out.send(read_buffer(self.editor)),
+            Some("ack") => {
+                // only failed acks are answered
+                return match client_ack(&json, self.editor, self.out.connection_id()) {
+                    Ok(()) => Ok(()),
+                    Err(failure) => self.out.send(failure.to_json().to_string()),
+                };
             }
+            _ => {}
         }
+        match self.handle_edit(&json) {
+            Ok(edit) => {
+                // The author lets the sender recognize its edit, also inside a batch.
+                let json = json!({"success": true, "author": self.out.connection_id()});
+                self.out.send(json.to_string())?;
+                if edit.action == EditAction::Noop {
+                    // Nothing changed for the others, only the sender needs the revision.
+                    // It must not overtake edits that are still waiting in the batch.
+                    self.flush_batch()?;
+                    self.out.send(self.edit_json(&edit).to_string())
+                } else {
+                    self.journal(&edit);
+                    let now = Instant::now();
+                    if self.merge.due(now) {
+                        self.editor.merge_pieces();
+                    }
+                    self.profile.edit();
+                    self.broadcast(self.edit_json(&edit))
+                }
+            }
+            Err(failure) => self.out.send(failure.to_json().to_string()),
+        }
+    }
+
+    fn on_timeout(&mut self, token: Token) -> ws::Result<()> {
+        if token == BATCH_TOKEN {
+            self.flush_batch()?;
+        } else if token == PROFILE_TOKEN {
+            let rate = self.profile.report(Instant::now());
+            eprintln!("{}", profile_line(rate, self.editor));
+            self.profile.release(self.out.connection_id());
+            self.claim_profile()?;
+        }
+        Ok(())
     }
 
-    fn on_close(&mut self, _: ws::CloseCode, _: &str) {
+    fn on_close(&mut self, code: ws::CloseCode, _: &str) {
+        if !self.admitted {
+            return;
+        }
         self.editor.disconnect(&self.out.connection_id());
+        let code: u16 = code.into();
+        self.log(json!({"event": "disconnect", "code": code}));
+        self.resync.unregister(self.out.connection_id());
+        self.profile.release(self.out.connection_id());
+        self.limit.leave();
+        self.admitted = false;
+        // The timeout for a batch scheduled by this connection does not fire anymore.
+        if let Err(e) = self.flush_batch() {
+            eprintln!("Could not send batched edits: {}", e);
+        }
     }
 
     fn on_request(&mut self, req: &Request) -> ws::Result<Response> {
         match req.resource() {
             "/" => Ok(Response::new(200, "OK", Vec::from(EDITOR_HTML))),
             "/editor.js" => Ok(Response::new(200, "OK", Vec::from(EDITOR_JS))),
-            "/ws" => Response::from_request(req),
+            "/ws" => ws_handshake(req, self.limit),
+            "/debug/pt" => Ok(debug_pt(req, self.editor, self.admin_token)),
+            "/debug/stats" => Ok(debug_stats(req, self.editor, self.admin_token)),
+            "/debug/dump" => Ok(debug_dump(req, self.editor, self.admin_token)),
             _ => Ok(Response::new(
                 404,
                 "Not Found",
@@ -68,6 +790,22 @@ impl<'a> Handler for Server<'a> {
     }
 }
 
+/// Creates the editor from `--load`, `--file` or `--seed`, which clap keeps from being combined,
+/// or an empty one.
+fn initial_editor(load: Option<&str>, file: Option<&str>, seed: Option<&str>) -> Editor<u32> {
+    match (load, file, seed) {
+        (Some(path), _, _) => {
+            let file = File::open(path).expect("Could not open the dump");
+            Editor::load(BufReader::new(file)).expect("Could not load the dump")
+        }
+        (None, Some(path), _) => {
+            Editor::from(fs::read_to_string(path).expect("Could not read the file"))
+        }
+        (None, None, Some(seed)) => Editor::from(seed),
+        (None, None, None) => Editor::new(),
+    }
+}
+
 fn main() {
     env_logger::init();
 
@@ -79,6 +817,72 @@ fn main() {
             .arg(Arg::with_name("port")
                 .default_value("8080")
                 .long("port"))
+            .arg(Arg::with_name("batch-window")
+                .long("batch-window")
+                .value_name("ms")
+                .default_value("0")
+                .help("Collects edits for this long and broadcasts them as one frame, 0 disables batching"))
+            .arg(Arg::with_name("grapheme-boundaries")
+                .long("grapheme-boundaries")
+                .help("Rejects edits that would split a grapheme cluster, like half of an emoji"))
+            .arg(Arg::with_name("merge-interval")
+                .long("merge-interval")
+                .value_name("ms")
+                .help("Merges adjacent pieces of the document at most this often, after edits"))
+            .arg(Arg::with_name("profile")
+                .long("profile")
+                .value_name("secs")
+                .help("Prints the edit rate and editor statistics this often, also without edits"))
+            .arg(Arg::with_name("file")
+                .long("file")
+                .value_name("path")
+                .conflicts_with("replay")
+                .help("Starts with the content of this file"))
+            .arg(Arg::with_name("seed")
+                .long("seed")
+                .value_name("text")
+                .conflicts_with_all(&["file", "load", "replay"])
+                .help("Starts with this text, e.g. for demos"))
+            .arg(Arg::with_name("snapshot-mode")
+                .long("snapshot-mode")
+                .conflicts_with("journal")
+                .help("Acknowledges edits without applying them, for load tests"))
+            .arg(Arg::with_name("max-clients")
+                .long("max-clients")
+                .value_name("N")
+                .help("Rejects new connections with 503 while N clients are connected"))
+            .arg(Arg::with_name("resync-lag")
+                .long("resync-lag")
+                .value_name("revisions")
+                .help("Sends the whole document instead of the next edit to clients that did not \
+                       acknowledge any of this many revisions, e.g. with a Noop edit"))
+            .arg(Arg::with_name("journal")
+                .long("journal")
+                .value_name("path")
+                .help("Appends applied edits to this file, one JSON object per line"))
+            .arg(Arg::with_name("journal-max-bytes")
+                .long("journal-max-bytes")
+                .value_name("bytes")
+                .requires("journal")
+                .help("Moves the journal to <path>.1 and starts a new one once it is this big"))
+            .arg(Arg::with_name("replay")
+                .long("replay")
+                .requires("journal")
+                .help("Restores the document from the journal on startup"))
+            .arg(Arg::with_name("load")
+                .long("load")
+                .value_name("path")
+                .conflicts_with_all(&["file", "replay"])
+                .help("Restores the state saved from /debug/dump on startup"))
+            .arg(Arg::with_name("json-lines")
+                .long("json-lines")
+                .value_name("path")
+                .help("Writes an access log of connects, edits and disconnects as JSON lines to \
+                       this file (- for stdout), without document content"))
+            .arg(Arg::with_name("admin-token")
+                .long("admin-token")
+                .takes_value(true)
+                .help("Enables the /debug endpoints for requests with this bearer token"))
             .get_matches()
     };
 
@@ -88,9 +892,607 @@ fn main() {
         .parse()
         .expect("Port must be a number");
 
-    let editor = Editor::new();
+    let batch_window: u64 = matches
+        .value_of("batch-window")
+        .unwrap()
+        .parse()
+        .expect("Batch window must be a number");
+
+    let merge_interval: Option<u64> = matches
+        .value_of("merge-interval")
+        .map(|ms| ms.parse().expect("Merge interval must be a number"));
+
+    let profile_interval: Option<u64> = matches
+        .value_of("profile")
+        .map(|secs| secs.parse().expect("Profile interval must be a number"));
+
+    let max_clients: Option<usize> = matches.value_of("max-clients").map(|max| {
+        max.parse()
+            .expect("Maximum number of clients must be a number")
+    });
+
+    let resync_lag: Option<u32> = matches
+        .value_of("resync-lag")
+        .map(|lag| lag.parse().expect("Resync lag must be a number"));
+
+    let journal_max_bytes: Option<u64> = matches
+        .value_of("journal-max-bytes")
+        .map(|bytes| bytes.parse().expect("Journal size must be a number"));
+
+    let journal_path = matches.value_of("journal");
+    let editor = match journal_path {
+        Some(path) if matches.is_present("replay") => match File::open(path) {
+            Ok(file) => {
+                journal::replay(BufReader::new(file)).expect("Could not replay the journal")
+            }
+            // nothing was journaled yet, e.g. on the first start
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Editor::new(),
+            Err(e) => panic!("Could not open the journal: {}", e),
+        },
+        _ => initial_editor(
+            matches.value_of("load"),
+            matches.value_of("file"),
+            matches.value_of("seed"),
+        ),
+    };
+    editor.set_grapheme_boundaries(matches.is_present("grapheme-boundaries"));
+    let batch = Batch::new(batch_window);
+    let limit = ClientLimit::new(max_clients);
+    let resync = Resync::new(resync_lag);
+    let sequence = Sequence::new();
+    let profile = Profile::new(profile_interval.map(Duration::from_secs), Instant::now());
+    let merge = MergeSchedule::new(merge_interval.map(Duration::from_millis), Instant::now());
+    let journal = journal_path.map(|path| {
+        RefCell::new(
+            Journal::open(path, journal_max_bytes, &editor).expect("Could not open the journal"),
+        )
+    });
+    let access_log = matches
+        .value_of("json-lines")
+        .map(|path| AccessLog::open(path).expect("Could not open the access log"));
     listen(("0.0.0.0", port), |out| Server {
         editor: &editor,
+        batch: &batch,
+        journal: journal.as_ref(),
+        limit: &limit,
+        merge: &merge,
+        resync: &resync,
+        sequence: &sequence,
+        profile: &profile,
+        access_log: access_log.as_ref(),
+        admitted: false,
+        snapshot_mode: matches.is_present("snapshot-mode"),
+        admin_token: matches.value_of("admin-token"),
         out,
     }).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failure_json() {
+        let failures = [
+            (
+                Failure::InvalidMessage,
+                "invalid_message",
+                "invalid message",
+            ),
+            (Failure::InvalidJson, "invalid_json", "invalid json"),
+            (
+                Failure::Edit(EditError::OldRevision),
+                "old_revision",
+                "old revision",
+            ),
+            (
+                Failure::Edit(EditError::FutureRevision),
+                "future_revision",
+                "future revision",
+            ),
+            (
+                Failure::Edit(EditError::OutOfRange),
+                "out_of_range",
+                "out of range",
+            ),
+            (
+                Failure::Edit(EditError::NotCharBoundary),
+                "not_char_boundary",
+                "not a char boundary",
+            ),
+            (
+                Failure::Edit(EditError::InvalidIndex),
+                "invalid_index",
+                "invalid index",
+            ),
+            (
+                Failure::Edit(EditError::NotImplemented),
+                "not_implemented",
+                "not implemented",
+            ),
+            (
+                Failure::Edit(EditError::SplitsGrapheme),
+                "splits_grapheme",
+                "splits a grapheme",
+            ),
+            (Failure::Edit(EditError::Conflict), "conflict", "conflict"),
+        ];
+        for &(failure, code, reason) in &failures {
+            assert_eq!(
+                failure.to_json(),
+                json!({"success": false, "code": code, "reason": reason})
+            );
+        }
+    }
+
+    fn debug_request(token: &str) -> Request {
+        debug_request_for("pt", token)
+    }
+
+    fn debug_request_for(endpoint: &str, token: &str) -> Request {
+        let raw = format!(
+            "GET /debug/{} HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n",
+            endpoint, token
+        );
+        Request::parse(raw.as_bytes()).unwrap().unwrap()
+    }
+
+    fn ws_request(protocols: Option<&str>) -> Request {
+        let mut raw = "GET /ws HTTP/1.1\r\n".to_string();
+        if let Some(protocols) = protocols {
+            raw += &format!("Sec-WebSocket-Protocol: {}\r\n", protocols);
+        }
+        raw += "\r\n";
+        Request::parse(raw.as_bytes()).unwrap().unwrap()
+    }
+
+    #[test]
+    fn protocol_negotiation() {
+        assert_eq!(negotiate(&ws_request(None)), Ok(None));
+        assert_eq!(
+            negotiate(&ws_request(Some("avian.v2, avian.v1"))),
+            Ok(Some("avian.v1"))
+        );
+        assert_eq!(
+            negotiate(&ws_request(Some("avian.v2"))),
+            Err("unsupported protocol avian.v2, supported: avian.v1".to_string())
+        );
+    }
+
+    #[test]
+    fn seeded_editor() {
+        let editor = initial_editor(None, None, Some("seeded\ntext"));
+        let status = editor.connect(0);
+        assert_eq!((status.rev, status.buffer.as_str()), (0, "seeded\ntext"));
+        assert_eq!(initial_editor(None, None, None).connect(0).buffer, "");
+    }
+
+    #[test]
+    fn parsed_messages() {
+        assert_eq!(
+            parse_message(&Message::text(r#"{"type":"ping","nonce":1}"#)),
+            Ok(json!({"type": "ping", "nonce": 1}))
+        );
+        assert_eq!(
+            parse_message(&Message::text("{")),
+            Err(Failure::InvalidJson)
+        );
+        assert_eq!(
+            parse_message(&Message::binary(vec![0xff])),
+            Err(Failure::InvalidMessage)
+        );
+        assert_eq!(
+            pong(&json!({"type": "ping", "nonce": 1})),
+            json!({"type": "pong", "nonce": 1}).to_string()
+        );
+    }
+
+    #[test]
+    fn read_range_message() {
+        let editor = Editor::from("some text");
+        let read = |json: Json| read_range(&json, &editor);
+        let response = read(json!({"type": "read_range", "start": 5, "end": 9}));
+        assert_eq!(
+            serde_json::from_str::<Json>(&response).unwrap(),
+            json!({"type": "range", "start": 5, "end": 9, "text": "text"})
+        );
+        let response = read(json!({"type": "read_range", "start": 5, "end": 10}));
+        assert_eq!(
+            serde_json::from_str::<Json>(&response).unwrap(),
+            Failure::Edit(EditError::InvalidIndex).to_json()
+        );
+        let response = read(json!({"type": "read_range", "start": 5}));
+        assert_eq!(
+            serde_json::from_str::<Json>(&response).unwrap(),
+            Failure::InvalidMessage.to_json()
+        );
+    }
+
+    #[test]
+    fn ack_message() {
+        let editor = Editor::from("acks");
+        editor.connect(0);
+        editor.connect(1);
+        let edit = Edit {
+            rev: 0,
+            pos: 0,
+            action: EditAction::Insert("a".to_string()),
+        };
+        editor.edit(0, edit).unwrap();
+        let ack = |id, json: Json| client_ack(&json, &editor, id);
+        assert_eq!(editor.backlog_len(), 1);
+        assert_eq!(ack(0, json!({"type": "ack", "rev": 1})), Ok(()));
+        assert_eq!(ack(1, json!({"type": "ack", "rev": 1})), Ok(()));
+        assert_eq!(editor.backlog_len(), 0);
+        assert_eq!(
+            ack(1, json!({"type": "ack", "rev": 2})),
+            Err(Failure::Edit(EditError::FutureRevision))
+        );
+        assert_eq!(ack(1, json!({"type": "ack"})), Err(Failure::InvalidMessage));
+    }
+
+    #[test]
+    fn access_log_lines() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_500);
+        let line = |event| serde_json::from_str::<Json>(&access_line(time, 7, event)).unwrap();
+        assert_eq!(
+            line(json!({"event": "connect", "rev": 3, "clients": 2})),
+            json!({"event": "connect", "rev": 3, "clients": 2, "time": 1.5, "connection": 7})
+        );
+        let edit = Edit {
+            pos: 4,
+            rev: 3,
+            action: EditAction::Insert("secret".to_string()),
+        };
+        let logged = access_line(time, 7, edit_event(Some(&edit), Ok(())));
+        assert!(!logged.contains("secret"));
+        assert_eq!(
+            serde_json::from_str::<Json>(&logged).unwrap(),
+            json!({
+                "event": "edit",
+                "pos": 4,
+                "rev": 3,
+                "action": "insert",
+                "result": "ok",
+                "time": 1.5,
+                "connection": 7,
+            })
+        );
+        let rejected = edit_event(Some(&edit), Err(Failure::Edit(EditError::FutureRevision)));
+        assert_eq!(rejected["result"], "future_revision");
+        assert_eq!(
+            line(edit_event(None, Err(Failure::InvalidJson))),
+            json!({"event": "edit", "result": "invalid_json", "time": 1.5, "connection": 7})
+        );
+        assert_eq!(
+            line(json!({"event": "disconnect", "code": 1000})),
+            json!({"event": "disconnect", "code": 1000, "time": 1.5, "connection": 7})
+        );
+    }
+
+    #[test]
+    fn read_message() {
+        let editor = Editor::from("whole");
+        assert_eq!(
+            serde_json::from_str::<Json>(&read_buffer(&editor)).unwrap(),
+            json!({"type": "buffer", "rev": 0, "buffer": "whole"})
+        );
+    }
+
+    #[test]
+    fn edits_since_message() {
+        let editor = Editor::from("text");
+        editor.connect(0);
+        let edit = Edit {
+            pos: 4,
+            rev: 0,
+            action: EditAction::Insert("!".to_string()),
+        };
+        let applied = editor.edit(0, edit).unwrap();
+        let request = |json: Json| edits_since(&json, &editor);
+        let response = request(json!({"type": "edits_since", "rev": 0}));
+        assert_eq!(
+            serde_json::from_str::<Json>(&response).unwrap(),
+            json!({"type": "edits", "rev": 0, "edits": [applied]})
+        );
+
+        // the backlog is trimmed once the client acknowledges the edit
+        let ack = Edit {
+            pos: 0,
+            rev: 1,
+            action: EditAction::Noop,
+        };
+        editor.edit(0, ack).unwrap();
+        let response = request(json!({"type": "edits_since", "rev": 0}));
+        assert_eq!(
+            serde_json::from_str::<Json>(&response).unwrap(),
+            json!({"type": "edits", "rev": 0, "resync": true})
+        );
+        let response = request(json!({"type": "edits_since"}));
+        assert_eq!(
+            serde_json::from_str::<Json>(&response).unwrap(),
+            Failure::InvalidMessage.to_json()
+        );
+    }
+
+    #[test]
+    fn resync_lagging_clients() {
+        let editor = Editor::from("lag");
+        editor.connect(0);
+        editor.connect(1);
+        for rev in 0..5 {
+            let edit = Edit {
+                pos: 0,
+                rev,
+                action: EditAction::Insert("x".to_string()),
+            };
+            editor.edit(0, edit).unwrap();
+        }
+        // client 0 based its last edit on revision 4, client 1 never acknowledged anything
+        let revs = editor.clients_at_revision();
+        assert_eq!(lagging_clients(&revs, editor.rev(), 5), Vec::<u32>::new());
+        assert_eq!(lagging_clients(&revs, editor.rev(), 4), vec![1]);
+
+        let resync: Json = resync_json(&editor.connect(1), 7);
+        assert_eq!(resync["type"], "resync");
+        assert_eq!(resync["seq"], 7);
+        assert_eq!(resync["buffer"], "xxxxxlag");
+        assert_eq!(resync["rev"], 5);
+        // the full state resets the client's revision, so it no longer lags
+        let revs = editor.clients_at_revision();
+        assert_eq!(lagging_clients(&revs, editor.rev(), 0), vec![0]);
+
+        // an edit from the future is rejected without acknowledging its revision
+        let edit = Edit {
+            pos: 0,
+            rev: 1_000_000,
+            action: EditAction::Insert("x".to_string()),
+        };
+        assert_eq!(editor.edit(0, edit), Err(EditError::FutureRevision));
+        assert_eq!(editor.clients_at_revision()[&0], 4);
+        // clients ahead of the editor never lag
+        let ahead: HashMap<u32, u32> = vec![(0, 7), (1, 2)].into_iter().collect();
+        assert_eq!(lagging_clients(&ahead, 5, 0), vec![1]);
+    }
+
+    #[test]
+    fn broadcast_sequence() {
+        let sequence = Sequence::new();
+        assert_eq!(sequence.last(), 0);
+        let batch = Batch::new(10);
+        let edit = json!({"pos": 0, "rev": 1, "action": {"Insert": "a"}});
+        assert_eq!(sequence.stamp(edit)["seq"], 1);
+        // batched broadcasts are numbered one by one
+        for _ in 0..2 {
+            let edit = json!({"pos": 0, "rev": 2, "action": {"Delete": 1}});
+            batch.push(sequence.stamp(edit));
+        }
+        let frame: Json = serde_json::from_str(&batch.take().unwrap()).unwrap();
+        let seqs: Vec<_> = frame
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["seq"].clone())
+            .collect();
+        assert_eq!(seqs, vec![json!(2), json!(3)]);
+        assert_eq!(sequence.last(), 3);
+    }
+
+    #[test]
+    fn snapshot_mode() {
+        let editor = Editor::from("frozen");
+        editor.connect(0);
+        let edit = Edit {
+            pos: 0,
+            rev: 0,
+            action: EditAction::Insert("not ".to_string()),
+        };
+        let acked = apply_edit(&editor, 0, edit.clone(), true).unwrap();
+        assert_eq!(acked.action, EditAction::Noop);
+        assert_eq!(acked.rev, 0);
+        assert_eq!(editor.buffer(), "frozen");
+
+        apply_edit(&editor, 0, edit, false).unwrap();
+        assert_eq!(editor.buffer(), "not frozen");
+    }
+
+    #[test]
+    fn debug_endpoint() {
+        let editor = Editor::from("0123456789");
+        editor.connect(0);
+        // alternating inserts split the table into tiny pieces
+        for i in 0..5 {
+            let edit = Edit {
+                pos: 2 * i + 1,
+                rev: i as u32,
+                action: EditAction::Insert("x".to_string()),
+            };
+            editor.edit(0, edit).unwrap();
+        }
+
+        let response = debug_pt(&debug_request("secret"), &editor, Some("secret"));
+        assert_eq!(response.status(), 200);
+        let stats: Json = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            stats,
+            json!({
+                "piece_count": 11,
+                "buffer_len": 15,
+                "logical_len": 15,
+                "piece_lengths": {"1": 10, "4": 1},
+            })
+        );
+
+        let response = debug_pt(&debug_request("guess"), &editor, Some("secret"));
+        assert_eq!(response.status(), 403);
+        let response = debug_pt(&debug_request("secret"), &editor, None);
+        assert_eq!(response.status(), 404);
+    }
+
+    #[test]
+    fn stats_endpoint() {
+        let editor = Editor::from("line\nline");
+        editor.connect(0);
+        let edit = Edit {
+            pos: 4,
+            rev: 0,
+            action: EditAction::Delete(1),
+        };
+        editor.edit(0, edit).unwrap();
+
+        let request = debug_request_for("stats", "secret");
+        let response = debug_stats(&request, &editor, Some("secret"));
+        assert_eq!(response.status(), 200);
+        let stats: Json = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(stats, serde_json::to_value(editor.stats()).unwrap());
+        assert_eq!(stats["lines"], 1);
+        assert_eq!(stats["buffer_overhead"], 1);
+
+        let request = debug_request_for("stats", "guess");
+        assert_eq!(debug_stats(&request, &editor, Some("secret")).status(), 403);
+    }
+
+    #[test]
+    fn dump_round_trip() {
+        let editor = Editor::from("dumped");
+        editor.connect(0);
+        let edit = Edit {
+            pos: 0,
+            rev: 0,
+            action: EditAction::Insert("un".to_string()),
+        };
+        editor.edit(0, edit).unwrap();
+        let request = debug_request_for("dump", "secret");
+        let response = debug_dump(&request, &editor, Some("secret"));
+        assert_eq!(response.status(), 200);
+
+        let loaded: Editor<u32> = Editor::load(response.body()).unwrap();
+        assert_eq!(loaded.connect(1), editor.connect(1));
+        let edit = Edit {
+            pos: avian::END,
+            rev: 1,
+            action: EditAction::Insert("!".to_string()),
+        };
+        loaded.edit(1, edit).unwrap();
+        assert_eq!(loaded.buffer(), "undumped!");
+
+        let response = debug_dump(&debug_request_for("dump", "guess"), &editor, Some("secret"));
+        assert_eq!(response.status(), 403);
+    }
+
+    #[test]
+    fn batched_edits() {
+        let batch = Batch::new(50);
+        assert_eq!(batch.take(), None);
+        let first = json!({"pos": 0, "rev": 1, "action": {"Insert": "a"}, "author": 1});
+        let second = json!({"pos": 1, "rev": 2, "action": {"Delete": 1}, "author": 2});
+        assert!(batch.push(first.clone()));
+        assert!(!batch.push(second.clone()));
+
+        let frame: Json = serde_json::from_str(&batch.take().unwrap()).unwrap();
+        assert_eq!(frame, json!([first, second]));
+        assert_eq!(batch.take(), None);
+        assert!(batch.push(first));
+    }
+
+    #[test]
+    fn profile_window() {
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        let profile = Profile::new(Some(Duration::from_secs(2)), start);
+        for _ in 0..5 {
+            profile.edit();
+        }
+        // five edits in 2.5 seconds
+        assert_eq!(profile.report(start + ms(2500)), 2.0);
+        // windows without edits are reported too
+        assert_eq!(profile.report(start + ms(4500)), 0.0);
+        assert_eq!(profile.report(start + ms(4500)), 0.0);
+
+        // one connection at a time schedules the timer
+        assert_eq!(profile.claim(1), Some(2000));
+        assert_eq!(profile.claim(2), None);
+        profile.release(2);
+        assert_eq!(profile.claim(2), None);
+        profile.release(1);
+        assert_eq!(profile.claim(2), Some(2000));
+        assert_eq!(Profile::new(None, start).claim(1), None);
+
+        let editor = Editor::from("profiled");
+        editor.connect(0);
+        let edit = Edit {
+            pos: 0,
+            rev: 0,
+            action: EditAction::Delete(2),
+        };
+        editor.edit(0, edit).unwrap();
+        assert_eq!(
+            profile_line(2.0, &editor),
+            "2.0 edits/s, rev 1, 1 clients, 1 pieces, 2 bytes of buffer overhead"
+        );
+    }
+
+    #[test]
+    fn merge_schedule() {
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let merge = MergeSchedule::new(Some(second), start);
+        assert!(!merge.due(start));
+        assert!(merge.due(start + second));
+        assert!(!merge.due(start + second + second / 2));
+        assert!(merge.due(start + 3 * second));
+        assert!(!MergeSchedule::new(None, start).due(start + 3 * second));
+
+        // merging leaves the document alone
+        let editor = Editor::from("ab");
+        editor.connect(0);
+        for rev in 0..4 {
+            let edit = Edit {
+                pos: 1,
+                rev: 2 * rev,
+                action: EditAction::Insert("x".to_string()),
+            };
+            editor.edit(0, edit).unwrap();
+            let delete = Edit {
+                pos: 1,
+                rev: 2 * rev + 1,
+                action: EditAction::Delete(1),
+            };
+            editor.edit(0, delete).unwrap();
+        }
+        let pieces = editor.table_stats().piece_count;
+        assert!(editor.merge_pieces() > 0);
+        assert!(editor.table_stats().piece_count < pieces);
+        assert_eq!(editor.buffer(), "ab");
+        assert_eq!(editor.connect(1).rev, 8);
+    }
+
+    #[test]
+    fn client_limit() {
+        let limit = ClientLimit::new(Some(2));
+        assert!(limit.admit());
+        assert!(limit.admit());
+        assert!(!limit.admit());
+        limit.leave();
+        assert!(limit.admit());
+        assert!(!limit.admit());
+
+        let unlimited = ClientLimit::new(None);
+        assert!((0..100).all(|_| unlimited.admit()));
+    }
+
+    #[test]
+    fn handshake_keeps_places() {
+        let limit = ClientLimit::new(Some(1));
+        // without a key, the handshake fails after negotiating the protocol
+        assert!(ws_handshake(&ws_request(None), &limit).is_err());
+        let raw = "GET /ws HTTP/1.1\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        let request = Request::parse(raw.as_bytes()).unwrap().unwrap();
+        assert_eq!(ws_handshake(&request, &limit).unwrap().status(), 101);
+        // answering the handshake takes no place, so neither attempt leaked one
+        assert!(limit.admit());
+        assert_eq!(ws_handshake(&request, &limit).unwrap().status(), 503);
+        limit.leave();
+        assert_eq!(ws_handshake(&request, &limit).unwrap().status(), 101);
+    }
+}
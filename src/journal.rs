@@ -0,0 +1,185 @@
+//! Append-only journal of applied edits, one JSON object per line.
+//!
+//! Every journal file starts with a snapshot of the document, followed by the edits applied
+//! after it, so the latest file alone is enough to restore the document. Once the file grows
+//! past its size limit, it is renamed to `<path>.1` and a new file starts with a fresh snapshot.
+
+use std::fs::{self, File, OpenOptions};
+use std::hash::Hash;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json;
+
+use super::{removed_text, Edit, Editor};
+
+/// The document at revision `rev`.
+#[derive(Debug, Serialize, Deserialize)]
+struct Snapshot {
+    rev: u32,
+    snapshot: String,
+}
+
+/// One line of the journal.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Snapshot(Snapshot),
+    Edit(Edit),
+}
+
+/// An open journal file, see the module documentation.
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    /// Size of the current file in bytes.
+    len: u64,
+    max_bytes: Option<u64>,
+}
+
+impl Journal {
+    /// Starts a journal at *path* with a snapshot of *editor*. An existing journal there is
+    /// rotated, so replay it first to continue from it. Without *max_bytes* the journal is never
+    /// rotated while running.
+    pub fn open<Id, P>(path: P, max_bytes: Option<u64>, editor: &Editor<Id>) -> io::Result<Self>
+    where
+        Id: Eq + Hash,
+        P: Into<PathBuf>,
+    {
+        let path = path.into();
+        if path.exists() {
+            fs::rename(&path, rotated_path(&path))?;
+        }
+        let mut journal = Journal {
+            file: create(&path)?,
+            path,
+            len: 0,
+            max_bytes,
+        };
+        journal.write_snapshot(editor)?;
+        Ok(journal)
+    }
+
+    /// Appends an edit as applied by *editor*, rotating the journal if it got too big.
+    /// Each line is written right away, so a crash loses at most the edit being written.
+    pub fn append<Id: Eq + Hash>(&mut self, edit: &Edit, editor: &Editor<Id>) -> io::Result<()> {
+        self.write_line(serde_json::to_string(edit)?)?;
+        if self.max_bytes.is_some_and(|max| self.len >= max) {
+            self.rotate(editor)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the current file to `<path>.1`, replacing an older one, and starts a new file.
+    fn rotate<Id: Eq + Hash>(&mut self, editor: &Editor<Id>) -> io::Result<()> {
+        fs::rename(&self.path, rotated_path(&self.path))?;
+        self.file = create(&self.path)?;
+        self.len = 0;
+        self.write_snapshot(editor)
+    }
+
+    fn write_snapshot<Id: Eq + Hash>(&mut self, editor: &Editor<Id>) -> io::Result<()> {
+        let snapshot = Snapshot {
+            rev: editor.rev(),
+            snapshot: editor.buffer(),
+        };
+        self.write_line(serde_json::to_string(&snapshot)?)
+    }
+
+    fn write_line(&mut self, mut line: String) -> io::Result<()> {
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()?;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+}
+
+fn create(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated_path(path: &Path) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".1");
+    PathBuf::from(rotated)
+}
+
+/// Restores an editor from a journal file. The editor continues at the revision of the last
+/// edit. Fails with `InvalidData` if the journal does not start with a snapshot, or an edit does
+/// not follow the previous revision or cannot be applied.
+pub fn replay<Id: Eq + Hash, R: BufRead>(reader: R) -> io::Result<Editor<Id>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let mut lines = reader.lines();
+    let first = lines.next().ok_or_else(|| invalid("empty journal"))??;
+    let snapshot: Snapshot = serde_json::from_str(&first)?;
+    let editor = Editor::from(snapshot.snapshot);
+    {
+        let mut inner = editor.0.borrow_mut();
+        let (ref mut table, ref mut history, _) = *inner;
+        history.first_rev = snapshot.rev;
+        for line in lines {
+            let mut edit = match serde_json::from_str(&line?)? {
+                Entry::Edit(edit) => edit,
+                Entry::Snapshot(snapshot) => {
+                    let message = format!("snapshot of revision {} after edits", snapshot.rev);
+                    return Err(invalid(&message));
+                }
+            };
+            if edit.rev != history.rev() + 1 {
+                return Err(invalid("edits are out of order"));
+            }
+            let removed = removed_text(table, &edit);
+            table
+                .apply_edit(&edit.action, edit.pos)
+                .map_err(|e| invalid(e.reason()))?;
+            history.record(&mut edit, removed, false);
+        }
+    }
+    Ok(editor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::BufReader;
+    use EditAction;
+
+    #[test]
+    fn replay_journal() {
+        let path = env::temp_dir().join(format!("avian-journal-{}.ndjson", ::std::process::id()));
+        let editor = Editor::<u32>::from("journal");
+        editor.connect(0);
+        let mut journal = Journal::open(&path, Some(100), &editor).unwrap();
+        for rev in 0..20 {
+            let edit = Edit {
+                rev,
+                pos: 0,
+                action: if rev % 3 == 2 {
+                    EditAction::Delete(1)
+                } else {
+                    EditAction::Insert(format!("{},", rev))
+                },
+            };
+            let edit = editor.edit(0, edit).unwrap();
+            journal.append(&edit, &editor).unwrap();
+        }
+        // the journal was rotated, but the latest file is enough
+        let rotated = rotated_path(&path);
+        assert!(rotated.exists());
+        let replayed: Editor<u32> = replay(BufReader::new(File::open(&path).unwrap())).unwrap();
+        assert_eq!(replayed.buffer(), editor.buffer());
+        assert_eq!(replayed.connect(1).rev, 20);
+
+        // reopening rotates the existing file away
+        drop(journal);
+        Journal::open(&path, None, &replayed).unwrap();
+        let restarted: Editor<u32> = replay(BufReader::new(File::open(&path).unwrap())).unwrap();
+        assert_eq!(restarted.buffer(), editor.buffer());
+        let old: Editor<u32> = replay(BufReader::new(File::open(&rotated).unwrap())).unwrap();
+        assert_eq!(old.buffer(), editor.buffer());
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&rotated).unwrap();
+    }
+}
@@ -3,20 +3,30 @@
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::cmp;
 use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
 use std::hash::Hash;
+use std::io;
+use std::mem;
+use std::time::{Duration, Instant};
 
+use serde::de::{self, DeserializeOwned};
+use serde::Serialize;
+
+pub mod journal;
 mod pt;
 
-use self::pt::PieceTable;
+pub use self::pt::{PieceTable, TableStats};
 
 /// One edit in the editor. Each edit happens at a position, which is an index in bytes into the
 /// buffer. Edits with an invalid index are rejected. Each edit also has a base revision number,
 /// which is used to prevent race conditions.
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Edit {
     pub pos: usize,
     /// Base revision when sent by the client, current revision number when sent by the server.
@@ -26,90 +36,1116 @@ pub struct Edit {
 
 /// Represents a single editor action, regardless of place.
 /// To be used inside Edit.
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum EditAction {
     /// Insert action with offset in bytes, inserted string
     Insert(String),
     /// Delete action with offset and length in bytes
     Delete(usize),
+    /// An edit cancelled by a concurrent edit, e.g. deleting text another client already
+    /// deleted. Changes nothing and does not create a revision.
+    Noop,
+    /// Delete action that only applies if the deleted text is exactly this one, after the edit
+    /// was rebased. Rejected with `EditError::Conflict` if a concurrent edit changed the text.
+    /// Broadcast and recorded as a plain `Delete`.
+    DeleteExpecting(String),
+    /// Inserts several texts at once, e.g. for multiple cursors, as pairs of (offset, text).
+    /// The offsets refer to the buffer before any of the texts is inserted and must be strictly
+    /// increasing; the edit's own position is ignored. Creates a single revision.
+    InsertMany(Vec<(usize, String)>),
+    /// Replaces this many bytes with the text, e.g. to overwrite a selection, as a single
+    /// revision. Like for a delete, the length must not be 0. If a concurrent edit already
+    /// deleted the range, only the text is inserted.
+    Replace(usize, String),
+}
+
+impl EditAction {
+    /// Number of bytes the action takes up when serialized as JSON, computed without
+    /// serializing it.
+    pub fn serialized_len(&self) -> usize {
+        match *self {
+            // {"Insert":"..."}
+            EditAction::Insert(ref content) => 11 + escaped_len(content),
+            // {"Delete":n}
+            EditAction::Delete(len) => 11 + len.to_string().len(),
+            // "Noop"
+            EditAction::Noop => 6,
+            // {"DeleteExpecting":"..."}
+            EditAction::DeleteExpecting(ref text) => 20 + escaped_len(text),
+            // {"InsertMany":[[n,"..."],...]}
+            EditAction::InsertMany(ref inserts) => {
+                let items: usize = inserts
+                    .iter()
+                    .map(|&(pos, ref content)| 3 + pos.to_string().len() + escaped_len(content))
+                    .sum();
+                17 + items + inserts.len().saturating_sub(1)
+            }
+            // {"Replace":[n,"..."]}
+            EditAction::Replace(len, ref content) => {
+                15 + len.to_string().len() + escaped_len(content)
+            }
+        }
+    }
+
+    /// Number of bytes the action deletes, `None` if it is not a delete.
+    pub fn deleted_len(&self) -> Option<usize> {
+        match *self {
+            EditAction::Delete(len) => Some(len),
+            EditAction::DeleteExpecting(ref text) => Some(text.len()),
+            EditAction::Replace(len, _) => Some(len),
+            EditAction::Insert(_) | EditAction::InsertMany(_) | EditAction::Noop => None,
+        }
+    }
+}
+
+/// Length of a string serialized as JSON, including the quotes.
+fn escaped_len(s: &str) -> usize {
+    let escaped: usize = s
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' | '\n' | '\r' | '\t' | '\u{8}' | '\u{c}' => 2,
+            c if c < ' ' => 6,
+            c => c.len_utf8(),
+        })
+        .sum();
+    2 + escaped
+}
+
+/// Reasons for rejecting an edit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditError {
+    /// The edit is based on a revision that was already removed from the history.
+    OldRevision,
+    /// The edit is based on a revision that does not exist yet.
+    FutureRevision,
+    /// The edit's position is past the end of the document.
+    OutOfRange,
+    /// The edit's position lies inside a multi-byte character.
+    NotCharBoundary,
+    /// The edit's positions or range are invalid otherwise, e.g. an empty delete or positions
+    /// out of order.
+    InvalidIndex,
+    /// The edit overlaps with a concurrent edit in a way the transform does not handle yet.
+    NotImplemented,
+    /// The edit would split a grapheme cluster, see `Editor::set_grapheme_boundaries`.
+    SplitsGrapheme,
+    /// The text a `DeleteExpecting` should delete was changed by a concurrent edit.
+    Conflict,
+}
+
+impl EditError {
+    /// Human-readable reason, as sent to clients.
+    pub fn reason(&self) -> &'static str {
+        match *self {
+            EditError::OldRevision => "old revision",
+            EditError::FutureRevision => "future revision",
+            EditError::OutOfRange => "out of range",
+            EditError::NotCharBoundary => "not a char boundary",
+            EditError::InvalidIndex => "invalid index",
+            EditError::NotImplemented => "not implemented",
+            EditError::SplitsGrapheme => "splits a grapheme",
+            EditError::Conflict => "conflict",
+        }
+    }
+
+    /// Stable machine-readable code, as sent to clients.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            EditError::OldRevision => "old_revision",
+            EditError::FutureRevision => "future_revision",
+            EditError::OutOfRange => "out_of_range",
+            EditError::NotCharBoundary => "not_char_boundary",
+            EditError::InvalidIndex => "invalid_index",
+            EditError::NotImplemented => "not_implemented",
+            EditError::SplitsGrapheme => "splits_grapheme",
+            EditError::Conflict => "conflict",
+        }
+    }
+}
+
+impl fmt::Display for EditError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.reason())
+    }
+}
+
+impl Error for EditError {}
+
+/// Status sent to a client when it connects.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ConnectStatus {
+    /// Current revision, which the client's first edit is based on.
+    pub rev: u32,
+    pub buffer: String,
+    /// Checksum of the buffer, see `checksum`.
+    pub checksum: u64,
+    /// Number of connected clients, including the new one.
+    pub clients: usize,
+}
+
+/// Summary of the editor's state, see `Editor::stats`.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct EditorStats {
+    pub rev: u32,
+    pub first_rev: u32,
+    /// Number of connected clients.
+    pub clients: usize,
+    /// Length of the document in bytes.
+    pub len: usize,
+    /// Length of the document in chars.
+    pub chars: usize,
+    /// Number of lines, see `PieceTable::line_count`.
+    pub lines: usize,
+    pub pieces: usize,
+    /// Bytes of the piece table's buffer that are no longer part of the document.
+    pub buffer_overhead: usize,
+}
+
+/// Deterministic 64 bit FNV-1a hash of a document's bytes. Used by clients to check that their
+/// copy of the buffer matches the server's.
+pub fn checksum<I: IntoIterator<Item = u8>>(bytes: I) -> u64 {
+    bytes.into_iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Applies an edit broadcast by the server to a client's copy of the document. The edit is
+/// already rebased, so it applies as is once the copy is at the revision before it; the range is
+/// still checked, and an edit that does not fit the copy is rejected.
+pub fn apply_remote_edit(pt: &mut PieceTable, edit: &Edit) -> Result<(), EditError> {
+    pt.apply_edit(&edit.action, edit.pos)
+}
+
+/// Turns *old* into *new* with at most a delete and an insert of the part between their common
+/// prefix and suffix, as positions and actions to apply in order.
+pub fn diff(old: &str, new: &str) -> Vec<(usize, EditAction)> {
+    let mut prefix = old
+        .bytes()
+        .zip(new.bytes())
+        .take_while(|&(a, b)| a == b)
+        .count();
+    while !old.is_char_boundary(prefix) {
+        prefix -= 1;
+    }
+    let mut suffix = old.as_bytes()[prefix..]
+        .iter()
+        .rev()
+        .zip(new.as_bytes()[prefix..].iter().rev())
+        .take_while(|&(a, b)| a == b)
+        .count();
+    while !old.is_char_boundary(old.len() - suffix) || !new.is_char_boundary(new.len() - suffix) {
+        suffix -= 1;
+    }
+    let mut edits = Vec::new();
+    if old.len() - suffix > prefix {
+        edits.push((prefix, EditAction::Delete(old.len() - suffix - prefix)));
+    }
+    if new.len() - suffix > prefix {
+        let inserted = new[prefix..new.len() - suffix].to_string();
+        edits.push((prefix, EditAction::Insert(inserted)));
+    }
+    edits
 }
 
+/// WebSocket subprotocol of the current JSON wire format. Clients that do not ask for a protocol
+/// get this one as well.
+pub const PROTOCOL: &str = "avian.v1";
+
+/// Edit position standing for the end of the document at the time the edit is applied. Lets
+/// clients append without racing other clients for the length.
+pub const END: usize = usize::MAX;
+
+/// Maximum time between two keystrokes of a client for them to end up in the same undo group.
+const TYPING_WINDOW: Duration = Duration::from_secs(1);
+
 /// The main struct to keep track of editor status. Wraps its contents in a RefCell
 /// to allow mutation without ownership.
 /// The Id is generic for type safety and in case the id type (which is currently always u32)
 /// needs to be changed in the future, likely if the ws implementation is switched out.
-pub struct Editor<Id>(RefCell<(PieceTable, History, HashMap<Id, u32>)>);
+/// The second field is set if edits must keep grapheme clusters intact. The third holds the
+/// callbacks registered with `on_edit`.
+pub struct Editor<Id>(
+    RefCell<(PieceTable, History, HashMap<Id, Client>)>,
+    Cell<bool>,
+    RefCell<Vec<EditCallback>>,
+);
+
+/// Callback registered with `Editor::on_edit`.
+type EditCallback = Box<dyn Fn(&Edit)>;
+
+/// What the editor knows about a connected client.
+struct Client {
+    /// The latest revision the client acknowledged.
+    rev: u32,
+    /// End position, revision and time of the client's last edit, if it was a single-character
+    /// insert. Used to group typing into one undo step.
+    typing: Option<(usize, u32, Instant)>,
+    /// Time of the client's last edit or acknowledgement, or of connecting.
+    last_seen: Instant,
+}
+
+impl Client {
+    fn new(rev: u32) -> Self {
+        Client {
+            rev,
+            typing: None,
+            last_seen: Instant::now(),
+        }
+    }
+}
 
 impl<Id: Eq + Hash> Editor<Id> {
     pub fn new() -> Self {
-        Editor(RefCell::new((
-            PieceTable::new(),
-            History::new(),
-            HashMap::new(),
-        )))
+        Editor(
+            RefCell::new((PieceTable::new(), History::new(), HashMap::new())),
+            Cell::new(false),
+            RefCell::new(Vec::new()),
+        )
+    }
+
+    /// Sets whether edits from clients must start and end on grapheme cluster boundaries, not
+    /// just on char boundaries. Off by default. Edits splitting a grapheme, like half of a flag
+    /// emoji, are rejected with `EditError::SplitsGrapheme`.
+    pub fn set_grapheme_boundaries(&self, required: bool) {
+        self.1.set(required);
+    }
+
+    /// Checks that an edit can be applied to the table.
+    fn check(&self, table: &PieceTable, edit: &Edit) -> Result<(), EditError> {
+        table.check_edit(&edit.action, edit.pos)?;
+        if self.1.get() {
+            table.check_graphemes(&edit.action, edit.pos)?;
+        }
+        Ok(())
     }
 
     /// Registers an edit from a specific client.
     /// The edit's rev number is used to determine the client's knowledge,
     /// meaning: the client acknowledges all edits up to number *rev*.
-    pub fn edit(&self, id: Id, edit: Edit) -> Result<Edit, &'static str> {
-        self.acknowledge(id, edit.rev);
+    /// A position of `END` is resolved to the current length of the document.
+    ///
+    /// Consecutive single-character inserts of one client are grouped into one undo step, as
+    /// long as they are adjacent and no more than `TYPING_WINDOW` apart.
+    pub fn edit(&self, id: Id, edit: Edit) -> Result<Edit, EditError> {
+        let edit = self.apply_client_edit(id, edit)?;
+        // The state is no longer borrowed, so callbacks may use the editor.
+        for callback in self.2.borrow().iter() {
+            callback(&edit);
+        }
+        Ok(edit)
+    }
+
+    /// Registers a callback that is called with every edit applied by `edit`, after it was
+    /// recorded, in the order of registration. Callbacks must not register further callbacks.
+    pub fn on_edit<F: Fn(&Edit) + 'static>(&self, callback: F) {
+        self.2.borrow_mut().push(Box::new(callback));
+    }
+
+    fn apply_client_edit(&self, id: Id, edit: Edit) -> Result<Edit, EditError> {
         let mut inner = self.0.borrow_mut();
-        let mut edit = inner.1.transform(edit)?;
-        match edit.action {
-            EditAction::Insert(ref content) => {
-                if inner.0.valid_index(edit.pos) {
-                    inner.0.insert(edit.pos, content);
-                } else {
-                    return Err("invalid index");
-                }
+        let (ref mut table, ref mut history, ref mut clients) = *inner;
+        // A client cannot acknowledge revisions that do not exist yet.
+        history.check_rev(edit.rev)?;
+        let client = acknowledge(history, clients, id, cmp::min(edit.rev, history.rev()));
+
+        let mut edit = rebase(table, history, edit)?;
+        self.check(table, &edit)?;
+        plain_delete(&mut edit);
+        let removed = removed_text(table, &edit);
+        table.apply_edit(&edit.action, edit.pos)?;
+
+        let now = Instant::now();
+        let keystroke = match edit.action {
+            EditAction::Insert(ref content) if content.chars().count() == 1 => Some(content.len()),
+            _ => None,
+        };
+        // Only continue typing if nobody else edited in between.
+        let continues_typing = keystroke.is_some()
+            && client.typing.is_some_and(|(end, rev, time)| {
+                end == edit.pos && rev == history.rev() && now - time < TYPING_WINDOW
+            });
+        history.record(&mut edit, removed, continues_typing);
+        client.typing = keystroke.map(|len| (edit.pos + len, edit.rev, now));
+        Ok(edit)
+    }
+
+    /// Applies an edit as another editor broadcast it, to follow that editor as a replica. The
+    /// edit is already rebased, so it is applied as is; its revision must be the one after the
+    /// current, otherwise fails with `OldRevision` or `FutureRevision`. Registered callbacks are
+    /// called like for `edit`.
+    pub fn apply_remote(&self, edit: Edit) -> Result<(), EditError> {
+        let edit = {
+            let mut inner = self.0.borrow_mut();
+            let (ref mut table, ref mut history, _) = *inner;
+            if edit.rev <= history.rev() {
+                return Err(EditError::OldRevision);
+            } else if edit.rev > history.rev() + 1 {
+                return Err(EditError::FutureRevision);
             }
-            EditAction::Delete(len) => {
-                if len > 0 && inner.0.valid_index(edit.pos) && inner.0.valid_index(edit.pos + len) {
-                    inner.0.delete(edit.pos, len);
-                } else {
-                    return Err("invalid index");
-                }
+            let mut edit = edit;
+            self.check(table, &edit)?;
+            plain_delete(&mut edit);
+            let removed = removed_text(table, &edit);
+            table.apply_edit(&edit.action, edit.pos)?;
+            history.record(&mut edit, removed, false);
+            edit
+        };
+        for callback in self.2.borrow().iter() {
+            callback(&edit);
+        }
+        Ok(())
+    }
+
+    /// Applies the same action at several cursor positions of one client, all based on revision
+    /// *rev*. The positions must be sorted and distinct. Every edit is checked before any is
+    /// applied, so either all of them succeed or none. Returns the applied edits for broadcast,
+    /// which form one undo group.
+    pub fn edit_many(
+        &self,
+        id: Id,
+        rev: u32,
+        positions: &[usize],
+        action: EditAction,
+    ) -> Result<Vec<Edit>, EditError> {
+        let mut inner = self.0.borrow_mut();
+        let (ref mut table, ref mut history, ref mut clients) = *inner;
+        history.check_rev(rev)?;
+        acknowledge(history, clients, id, cmp::min(rev, history.rev())).typing = None;
+
+        let mut edits = Vec::with_capacity(positions.len());
+        for &pos in positions {
+            let edit = history.transform(Edit {
+                pos,
+                rev,
+                action: action.clone(),
+            })?;
+            // cancelled by a concurrent delete
+            if edit.action != EditAction::Noop {
+                edits.push(edit);
             }
         }
-        inner.1.record(&mut edit);
-        Ok(edit)
+        let len = action.deleted_len().unwrap_or(0);
+        for (i, edit) in edits.iter().enumerate() {
+            self.check(table, edit)?;
+            // Positions must be increasing and deleted ranges must not overlap, or applying one
+            // edit would move the next.
+            if edits
+                .get(i + 1)
+                .is_some_and(|next| next.pos < edit.pos + cmp::max(len, 1))
+            {
+                return Err(EditError::InvalidIndex);
+            }
+        }
+
+        // Going backwards keeps the positions of the remaining edits valid.
+        edits.reverse();
+        for (i, edit) in edits.iter_mut().enumerate() {
+            plain_delete(edit);
+            let removed = removed_text(table, edit);
+            table.apply_edit(&edit.action, edit.pos)?;
+            history.record(edit, removed, i > 0);
+        }
+        Ok(edits)
+    }
+
+    /// Replaces *del_len* bytes at *pos* with *insert_text* for one client, based on revision
+    /// *rev*, as a single revision. Like `edit` with a `Replace` action, or an `Insert` if
+    /// nothing is deleted, so the splice applies completely or not at all. Returns the applied
+    /// edit for broadcast.
+    pub fn splice(
+        &self,
+        id: Id,
+        rev: u32,
+        pos: usize,
+        del_len: usize,
+        insert_text: &str,
+    ) -> Result<Edit, EditError> {
+        let action = if del_len > 0 {
+            EditAction::Replace(del_len, insert_text.to_string())
+        } else {
+            EditAction::Insert(insert_text.to_string())
+        };
+        self.edit(id, Edit { pos, rev, action })
+    }
+
+    /// Removes whitespace at the end of every line. Returns the applied edits for broadcast, which
+    /// form one undo group.
+    pub fn trim_trailing_whitespace(&self) -> Vec<Edit> {
+        // Going backwards keeps the positions of the remaining ranges valid.
+        let ranges = self.0.borrow().0.trailing_whitespace();
+        let edits = ranges
+            .into_iter()
+            .rev()
+            .map(|(pos, len)| (pos, EditAction::Delete(len)));
+        self.edit_unchecked(edits)
+    }
+
+    /// Replaces every occurrence of *from* with *to*. Returns the applied edits for broadcast,
+    /// which form one undo group.
+    pub fn replace_all(&self, from: &str, to: &str) -> Vec<Edit> {
+        let mut edits = Vec::new();
+        // Going backwards keeps the positions of the remaining matches valid.
+        let positions = self.0.borrow().0.find_all(from);
+        for pos in positions.into_iter().rev() {
+            edits.push((pos, EditAction::Delete(from.len())));
+            if !to.is_empty() {
+                edits.push((pos, EditAction::Insert(to.to_string())));
+            }
+        }
+        self.edit_unchecked(edits)
+    }
+
+    /// Replaces tabs with spaces, up to the next multiple of *tab_width* columns. Returns the
+    /// applied edits for broadcast, which form one undo group.
+    pub fn expand_tabs(&self, tab_width: usize) -> Vec<Edit> {
+        let mut edits = Vec::new();
+        let widths = self.0.borrow().0.tab_widths(tab_width);
+        for (pos, width) in widths.into_iter().rev() {
+            edits.push((pos, EditAction::Delete(1)));
+            if width > 0 {
+                edits.push((pos, EditAction::Insert(" ".repeat(width))));
+            }
+        }
+        self.edit_unchecked(edits)
+    }
+
+    /// Applies and records edits the editor generated itself, see `apply_generated`, as one undo
+    /// group. Unlike `edit`, nothing is transformed or checked: every position must be valid
+    /// after the previous edits, or the document is corrupted or this panics. Only for callers
+    /// that derived the edits from the current document.
+    pub(crate) fn edit_unchecked<I>(&self, edits: I) -> Vec<Edit>
+    where
+        I: IntoIterator<Item = (usize, EditAction)>,
+    {
+        let mut inner = self.0.borrow_mut();
+        let (ref mut table, ref mut history, _) = *inner;
+        apply_generated(table, history, edits)
+    }
+
+    /// Empties the document and returns its content, together with the applied delete for
+    /// broadcast. An empty document is returned as is, without an edit or a new revision.
+    pub fn take_buffer(&self) -> (String, Option<Edit>) {
+        let mut inner = self.0.borrow_mut();
+        let (ref mut table, ref mut history, _) = *inner;
+        let content = table.to_string();
+        if content.is_empty() {
+            return (content, None);
+        }
+        let mut edits =
+            apply_generated(table, history, Some((0, EditAction::Delete(content.len()))));
+        (content, edits.pop())
+    }
+
+    /// Reverts the most recent undo group, whoever made it, e.g. a whole typed word, by applying
+    /// the inverse of its edits in reverse order as a new group. Meant for documents with a
+    /// single client, where per-client undo is not needed; undoing again reverts the undo itself.
+    /// Returns the applied edits for broadcast, or `EditError::OldRevision` if part of the group
+    /// already left the history.
+    pub fn undo_global(&self) -> Result<Vec<Edit>, EditError> {
+        let mut inner = self.0.borrow_mut();
+        let (ref mut table, ref mut history, _) = *inner;
+        let group: Vec<(Edit, String)> = match history.last_group() {
+            Some(group) => group
+                .map(|(edit, removed)| (edit.clone(), removed.to_string()))
+                .collect(),
+            None => return Err(EditError::OldRevision),
+        };
+        let mut edits = Vec::with_capacity(group.len());
+        // Each inverse is computed on the document right after the edit it reverts.
+        for (edit, removed) in group.iter().rev() {
+            let (pos, action) = inverse(table, edit, removed);
+            if action != EditAction::Noop {
+                let continue_group = !edits.is_empty();
+                edits.push(apply_one(table, history, pos, action, continue_group));
+            }
+        }
+        Ok(edits)
+    }
+
+    /// Rebases an edit onto the current revision, like `edit` does, without applying it.
+    /// Useful to preview where an edit would land. Does not change any state.
+    pub fn transform_only(&self, edit: Edit) -> Result<Edit, EditError> {
+        let inner = self.0.borrow();
+        rebase(&inner.0, &inner.1, edit)
+    }
+
+    /// Checks whether `edit` would accept an edit, without applying it. Returns the same error
+    /// `edit` would.
+    pub fn is_valid_edit(&self, edit: &Edit) -> Result<(), EditError> {
+        let inner = self.0.borrow();
+        let edit = rebase(&inner.0, &inner.1, edit.clone())?;
+        self.check(&inner.0, &edit)
+    }
+
+    /// Returns the document as it would look after applying *edits* in order, without changing
+    /// it. Each position refers to the document after the previous edits, the revisions are
+    /// ignored. Fails with the error of the first edit that `edit` would reject.
+    pub fn preview_apply(&self, edits: &[Edit]) -> Result<String, EditError> {
+        let mut table = self.0.borrow().0.clone();
+        for edit in edits {
+            self.check(&table, edit)?;
+            table.apply_edit(&edit.action, edit.pos)?;
+        }
+        Ok(table.to_string())
+    }
+
+    /// Rebases edits a client made on top of each other without sending them, see
+    /// `History::rebase_all`. The result can be passed to `edit` in order. Does not change any
+    /// state.
+    pub fn rebase_all(&self, edits: Vec<Edit>) -> Result<Vec<Edit>, EditError> {
+        self.0.borrow().1.rebase_all(edits)
     }
 
-    /// Signals that a client knows about revision *rev*
-    fn acknowledge(&self, id: Id, rev: u32) {
+    /// Maps a position at revision *rev* to the current revision, e.g. to keep annotations
+    /// anchored to the text they were made on. Returns `None` if *rev* is no longer (or not yet)
+    /// in the history.
+    pub fn delta_for(&self, rev: u32, pos: usize) -> Option<usize> {
+        self.0.borrow().1.delta_for(rev, pos)
+    }
+
+    /// Signals that client *id* knows about revision *rev* without sending an edit, so clients
+    /// that only read do not hold back the backlog. A client never moves back, so an older *rev*
+    /// than its last one is ignored. Fails with `FutureRevision` if *rev* does not exist yet.
+    pub fn acknowledge_public(&self, id: Id, rev: u32) -> Result<(), EditError> {
         let mut inner = self.0.borrow_mut();
-        inner.2.insert(id, rev);
-        let &min_rev = inner.2.values().min().unwrap();
-        inner.1.acknowledge(min_rev);
+        let (_, ref mut history, ref mut clients) = *inner;
+        if rev > history.rev() {
+            return Err(EditError::FutureRevision);
+        }
+        let rev = clients
+            .get(&id)
+            .map_or(rev, |client| cmp::max(client.rev, rev));
+        acknowledge(history, clients, id, rev);
+        Ok(())
     }
 
     /// Signals that a client has disconnected
     pub fn disconnect(&self, id: &Id) {
+        self.0.borrow_mut().2.remove(id);
+        self.flush_acks();
+    }
+
+    /// Trims the backlog to the oldest revision a connected client is based on, or to the current
+    /// revision if no client is connected. Returns the oldest revision left in the backlog.
+    pub fn flush_acks(&self) -> u32 {
         let mut inner = self.0.borrow_mut();
-        inner.2.remove(id);
-        let min_opt = inner.2.values().min().map(|&min| min);
-        if let Some(min_rev) = min_opt {
-            inner.1.acknowledge(min_rev);
-        } else {
-            let rev = inner.1.rev();
-            inner.1.acknowledge(rev);
+        let (_, ref mut history, ref clients) = *inner;
+        let min_rev = clients
+            .values()
+            .map(|client| client.rev)
+            .min()
+            .unwrap_or_else(|| history.rev());
+        history.acknowledge(min_rev);
+        history.first_rev()
+    }
+
+    /// Returns the edits a client missed since revision *rev*, or `None` if they are no longer
+    /// in the history and the client needs the full buffer instead.
+    pub fn pending_edits(&self, rev: u32) -> Option<Vec<Edit>> {
+        self.0.borrow().1.edits_since(rev)
+    }
+
+    /// Returns the change from the buffer at revision *rev* to the current one as at most a
+    /// delete and an insert of the part that differs, or `None` if *rev* is no longer in the
+    /// history. An alternative to `pending_edits` for clients that missed many small edits. The
+    /// edits apply in order and carry the current revision.
+    pub fn diff_since(&self, rev: u32) -> Option<Vec<Edit>> {
+        let inner = self.0.borrow();
+        let (ref table, ref history, _) = *inner;
+        let old = history.buffer_at(table, rev)?.to_string();
+        let edits = diff(&old, &table.to_string())
+            .into_iter()
+            .map(|(pos, action)| Edit {
+                pos,
+                rev: history.rev(),
+                action,
+            })
+            .collect();
+        Some(edits)
+    }
+
+    /// Adds a client that already knows the buffer at revision *rev*, e.g. after losing its
+    /// connection. Returns the edits it missed. If those are no longer available, the client is
+    /// not added and needs to `connect` instead.
+    pub fn reconnect(&self, id: Id, rev: u32) -> Option<Vec<Edit>> {
+        let mut inner = self.0.borrow_mut();
+        let edits = inner.1.edits_since(rev)?;
+        inner.2.insert(id, Client::new(rev));
+        Some(edits)
+    }
+
+    /// Moves the state of client *old* to *new*, keeping its revision and typing state. For
+    /// clients that reconnect under a new id. Does nothing if *old* is not connected.
+    pub fn rename_client(&self, old: Id, new: Id) {
+        {
+            let mut inner = self.0.borrow_mut();
+            if let Some(client) = inner.2.remove(&old) {
+                inner.2.insert(new, client);
+            }
         }
+        // a client previously known as *new* may have been replaced
+        self.flush_acks();
+    }
+
+    /// Returns the clients that have not edited, acknowledged or connected for at least
+    /// *threshold*, in no particular order. The server can disconnect them to free their backlog.
+    pub fn idle_clients(&self, threshold: Duration) -> Vec<Id>
+    where
+        Id: Clone,
+    {
+        let now = Instant::now();
+        self.0
+            .borrow()
+            .2
+            .iter()
+            .filter(|&(_, client)| now - client.last_seen >= threshold)
+            .map(|(id, _)| id.clone())
+            .collect()
     }
 
     /// Adds a client and returns current status
-    pub fn connect(&self, id: Id) -> (u32, String) {
+    pub fn connect(&self, id: Id) -> ConnectStatus {
         let mut inner = self.0.borrow_mut();
         let rev = inner.1.rev();
-        inner.2.insert(id, rev);
-        (rev, inner.0.to_string())
+        inner.2.insert(id, Client::new(rev));
+        ConnectStatus {
+            rev,
+            buffer: inner.0.to_string(),
+            checksum: inner.0.checksum(),
+            clients: inner.2.len(),
+        }
+    }
+
+    /// Checksum of the current buffer, see `checksum`.
+    pub fn checksum(&self) -> u64 {
+        self.0.borrow().0.checksum()
+    }
+
+    /// Oldest revision in the backlog and the backlog's offset pairs, for debugging transforms.
+    pub fn backlog_map(&self) -> (u32, Vec<(usize, usize)>) {
+        let history = &self.0.borrow().1;
+        (history.first_rev(), history.current_map())
+    }
+
+    /// Current revision of the document.
+    pub fn rev(&self) -> u32 {
+        self.0.borrow().1.rev()
+    }
+
+    /// The latest revision each connected client acknowledged. Together with `rev`, this shows
+    /// how far behind each client is.
+    pub fn clients_at_revision(&self) -> HashMap<Id, u32>
+    where
+        Id: Clone,
+    {
+        self.0
+            .borrow()
+            .2
+            .iter()
+            .map(|(id, client)| (id.clone(), client.rev))
+            .collect()
+    }
+
+    /// Oldest revision clients can be caught up from. A client based on an older revision needs
+    /// the whole buffer again.
+    pub fn first_rev(&self) -> u32 {
+        self.0.borrow().1.first_rev()
+    }
+
+    /// The revisions clients can still be caught up from with `pending_edits`, as
+    /// `(first_rev, rev)`, both inclusive. A client at an older revision needs the whole buffer.
+    pub fn revision_range(&self) -> (u32, u32) {
+        let history = &self.0.borrow().1;
+        (history.first_rev(), history.rev())
+    }
+
+    /// Number of edits in the backlog.
+    pub fn backlog_len(&self) -> usize {
+        self.0.borrow().1.backlog_len()
+    }
+
+    /// Merges pieces of the table that follow each other in its buffer, see
+    /// `PieceTable::merge_adjacent`. Neither the content nor the revision change.
+    pub fn merge_pieces(&self) -> usize {
+        self.0.borrow_mut().0.merge_adjacent()
+    }
+
+    /// Rebuilds the piece table in a buffer of its own, see `PieceTable::compact`, and returns
+    /// the reclaimed bytes. Neither the content, the revision nor the clients change, so this is
+    /// safe to call between edits at any time.
+    pub fn compact(&self) -> usize {
+        self.0.borrow_mut().0.compact()
+    }
+
+    /// Estimated memory used by the document and its history in bytes, for capacity planning:
+    /// the piece table's buffer, including deleted text not yet compacted away, and its pieces,
+    /// plus `History::memory_footprint`.
+    pub fn memory_footprint(&self) -> usize {
+        let inner = self.0.borrow();
+        let stats = inner.0.stats();
+        stats.buffer_len
+            + stats.piece_count * mem::size_of::<(usize, usize)>()
+            + inner.1.memory_footprint()
+    }
+
+    /// Layout statistics of the piece table, for debugging.
+    pub fn table_stats(&self) -> TableStats {
+        self.0.borrow().0.stats()
+    }
+
+    /// All of the statistics above at once, for monitoring. The document is scanned only once,
+    /// counting chars and lines together.
+    pub fn stats(&self) -> EditorStats {
+        let inner = self.0.borrow();
+        let (ref table, ref history, ref clients) = *inner;
+        let (mut chars, mut newlines) = (0, 0);
+        for byte in table.bytes() {
+            // every char starts with exactly one byte that is not a continuation byte
+            if byte & 0xC0 != 0x80 {
+                chars += 1;
+            }
+            if byte == b'\n' {
+                newlines += 1;
+            }
+        }
+        let len = table.len();
+        EditorStats {
+            rev: history.rev(),
+            first_rev: history.first_rev(),
+            clients: clients.len(),
+            len,
+            chars,
+            lines: 1 + newlines,
+            pieces: table.piece_count(),
+            buffer_overhead: table.buffer_len() - len,
+        }
+    }
+
+    /// Length of the document in bytes.
+    pub fn len(&self) -> usize {
+        self.0.borrow().0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().0.is_empty()
+    }
+
+    /// Number of lines in the document, see `PieceTable::line_count`.
+    pub fn line_count(&self) -> usize {
+        self.0.borrow().0.line_count()
     }
 
     pub fn buffer(&self) -> String {
         self.0.borrow().0.to_string()
     }
+
+    /// Returns the part of the buffer between the byte positions *start* and *end*, e.g. the
+    /// part a client currently shows. Fails with `InvalidIndex` unless both are valid indices
+    /// and *start* is not after *end*.
+    pub fn buffer_range(&self, start: usize, end: usize) -> Result<String, EditError> {
+        let table = &self.0.borrow().0;
+        if start > end || !table.valid_index(start) || !table.valid_index(end) {
+            return Err(EditError::InvalidIndex);
+        }
+        Ok(table.substring(start, end))
+    }
+
+    /// Writes the whole state as JSON: the content, the history and the revision of every
+    /// client. Restore it with `load`, e.g. to move a document to another server.
+    pub fn dump<W: io::Write>(&self, writer: W) -> serde_json::Result<()>
+    where
+        Id: Serialize,
+    {
+        let inner = self.0.borrow();
+        let (ref table, ref history, ref clients) = *inner;
+        let state = DumpRef {
+            table,
+            history,
+            clients: clients
+                .iter()
+                .map(|(id, client)| (id, client.rev))
+                .collect(),
+        };
+        serde_json::to_writer(writer, &state)
+    }
+
+    /// Restores an editor written by `dump`. The clients continue at their revisions, so edits
+    /// based on them still apply, but they count as just seen and as not typing. Options like
+    /// `set_grapheme_boundaries` and the callbacks are not part of the state.
+    pub fn load<R: io::Read>(reader: R) -> serde_json::Result<Self>
+    where
+        Id: DeserializeOwned,
+    {
+        let state: Dump<Id> = serde_json::from_reader(reader)?;
+        let history = state.history;
+        let revisions = history.spans.len();
+        if history.applied.len() != revisions
+            || history.removed.len() != revisions
+            || history.spans.iter().sum::<usize>() != history.edits.len()
+        {
+            return Err(de::Error::custom("invalid history: backlog lengths differ"));
+        }
+        let mut clients = HashMap::new();
+        for (id, rev) in state.clients {
+            if rev < history.first_rev || rev > history.rev() {
+                return Err(de::Error::custom("invalid client revision"));
+            }
+            clients.insert(id, Client::new(rev));
+        }
+        Ok(Editor(
+            RefCell::new((state.table, history, clients)),
+            Cell::new(false),
+            RefCell::new(Vec::new()),
+        ))
+    }
+}
+
+/// The state written by `Editor::dump`.
+#[derive(Serialize)]
+struct DumpRef<'a, Id: 'a> {
+    table: &'a PieceTable,
+    history: &'a History,
+    clients: Vec<(&'a Id, u32)>,
+}
+
+/// The state read by `Editor::load`.
+#[derive(Deserialize)]
+struct Dump<Id> {
+    table: PieceTable,
+    history: History,
+    clients: Vec<(Id, u32)>,
+}
+
+/// Signals that client *id* knows about revision *rev*, trimming the backlog to what the other
+/// clients still need. Returns the client's state.
+fn acknowledge<'a, Id: Eq + Hash>(
+    history: &mut History,
+    clients: &'a mut HashMap<Id, Client>,
+    id: Id,
+    rev: u32,
+) -> &'a mut Client {
+    let min_rev = clients
+        .iter()
+        .filter(|&(client_id, _)| *client_id != id)
+        .map(|(_, client)| client.rev)
+        .fold(rev, cmp::min);
+    history.acknowledge(min_rev);
+    let client = clients.entry(id).or_insert_with(|| Client::new(rev));
+    client.rev = rev;
+    client.last_seen = Instant::now();
+    client
+}
+
+/// Transforms an edit from a client onto the current revision. `END` becomes the current length,
+/// which needs no transform, so only the revision is checked.
+fn rebase(table: &PieceTable, history: &History, edit: Edit) -> Result<Edit, EditError> {
+    if edit.pos == END {
+        history.check_rev(edit.rev)?;
+        history.transform(Edit {
+            pos: table.len(),
+            rev: history.rev(),
+            ..edit
+        })
+    } else {
+        history.transform(edit)
+    }
+}
+
+/// Applies and records edits generated by the editor itself, in order, as one undo group.
+/// The edits are not transformed, each position must be valid after applying the previous edits.
+fn apply_generated<I>(table: &mut PieceTable, history: &mut History, edits: I) -> Vec<Edit>
+where
+    I: IntoIterator<Item = (usize, EditAction)>,
+{
+    edits
+        .into_iter()
+        .enumerate()
+        .map(|(i, (pos, action))| apply_one(table, history, pos, action, i > 0))
+        .collect()
+}
+
+/// Applies and records one edit generated by the editor itself, see `apply_generated` and
+/// `History::record`.
+fn apply_one(
+    table: &mut PieceTable,
+    history: &mut History,
+    pos: usize,
+    action: EditAction,
+    continue_group: bool,
+) -> Edit {
+    let mut edit = Edit {
+        pos,
+        rev: history.rev(),
+        action,
+    };
+    let removed = removed_text(table, &edit);
+    match edit.action {
+        EditAction::Insert(ref content) => table.insert(pos, content),
+        EditAction::Delete(len) => table.delete(pos, len),
+        EditAction::DeleteExpecting(ref text) => table.delete(pos, text.len()),
+        EditAction::InsertMany(ref inserts) => {
+            for &(pos, ref content) in inserts.iter().rev() {
+                table.insert(pos, content);
+            }
+        }
+        EditAction::Replace(len, ref content) => table.replace(pos, len, content),
+        EditAction::Noop => {}
+    }
+    history.record(&mut edit, removed, continue_group);
+    edit
+}
+
+/// The edit reverting *edit* in *table*, the document right after it, given the text it
+/// *removed*.
+fn inverse(table: &PieceTable, edit: &Edit, removed: &str) -> (usize, EditAction) {
+    let action = match edit.action {
+        EditAction::Insert(ref content) => EditAction::Delete(content.len()),
+        EditAction::Delete(_) | EditAction::DeleteExpecting(_) => {
+            EditAction::Insert(removed.to_string())
+        }
+        EditAction::Replace(_, ref content) if content.is_empty() => {
+            EditAction::Insert(removed.to_string())
+        }
+        EditAction::Replace(_, ref content) => {
+            EditAction::Replace(content.len(), removed.to_string())
+        }
+        EditAction::InsertMany(ref inserts) => {
+            // Deleting all texts in one revision is replacing the range from the first text to
+            // the end of the last with the text that was between them.
+            let start = inserts.first().map_or(edit.pos, |&(pos, _)| pos);
+            let mut kept = String::new();
+            let mut end = start;
+            let mut inserted = 0;
+            for &(pos, ref content) in inserts {
+                kept.push_str(&table.substring(end, pos + inserted));
+                end = pos + inserted + content.len();
+                inserted += content.len();
+            }
+            let action = if end == start {
+                EditAction::Noop
+            } else if kept.is_empty() {
+                EditAction::Delete(end - start)
+            } else {
+                EditAction::Replace(end - start, kept)
+            };
+            return (start, action);
+        }
+        EditAction::Noop => unreachable!("noops are not recorded"),
+    };
+    (edit.pos, action)
+}
+
+/// Turns a `DeleteExpecting` that passed its check into the plain `Delete` that is recorded and
+/// broadcast.
+fn plain_delete(edit: &mut Edit) {
+    if let EditAction::DeleteExpecting(_) = edit.action {
+        edit.action = EditAction::Delete(edit.action.deleted_len().unwrap());
+    }
+}
+
+/// The text an edit would remove from the table, empty unless it is a delete.
+fn removed_text(table: &PieceTable, edit: &Edit) -> String {
+    match edit.action.deleted_len() {
+        Some(len) => table.substring(edit.pos, edit.pos + len),
+        None => String::new(),
+    }
+}
+
+impl<Id: Eq + Hash> Default for Editor<Id> {
+    fn default() -> Self {
+        Editor::new()
+    }
+}
+
+/// Creates an editor with initial content. The content is part of revision 0, so it is not
+/// recorded as an edit.
+impl<Id: Eq + Hash, S: Into<String>> From<S> for Editor<Id> {
+    fn from(content: S) -> Self {
+        Editor(
+            RefCell::new((PieceTable::from(content), History::new(), HashMap::new())),
+            Cell::new(false),
+            RefCell::new(Vec::new()),
+        )
+    }
+}
+
+/// Applies the transform rules for one backlog entry of `(old, new)` offsets to an edit at
+/// *pos*. Returns how the entry affects the edit and the edit's new position.
+fn transform_step(
+    (old, new): (usize, usize),
+    pos: usize,
+    action: &EditAction,
+) -> (TransformOutcome, usize) {
+    if old < pos {
+        // Rule 1. Adjust position.
+        (TransformOutcome::ShiftedBefore, pos + new - old)
+    } else if cmp::min(old, new) > pos {
+        // Rule 2. No effect.
+        (TransformOutcome::NoEffect, pos)
+    } else if old > new && (pos == old || (pos == new && action.deleted_len().is_none())) {
+        // Rule 4. At the edge of a deleted range, the edit stays with the text next to it.
+        (TransformOutcome::DeletionEdge, new)
+    } else {
+        match action.deleted_len() {
+            // Rule 3. The whole range was already deleted.
+            Some(len) if new <= pos && pos + len <= old => (TransformOutcome::AlreadyDeleted, new),
+            // some overlap occurs.
+            _ => (TransformOutcome::Overlap, pos),
+        }
+    }
+}
+
+/// Rebases a single insert or delete *action* at *pos* over the backlog *entries* as
+/// `History::rebase_all` sees them, and moves the entries the edit comes before. Returns the new
+/// position and whether a concurrent delete cancelled the edit.
+fn rebase_over(
+    entries: &mut [(usize, usize)],
+    pos: usize,
+    action: &EditAction,
+) -> Result<(usize, bool), EditError> {
+    let (inserted, deleted) = match *action {
+        EditAction::Insert(ref content) => (content.len(), 0),
+        ref action => (0, action.deleted_len().unwrap_or(0)),
+    };
+    let mut pos = pos;
+    for entry in entries {
+        let (outcome, next) = transform_step(*entry, pos, action);
+        match outcome {
+            TransformOutcome::ShiftedBefore => {}
+            // The entry must not start inside the deleted range.
+            TransformOutcome::NoEffect if cmp::min(entry.0, entry.1) >= pos + deleted => {
+                entry.0 = entry.0 + inserted - deleted;
+                entry.1 = entry.1 + inserted - deleted;
+            }
+            TransformOutcome::AlreadyDeleted => {
+                // the entry deletes less text after this edit
+                entry.0 -= deleted;
+                return Ok((next, true));
+            }
+            _ => return Err(EditError::NotImplemented),
+        }
+        pos = next;
+    }
+    Ok((pos, false))
 }
 
+/// How the transform treated an edit for one backlog entry, see `History::transform`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TransformOutcome {
+    /// The entry changed the text before the edit, which was shifted (rule 1).
+    ShiftedBefore,
+    /// The entry changed the text after the edit (rule 2).
+    NoEffect,
+    /// The entry already deleted the whole range the edit deletes (rule 3).
+    AlreadyDeleted,
+    /// The edit is at the start or end of a range the entry deleted, or starts deleting at its
+    /// end, and moves to where the range was (rule 4).
+    DeletionEdge,
+    /// The entry overlaps the edit in a way that is not handled yet.
+    Overlap,
+}
+
+#[derive(Serialize, Deserialize)]
 struct History {
     first_rev: u32,
     /// Backlog of edits that at least one client has not ack'd.
@@ -117,6 +1153,17 @@ struct History {
     /// Example: inserting 5 characters at index 0 generates: (0, 5)
     /// deleting 4 characters at index 6 generates: (10, 6)
     edits: VecDeque<(usize, usize)>,
+    /// Number of entries in `edits` for each revision in the backlog. Usually 1, but an
+    /// `InsertMany` records one entry per text, in the order they were inserted, and a `Replace`
+    /// one for its text and one for the range it replaced.
+    spans: VecDeque<usize>,
+    /// The edits in the backlog as they were applied, for clients catching up.
+    applied: VecDeque<Edit>,
+    /// The text removed by each edit in `applied`, empty for inserts. Needed to undo deletes.
+    removed: VecDeque<String>,
+    /// Undo groups overlapping the backlog, as the revision of the first edit in each group.
+    /// A group lasts until the next one starts.
+    groups: VecDeque<u32>,
 }
 
 impl History {
@@ -124,6 +1171,10 @@ impl History {
         History {
             first_rev: 0,
             edits: VecDeque::new(),
+            spans: VecDeque::new(),
+            applied: VecDeque::new(),
+            removed: VecDeque::new(),
+            groups: VecDeque::new(),
         }
     }
 
@@ -138,61 +1189,364 @@ impl History {
     ///   in this case, nothing needs to be done
     /// * The edit deletes a range that overlaps with a range deleted by another editor;
     ///   in this case, indices need to be adjusted to avoid deleting an unintended range.
+    ///   If the whole range was already deleted, the edit becomes a `Noop`.
     /// * The edit deletes a range that overlaps with a range inserted by another editor;
     ///   in this case, the edit must be split in two.
     /// * The edit inserts a range contained by a range deleted by another editor;
     ///   in this case, indices are adjusted to move the insert before the deletion (spatially)
-    pub fn transform(&self, edit: Edit) -> Result<Edit, &'static str> {
-        if edit.rev < self.first_rev {
-            // The client already knows about a later edit. This is just trolling.
-            return Err("old revision");
+    /// * The edit inserts at the start or end of a range deleted by another editor, or deletes
+    ///   from its end on; in this case, the edit moves to where the range was, next to the text
+    ///   that survived on either side.
+    pub fn transform(&self, edit: Edit) -> Result<Edit, EditError> {
+        self.transform_with(edit, |_| {})
+    }
+
+    /// Like `transform`, but also returns which rule applied to each backlog entry the edit was
+    /// transformed over, for tests of the transform rules.
+    #[cfg(test)]
+    pub fn transform_traced(&self, edit: Edit) -> (Result<Edit, EditError>, Vec<TransformOutcome>) {
+        let mut outcomes = Vec::new();
+        let result = self.transform_with(edit, |outcome| outcomes.push(outcome));
+        (result, outcomes)
+    }
+
+    /// Implements `transform`, passing the outcome for each backlog entry to *trace*.
+    fn transform_with<F>(&self, edit: Edit, mut trace: F) -> Result<Edit, EditError>
+    where
+        F: FnMut(TransformOutcome),
+    {
+        self.check_rev(edit.rev)?;
+
+        if edit.action == EditAction::Noop {
+            return Ok(edit);
         }
-        if edit.rev > self.first_rev + self.edits.len() as u32 {
-            return Err("future revision");
+
+        if let EditAction::InsertMany(ref inserts) = edit.action {
+            // Each text is transformed like a single insert.
+            let mut rebased = Vec::with_capacity(inserts.len());
+            for &(pos, ref content) in inserts {
+                let mut pos = pos;
+                for &entry in self.entries_since(edit.rev) {
+                    let (outcome, next) = transform_step(entry, pos, &edit.action);
+                    trace(outcome);
+                    if outcome == TransformOutcome::Overlap {
+                        return Err(EditError::NotImplemented);
+                    }
+                    pos = next;
+                }
+                rebased.push((pos, content.clone()));
+            }
+            return Ok(Edit {
+                action: EditAction::InsertMany(rebased),
+                ..edit
+            });
         }
 
-        let delta = edit.rev - self.first_rev;
         let mut pos = edit.pos;
+        let mut action = edit.action;
 
-        for &(old, new) in self.edits.iter().skip(delta as usize) {
-            if old < pos {
-                // Rule 1. Adjust position.
-                pos += new;
-                pos -= old;
-            } else if cmp::min(old, new) > pos {
-                // Rule 2. No effect.
-                continue;
-            } else {
-                // some overlap occurs.
+        for &entry in self.entries_since(edit.rev) {
+            let (outcome, next) = transform_step(entry, pos, &action);
+            trace(outcome);
+            pos = next;
+            match outcome {
+                // What is left of a replace is inserting its text.
+                TransformOutcome::AlreadyDeleted => match action {
+                    EditAction::Replace(_, content) if !content.is_empty() => {
+                        action = EditAction::Insert(content);
+                    }
+                    _ => {
+                        return Ok(Edit {
+                            pos,
+                            action: EditAction::Noop,
+                            ..edit
+                        });
+                    }
+                },
                 // TODO Implement transform for overlapping ranges.
-                return Err("not implemented");
+                TransformOutcome::Overlap => return Err(EditError::NotImplemented),
+                _ => {}
             }
         }
 
-        Ok(Edit { pos, ..edit })
+        Ok(Edit {
+            pos,
+            action,
+            ..edit
+        })
     }
 
-    /// Records the effects of an edit on buffer offsets. Changes the edit's revision to
-    /// the current revision.
-    pub fn record(&mut self, edit: &mut Edit) {
-        self.edits.push_back(match edit.action {
-            EditAction::Insert(ref s) => (edit.pos, edit.pos + s.len()),
-            EditAction::Delete(len) => (edit.pos + len, edit.pos),
-        });
-        edit.rev = self.first_rev + self.edits.len() as u32;
+    /// Rebases several edits of one client that are all based on the revision of the first edit,
+    /// each made on top of the previous ones, like local edits that were not sent yet. The
+    /// backlog is transformed over each rebased edit before rebasing the next one. Returns the
+    /// edits with the revisions they get when applied in order; edits cancelled by a concurrent
+    /// edit become `Noop`s.
+    pub fn rebase_all(&self, edits: Vec<Edit>) -> Result<Vec<Edit>, EditError> {
+        let base = match edits.first() {
+            Some(edit) => edit.rev,
+            None => return Ok(edits),
+        };
+        self.check_rev(base)?;
+        // The backlog as seen by the next edit, with the earlier edits applied before it.
+        let mut entries: Vec<_> = self.entries_since(base).cloned().collect();
+        let mut rev = self.rev();
+        let mut rebased = Vec::with_capacity(edits.len());
+        for mut edit in edits {
+            edit.rev = rev;
+            // Edits with several backlog entries are rebased part by part, in the order
+            // `record` makes their entries.
+            let action = match edit.action {
+                EditAction::Noop => EditAction::Noop,
+                EditAction::InsertMany(ref inserts) => {
+                    let mut rebased = inserts.clone();
+                    for &mut (ref mut pos, ref content) in rebased.iter_mut().rev() {
+                        let insert = EditAction::Insert(content.clone());
+                        *pos = rebase_over(&mut entries, *pos, &insert)?.0;
+                    }
+                    EditAction::InsertMany(rebased)
+                }
+                EditAction::Replace(len, ref content) => {
+                    let mut pos = edit.pos;
+                    if !content.is_empty() {
+                        let insert = EditAction::Insert(content.clone());
+                        pos = rebase_over(&mut entries, pos, &insert)?.0;
+                    }
+                    let delete = EditAction::Delete(len);
+                    let (end, cancelled) =
+                        rebase_over(&mut entries, edit.pos + content.len(), &delete)?;
+                    if end != pos + content.len() {
+                        return Err(EditError::NotImplemented);
+                    }
+                    edit.pos = pos;
+                    if !cancelled {
+                        EditAction::Replace(len, content.clone())
+                    } else if content.is_empty() {
+                        EditAction::Noop
+                    } else {
+                        EditAction::Insert(content.clone())
+                    }
+                }
+                ref action => {
+                    let (pos, cancelled) = rebase_over(&mut entries, edit.pos, action)?;
+                    edit.pos = pos;
+                    if cancelled {
+                        EditAction::Noop
+                    } else {
+                        action.clone()
+                    }
+                }
+            };
+            edit.action = action;
+            if edit.action != EditAction::Noop {
+                rev += 1;
+            }
+            rebased.push(edit);
+        }
+        Ok(rebased)
     }
 
-    /// Gets the current revision number
+    /// Checks that edits can be based on revision *rev*.
+    pub fn check_rev(&self, rev: u32) -> Result<(), EditError> {
+        if rev < self.first_rev {
+            // The client already knows about a later edit. This is just trolling.
+            return Err(EditError::OldRevision);
+        }
+        if rev > self.rev() {
+            return Err(EditError::FutureRevision);
+        }
+        Ok(())
+    }
+
+    /// Maps a position at revision *rev* to the current revision, using the same rules as
+    /// transform. Positions inside a range deleted since then move to its start.
+    /// Returns `None` if *rev* is not in the backlog.
+    pub fn delta_for(&self, rev: u32, pos: usize) -> Option<usize> {
+        if rev < self.first_rev || rev > self.rev() {
+            return None;
+        }
+        let mut pos = pos;
+        for &(old, new) in self.entries_since(rev) {
+            if old < pos {
+                pos += new;
+                pos -= old;
+            } else {
+                pos = cmp::min(pos, new);
+            }
+        }
+        Some(pos)
+    }
+
+    /// Records the effects of an edit on buffer offsets. Changes the edit's revision to
+    /// the current revision. If *continue_group* is set, the edit becomes part of the previous
+    /// undo group instead of starting a new one. *removed* is the text a delete removed.
+    /// A `Noop` is not recorded.
+    pub fn record(&mut self, edit: &mut Edit, removed: String, continue_group: bool) {
+        let offsets = match edit.action {
+            EditAction::Insert(ref s) => vec![(edit.pos, edit.pos + s.len())],
+            EditAction::Delete(len) => vec![(edit.pos + len, edit.pos)],
+            EditAction::DeleteExpecting(ref text) => vec![(edit.pos + text.len(), edit.pos)],
+            // inserted from the highest offset down
+            EditAction::InsertMany(ref inserts) => inserts
+                .iter()
+                .rev()
+                .map(|&(pos, ref s)| (pos, pos + s.len()))
+                .collect(),
+            // the text is inserted before the replaced range is deleted, so edits at the end
+            // of the range stay after the text (rule 4)
+            EditAction::Replace(len, ref s) if s.is_empty() => vec![(edit.pos + len, edit.pos)],
+            EditAction::Replace(len, ref s) => {
+                let end = edit.pos + s.len();
+                vec![(edit.pos, end), (end + len, end)]
+            }
+            EditAction::Noop => {
+                // Nothing changed, so there is no new revision.
+                edit.rev = self.rev();
+                return;
+            }
+        };
+        self.spans.push_back(offsets.len());
+        self.edits.extend(offsets);
+        edit.rev = self.rev();
+        self.applied.push_back(edit.clone());
+        self.removed.push_back(removed);
+        if !continue_group || self.groups.is_empty() {
+            self.groups.push_back(edit.rev);
+        }
+    }
+
+    /// Returns the edits applied after revision *rev*, or `None` if some of them already left
+    /// the backlog.
+    pub fn edits_since(&self, rev: u32) -> Option<Vec<Edit>> {
+        if rev < self.first_rev || rev > self.rev() {
+            return None;
+        }
+        let delta = (rev - self.first_rev) as usize;
+        Some(self.applied.iter().skip(delta).cloned().collect())
+    }
+
+    /// Reconstructs the document at revision *rev* from *table*, the document at the current
+    /// revision, by reverting the edits after *rev*. `None` if *rev* is not in the backlog.
+    fn buffer_at(&self, table: &PieceTable, rev: u32) -> Option<PieceTable> {
+        if rev < self.first_rev || rev > self.rev() {
+            return None;
+        }
+        let mut table = table.clone();
+        let delta = (rev - self.first_rev) as usize;
+        let edits = self.applied.iter().zip(&self.removed).skip(delta);
+        for (edit, removed) in edits.rev() {
+            match edit.action {
+                EditAction::Insert(ref text) => table.delete(edit.pos, text.len()),
+                EditAction::Delete(_) | EditAction::DeleteExpecting(_) => {
+                    table.insert(edit.pos, removed)
+                }
+                EditAction::Replace(_, ref text) => table.replace(edit.pos, text.len(), removed),
+                // Removing the texts from the left leaves the offsets of the others as they
+                // were before the edit.
+                EditAction::InsertMany(ref inserts) => {
+                    for &(pos, ref text) in inserts {
+                        table.delete(pos, text.len());
+                    }
+                }
+                EditAction::Noop => {}
+            }
+        }
+        Some(table)
+    }
+
+    /// The edits of the most recent undo group, oldest first, with the text each removed.
+    /// `None` if there is none or its first edit already left the backlog.
+    pub fn last_group(&self) -> Option<impl Iterator<Item = (&Edit, &str)> + '_> {
+        let &start = self.groups.back()?;
+        if start <= self.first_rev {
+            return None;
+        }
+        let skip = (start - self.first_rev - 1) as usize;
+        let edits = self.applied.iter().zip(&self.removed).skip(skip);
+        Some(edits.map(|(edit, removed)| (edit, removed.as_str())))
+    }
+
+    /// The backlog entries of the edits after revision *rev*, which must be in the backlog.
+    fn entries_since(&self, rev: u32) -> impl Iterator<Item = &(usize, usize)> + '_ {
+        let skip = self
+            .spans
+            .iter()
+            .take((rev - self.first_rev) as usize)
+            .sum();
+        self.edits.iter().skip(skip)
+    }
+
+    /// Snapshot of the backlog's `(old, new)` offset pairs, oldest first. The first entry is
+    /// the edit creating revision `first_rev + 1`; an `InsertMany` has an entry for each text.
+    pub fn current_map(&self) -> Vec<(usize, usize)> {
+        self.edits.iter().cloned().collect()
+    }
+
+    /// Oldest revision still in the backlog, which edits can be based on.
+    pub fn first_rev(&self) -> u32 {
+        self.first_rev
+    }
+
+    /// Number of edits in the backlog.
+    pub fn backlog_len(&self) -> usize {
+        self.spans.len()
+    }
+
+    /// Gets the current revision number
     pub fn rev(&self) -> u32 {
-        self.first_rev + self.edits.len() as u32
+        self.first_rev + self.spans.len() as u32
     }
 
-    /// Removes all backlog entries up to rev
+    /// Removes all backlog entries up to rev. Revisions before the backlog change nothing, and
+    /// future ones only empty it.
     pub fn acknowledge(&mut self, rev: u32) {
+        if rev < self.first_rev {
+            return;
+        }
+        let rev = cmp::min(rev, self.rev());
         for _ in self.first_rev..rev {
-            self.edits.pop_front();
+            let span = self.spans.pop_front().unwrap_or(0);
+            self.edits.drain(..span);
+            self.applied.pop_front();
+            self.removed.pop_front();
         }
         self.first_rev = rev;
+        // drop groups whose edits all left the backlog
+        while let Some(&next) = self.groups.get(1) {
+            if next > rev + 1 {
+                break;
+            }
+            self.groups.pop_front();
+        }
+        if self.spans.is_empty() {
+            self.groups.clear();
+        }
+    }
+
+    /// Estimated memory used by the backlog in bytes: the size of its entries plus the text the
+    /// recorded edits inserted and removed. Allocator overhead and spare capacity are not
+    /// counted.
+    pub fn memory_footprint(&self) -> usize {
+        let content: usize = self
+            .applied
+            .iter()
+            .map(|edit| match edit.action {
+                EditAction::Insert(ref text)
+                | EditAction::DeleteExpecting(ref text)
+                | EditAction::Replace(_, ref text) => text.len(),
+                EditAction::InsertMany(ref inserts) => inserts
+                    .iter()
+                    .map(|(_, text)| mem::size_of::<(usize, String)>() + text.len())
+                    .sum(),
+                EditAction::Delete(_) | EditAction::Noop => 0,
+            })
+            .chain(self.removed.iter().map(String::len))
+            .sum();
+        self.edits.len() * mem::size_of::<(usize, usize)>()
+            + self.spans.len() * mem::size_of::<usize>()
+            + self.applied.len() * mem::size_of::<Edit>()
+            + self.removed.len() * mem::size_of::<String>()
+            + self.groups.len() * mem::size_of::<u32>()
+            + content
     }
 }
 
@@ -201,9 +1555,17 @@ mod tests {
     use super::*;
 
     #[test]
-    fn single_client() -> Result<(), &'static str> {
+    fn single_client() -> Result<(), EditError> {
         let editor = Editor::new();
-        assert_eq!(editor.connect(0u32), (0, String::new()));
+        assert_eq!(
+            editor.connect(0u32),
+            ConnectStatus {
+                rev: 0,
+                buffer: String::new(),
+                checksum: checksum(vec![]),
+                clients: 1,
+            }
+        );
         let edit = Edit {
             rev: 0,
             pos: 0,
@@ -234,11 +1596,1721 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn seeded_editor() {
+        let editor = Editor::<u32>::from("hello");
+        assert_eq!(editor.buffer(), "hello");
+        assert_eq!(
+            editor.connect(0),
+            ConnectStatus {
+                rev: 0,
+                buffer: "hello".to_string(),
+                checksum: checksum("hello".bytes()),
+                clients: 1,
+            }
+        );
+        let edit = Edit {
+            rev: 0,
+            pos: "hello".len(),
+            action: EditAction::Insert(" world".to_string()),
+        };
+        assert_eq!(editor.edit(0, edit).unwrap().rev, 1);
+        assert_eq!(editor.buffer(), "hello world");
+    }
+
+    #[test]
+    fn document_checksum() {
+        assert_eq!(checksum(vec![]), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(checksum("a".bytes()), 0xaf63_dc4c_8601_ec8c);
+
+        let editor = Editor::<u32>::from("hello world");
+        let before = editor.checksum();
+        let edit = Edit {
+            rev: 0,
+            pos: "hello".len(),
+            action: EditAction::Insert(",".to_string()),
+        };
+        editor.edit(0, edit).unwrap();
+        assert_ne!(editor.checksum(), before);
+        let status = editor.connect(1);
+        assert_eq!(status.checksum, checksum(status.buffer.bytes()));
+        assert_eq!(status.checksum, PieceTable::from("hello, world").checksum());
+    }
+
+    #[test]
+    fn overlapping_delete_noop() {
+        let editor = Editor::<u32>::from("hello world!");
+        editor.connect(0);
+        editor.connect(1);
+        let edit = Edit {
+            rev: 0,
+            pos: "hello".len(),
+            action: EditAction::Delete(" world".len()),
+        };
+        assert_eq!(editor.edit(0, edit).unwrap().rev, 1);
+
+        // client 1 deletes part of the same range
+        let edit = Edit {
+            rev: 0,
+            pos: "hello w".len(),
+            action: EditAction::Delete("orld".len()),
+        };
+        let noop = editor.edit(1, edit).unwrap();
+        assert_eq!(noop.action, EditAction::Noop);
+        assert_eq!(noop.rev, 1);
+        assert_eq!(editor.buffer(), "hello!");
+        assert_eq!(editor.pending_edits(0).unwrap().len(), 1);
+
+        // a partial overlap is still not handled
+        let edit = Edit {
+            rev: 0,
+            pos: "hello w".len(),
+            action: EditAction::Delete("orld!".len()),
+        };
+        assert_eq!(editor.edit(1, edit), Err(EditError::NotImplemented));
+    }
+
+    #[test]
+    fn flush_acks() {
+        let editor = Editor::<u32>::from("a \nb \nc ");
+        assert_eq!(editor.trim_trailing_whitespace().len(), 3);
+        // without clients, nobody needs the backlog
+        assert_eq!(editor.flush_acks(), 3);
+        assert_eq!(editor.pending_edits(0), None);
+        assert_eq!(editor.pending_edits(3), Some(vec![]));
+
+        editor.connect(0);
+        editor.connect(1);
+        for rev in 3..6 {
+            let edit = Edit {
+                rev,
+                pos: 0,
+                action: EditAction::Insert("x".to_string()),
+            };
+            editor.edit(0, edit).unwrap();
+        }
+        // client 1 is still based on revision 3
+        assert_eq!(editor.flush_acks(), 3);
+        assert_eq!(editor.pending_edits(3).unwrap().len(), 3);
+
+        let edit = Edit {
+            rev: 6,
+            pos: 0,
+            action: EditAction::Insert("y".to_string()),
+        };
+        editor.edit(1, edit).unwrap();
+        assert_eq!(editor.flush_acks(), 5);
+        assert_eq!(editor.pending_edits(5).unwrap().len(), 2);
+        assert_eq!(editor.buffer(), "yxxxa\nb\nc");
+    }
+
+    #[test]
+    fn multi_cursor() {
+        let editor = Editor::<u32>::from("abc\ndef\nghi");
+        editor.connect(0);
+        let edits = editor
+            .edit_many(0, 0, &[0, 4, 8], EditAction::Insert("> ".to_string()))
+            .unwrap();
+        assert_eq!(editor.buffer(), "> abc\n> def\n> ghi");
+        let positions: Vec<_> = edits.iter().map(|edit| (edit.pos, edit.rev)).collect();
+        assert_eq!(positions, [(8, 1), (4, 2), (0, 3)]);
+
+        // one invalid position rejects all of them
+        let action = EditAction::Delete("> ".len());
+        assert_eq!(
+            editor.edit_many(0, 3, &[0, 6, 100], action.clone()),
+            Err(EditError::OutOfRange)
+        );
+        assert_eq!(
+            editor.edit_many(0, 3, &[6, 0], action.clone()),
+            Err(EditError::InvalidIndex)
+        );
+        assert_eq!(
+            editor.edit_many(0, 3, &[0, 1], action.clone()),
+            Err(EditError::InvalidIndex)
+        );
+        assert_eq!(editor.buffer(), "> abc\n> def\n> ghi");
+
+        let edits = editor.edit_many(0, 3, &[0, 6, 12], action).unwrap();
+        assert_eq!(edits.len(), 3);
+        assert_eq!(editor.buffer(), "abc\ndef\nghi");
+        // all cursors are undone at once
+        assert_eq!(editor.undo_global().unwrap().len(), 3);
+        assert_eq!(editor.buffer(), "> abc\n> def\n> ghi");
+    }
+
+    #[test]
+    fn multi_cursor_revisions() {
+        let editor = Editor::<u32>::from("abc");
+        editor.connect(0);
+        editor.connect(1);
+        for rev in 0..3 {
+            let edit = Edit {
+                rev,
+                pos: 0,
+                action: EditAction::Insert("x".to_string()),
+            };
+            editor.edit(1, edit).unwrap();
+        }
+        editor.acknowledge_public(0, 1).unwrap();
+        let before = (
+            editor.clients_at_revision(),
+            editor.0.borrow().1.current_map(),
+        );
+
+        let insert = EditAction::Insert("> ".to_string());
+        assert_eq!(
+            editor.edit_many(0, 1_000_000, &[0], insert.clone()),
+            Err(EditError::FutureRevision)
+        );
+        assert_eq!(
+            editor.edit_many(0, 0, &[0], insert),
+            Err(EditError::OldRevision)
+        );
+        let after = (
+            editor.clients_at_revision(),
+            editor.0.borrow().1.current_map(),
+        );
+        assert_eq!(after, before);
+        assert_eq!(editor.buffer(), "xxxabc");
+    }
+
+    #[test]
+    fn backlog_map() {
+        let editor = Editor::<u32>::from("hello world");
+        editor.connect(0);
+        editor.connect(1);
+        let edit = Edit {
+            rev: 0,
+            pos: 5,
+            action: EditAction::Insert(",".to_string()),
+        };
+        editor.edit(0, edit).unwrap();
+        let edit = Edit {
+            rev: 1,
+            pos: 0,
+            action: EditAction::Delete("hello".len()),
+        };
+        editor.edit(0, edit).unwrap();
+        assert_eq!(editor.backlog_map(), (0, vec![(5, 6), (5, 0)]));
+
+        let edit = Edit {
+            rev: 2,
+            pos: 0,
+            action: EditAction::Insert("Hi".to_string()),
+        };
+        editor.edit(1, edit).unwrap();
+        assert_eq!(editor.backlog_map(), (1, vec![(5, 0), (0, 2)]));
+    }
+
+    #[test]
+    fn index_errors() {
+        let editor = Editor::from("añb");
+        editor.connect(0);
+        let edit = |pos, action| Edit {
+            rev: 0,
+            pos,
+            action,
+        };
+        let mid_char = edit(2, EditAction::Insert("x".to_string()));
+        assert_eq!(editor.edit(0, mid_char), Err(EditError::NotCharBoundary));
+        let past_end = edit(5, EditAction::Insert("x".to_string()));
+        assert_eq!(editor.edit(0, past_end), Err(EditError::OutOfRange));
+        let into_char = edit(0, EditAction::Delete(2));
+        assert_eq!(editor.edit(0, into_char), Err(EditError::NotCharBoundary));
+        assert_eq!(
+            editor.edit(0, edit(0, EditAction::Delete(0))),
+            Err(EditError::InvalidIndex)
+        );
+        assert_eq!(editor.buffer(), "añb");
+        assert_eq!(editor.rev(), 0);
+    }
+
+    #[test]
+    fn preview_apply() {
+        let editor = Editor::from("hello world");
+        editor.connect(0);
+        let edit = |pos, action| Edit {
+            rev: 0,
+            pos,
+            action,
+        };
+        let edits = [
+            edit(5, EditAction::Delete(6)),
+            edit(5, EditAction::Insert(", you".to_string())),
+        ];
+        assert_eq!(editor.preview_apply(&edits), Ok("hello, you".to_string()));
+        assert_eq!(editor.preview_apply(&[]), Ok("hello world".to_string()));
+        // the second edit is checked against the result of the first
+        let edits = [
+            edit(5, EditAction::Delete(6)),
+            edit(6, EditAction::Insert("!".to_string())),
+        ];
+        assert_eq!(editor.preview_apply(&edits), Err(EditError::OutOfRange));
+        assert_eq!(editor.buffer(), "hello world");
+        assert_eq!(editor.rev(), 0);
+    }
+
+    #[test]
+    fn dry_run_validation() {
+        let editor = Editor::<u32>::from("hällo");
+        editor.connect(0);
+        editor.connect(1);
+        let edit = Edit {
+            rev: 0,
+            pos: 0,
+            action: EditAction::Insert("> ".to_string()),
+        };
+        assert_eq!(editor.is_valid_edit(&edit), Ok(()));
+        editor.edit(0, edit).unwrap();
+
+        let delete = |rev, pos, len| Edit {
+            rev,
+            pos,
+            action: EditAction::Delete(len),
+        };
+        let cases = [
+            (delete(0, 2, 1), Err(EditError::NotCharBoundary)),
+            (delete(1, 4, 0), Err(EditError::InvalidIndex)),
+            (delete(1, 5, 10), Err(EditError::OutOfRange)),
+            (delete(2, 0, 1), Err(EditError::FutureRevision)),
+            (delete(0, 0, 1), Err(EditError::NotImplemented)),
+            (delete(1, 0, 2), Ok(())),
+        ];
+        for &(ref edit, expected) in &cases {
+            assert_eq!(editor.is_valid_edit(edit), expected, "{:?}", edit);
+            assert_eq!(editor.buffer().len(), "> hällo".len());
+        }
+        for (edit, expected) in cases.iter().cloned() {
+            assert_eq!(editor.edit(1, edit).map(|_| ()), expected);
+        }
+        assert_eq!(editor.buffer(), "hällo");
+    }
+
+    #[test]
+    fn replace_all() {
+        let editor = Editor::<u32>::from("one fox, two foxes");
+        let mut mirrors = [PieceTable::new(), PieceTable::new()];
+        for (id, mirror) in mirrors.iter_mut().enumerate() {
+            mirror.rebuild_from(&editor.connect(id as u32).buffer);
+        }
+        let mut broadcasts = editor.replace_all("fox", "cat");
+        assert_eq!(broadcasts.len(), 4);
+        assert_eq!(editor.buffer(), "one cat, two cates");
+        assert_eq!(editor.replace_all("dog", "cat"), []);
+
+        // client 1 has not seen the replacements yet
+        let edit = Edit {
+            rev: 0,
+            pos: "one fox, two foxes".len(),
+            action: EditAction::Insert("!".to_string()),
+        };
+        broadcasts.push(editor.edit(1, edit).unwrap());
+        for mirror in &mut mirrors {
+            for edit in &broadcasts {
+                mirror.apply_edit(&edit.action, edit.pos).unwrap();
+            }
+            assert_eq!(mirror.to_string(), editor.buffer());
+        }
+        assert_eq!(editor.buffer(), "one cat, two cates!");
+    }
+
+    #[test]
+    fn serialized_len() {
+        let actions = [
+            EditAction::Insert(String::new()),
+            EditAction::Insert("plain ä text \u{1F600}".to_string()),
+            EditAction::Insert("\"quoted\" \\ \n\r\t \u{8}\u{c} \u{1} \u{1f}".to_string()),
+            EditAction::Delete(0),
+            EditAction::Delete(12345),
+            EditAction::Delete(usize::MAX),
+            EditAction::Noop,
+            EditAction::InsertMany(Vec::new()),
+            EditAction::InsertMany(vec![(0, "a\n".to_string()), (123, "ä".to_string())]),
+            EditAction::Replace(7, "\"new\" ä".to_string()),
+            EditAction::Replace(0, String::new()),
+        ];
+        for action in &actions {
+            let json = serde_json::to_string(action).unwrap();
+            assert_eq!(action.serialized_len(), json.len(), "{}", json);
+        }
+    }
+
+    #[test]
+    fn len_and_lines() {
+        let editor = Editor::<u32>::new();
+        assert!(editor.is_empty());
+        assert_eq!(editor.line_count(), 1);
+        let edit = Edit {
+            rev: 0,
+            pos: 0,
+            action: EditAction::Insert("first\nsecond ä\n".to_string()),
+        };
+        editor.edit(0, edit).unwrap();
+        assert_eq!(editor.len(), "first\nsecond ä\n".len());
+        assert_eq!(editor.line_count(), 3);
+        let edit = Edit {
+            rev: 1,
+            pos: 5,
+            action: EditAction::Delete(1),
+        };
+        editor.edit(0, edit).unwrap();
+        assert_eq!(editor.len(), "firstsecond ä\n".len());
+        assert_eq!(editor.line_count(), 2);
+        // only reading, the client map is unchanged
+        assert_eq!(editor.0.borrow().2.len(), 1);
+    }
+
+    #[test]
+    fn memory_footprint() {
+        let mut history = History::new();
+        let empty = history.memory_footprint();
+        assert_eq!(empty, 0);
+        let mut footprints = vec![empty];
+        for pos in 0..4 {
+            let mut edit = Edit {
+                rev: history.rev(),
+                pos,
+                action: EditAction::Insert("some text".to_string()),
+            };
+            history.record(&mut edit, String::new(), false);
+            footprints.push(history.memory_footprint());
+        }
+        let mut delete = Edit {
+            rev: history.rev(),
+            pos: 0,
+            action: EditAction::Delete(4),
+        };
+        history.record(&mut delete, "some".to_string(), false);
+        footprints.push(history.memory_footprint());
+        assert!(
+            footprints.windows(2).all(|pair| pair[0] < pair[1]),
+            "{:?}",
+            footprints
+        );
+
+        history.acknowledge(3);
+        let acknowledged = history.memory_footprint();
+        assert!(acknowledged < footprints[5]);
+        assert_eq!(acknowledged, footprints[5] - footprints[3]);
+        history.acknowledge(5);
+        assert_eq!(history.memory_footprint(), 0);
+
+        let editor = Editor::from("memory");
+        let before = editor.memory_footprint();
+        assert!(before >= "memory".len());
+        editor.connect(0);
+        let edit = Edit {
+            rev: 0,
+            pos: 0,
+            action: EditAction::Insert("more ".to_string()),
+        };
+        editor.edit(0, edit).unwrap();
+        assert!(editor.memory_footprint() > before);
+    }
+
+    #[test]
+    fn editor_stats() {
+        let editor = Editor::from("grüße\nall");
+        editor.connect(0);
+        editor.connect(1);
+        let edits = vec![
+            (0, EditAction::Insert("¡hola!\n".to_string())),
+            (9, EditAction::Delete(3)),
+            (5, EditAction::Insert("\n".to_string())),
+        ];
+        for (rev, (pos, action)) in edits.into_iter().enumerate() {
+            let edit = Edit {
+                rev: rev as u32,
+                pos,
+                action,
+            };
+            editor.edit(0, edit).unwrap();
+        }
+        editor.disconnect(&1);
+
+        let stats = editor.stats();
+        let table = editor.table_stats();
+        let buffer = editor.buffer();
+        assert_eq!(buffer, "¡hol\na!\ngße\nall");
+        assert_eq!(stats.rev, editor.rev());
+        assert_eq!(stats.first_rev, editor.first_rev());
+        assert_eq!(stats.clients, editor.clients_at_revision().len());
+        assert_eq!(stats.len, buffer.len());
+        assert_eq!(stats.chars, buffer.chars().count());
+        assert_eq!(stats.lines, buffer.lines().count());
+        assert_eq!(stats.lines, editor.line_count());
+        assert_eq!(stats.pieces, table.piece_count);
+        assert_eq!(stats.buffer_overhead, table.buffer_len - table.logical_len);
+        assert_eq!(
+            stats,
+            EditorStats {
+                rev: 3,
+                first_rev: 2,
+                clients: 1,
+                len: 17,
+                chars: 15,
+                lines: 4,
+                pieces: 6,
+                buffer_overhead: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn diff_since() {
+        let editor = Editor::from("the quick brown fox");
+        editor.connect(0);
+        // keeps the history from revision 0 on
+        editor.connect(1);
+        let edits = [
+            (4, EditAction::Delete(6)),
+            (4, EditAction::Insert("slöw ".to_string())),
+            (0, EditAction::Insert("> ".to_string())),
+            (
+                0,
+                EditAction::InsertMany(vec![(7, "!".to_string()), (17, "?".to_string())]),
+            ),
+        ];
+        let mut buffers = vec![editor.buffer()];
+        for (rev, &(pos, ref action)) in edits.iter().enumerate() {
+            let edit = Edit {
+                rev: rev as u32,
+                pos,
+                action: action.clone(),
+            };
+            editor.edit(0, edit).unwrap();
+            buffers.push(editor.buffer());
+        }
+        assert_eq!(editor.buffer(), "> the s!löw brown? fox");
+        for (rev, buffer) in buffers.iter().enumerate() {
+            let diff = editor.diff_since(rev as u32).unwrap();
+            assert!(diff.len() <= 2);
+            assert!(diff.iter().all(|edit| edit.rev == 4));
+            let mut mirror = PieceTable::from(buffer.as_str());
+            for edit in &diff {
+                apply_remote_edit(&mut mirror, edit).unwrap();
+            }
+            assert_eq!(mirror.to_string(), editor.buffer(), "{:?}", diff);
+        }
+        assert_eq!(editor.diff_since(4), Some(vec![]));
+        // only the part that changed
+        let diff = editor.diff_since(3).unwrap();
+        let pos = "> the s".len();
+        assert_eq!((diff[0].pos, diff[1].pos), (pos, pos));
+        assert_eq!(diff[0].action, EditAction::Delete("löw brown".len()));
+        assert_eq!(
+            diff[1].action,
+            EditAction::Insert("!löw brown?".to_string())
+        );
+        assert_eq!(editor.diff_since(5), None);
+    }
+
+    #[test]
+    fn read_only_acks() {
+        let editor = Editor::from("text");
+        editor.connect(0);
+        // only reads, but keeps the backlog from revision 0 on
+        editor.connect(1);
+        for rev in 0..4 {
+            let edit = Edit {
+                rev,
+                pos: 0,
+                action: EditAction::Insert("a".to_string()),
+            };
+            editor.edit(0, edit).unwrap();
+        }
+        assert_eq!(editor.revision_range(), (0, 4));
+        assert_eq!(editor.acknowledge_public(1, 3), Ok(()));
+        // the editing client is still based on revision 3
+        assert_eq!(editor.revision_range(), (3, 4));
+        assert_eq!(
+            editor.acknowledge_public(1, 5),
+            Err(EditError::FutureRevision)
+        );
+        assert_eq!(editor.acknowledge_public(1, 1), Ok(()));
+        assert_eq!(editor.clients_at_revision()[&1], 3);
+        let edit = Edit {
+            rev: 4,
+            pos: 0,
+            action: EditAction::Noop,
+        };
+        editor.edit(0, edit).unwrap();
+        assert_eq!(editor.acknowledge_public(1, 4), Ok(()));
+        assert_eq!(editor.revision_range(), (4, 4));
+        assert_eq!(editor.backlog_len(), 0);
+        assert_eq!(editor.buffer(), "aaaatext");
+    }
+
+    #[test]
+    fn unchecked_edits() {
+        let actions = vec![
+            (0, EditAction::Insert("unchecked ".to_string())),
+            (2, EditAction::Delete(2)),
+            (
+                0,
+                EditAction::InsertMany(vec![(0, "[".to_string()), (7, "]".to_string())]),
+            ),
+            (3, EditAction::DeleteExpecting("ec".to_string())),
+        ];
+        let checked = Editor::from("edits");
+        checked.connect(0);
+        let checked_edits: Vec<Edit> = actions
+            .iter()
+            .enumerate()
+            .map(|(rev, &(pos, ref action))| {
+                let edit = Edit {
+                    rev: rev as u32,
+                    pos,
+                    action: action.clone(),
+                };
+                checked.edit(0, edit).unwrap()
+            })
+            .collect();
+        let trusted = Editor::<u32>::from("edits");
+        let trusted_edits = trusted.edit_unchecked(actions);
+        assert_eq!(trusted.buffer(), "[unked] edits");
+        assert_eq!(trusted.buffer(), checked.buffer());
+        assert_eq!(trusted.rev(), checked.rev());
+        assert_eq!(trusted_edits[..3], checked_edits[..3]);
+        // only `edit` checks the expected text, and then records a plain delete
+        assert_eq!(checked_edits[3].action, EditAction::Delete(2));
+        assert_eq!(trusted_edits[3].pos, checked_edits[3].pos);
+    }
+
+    #[test]
+    fn backlog_accessors() {
+        let mut history = History::new();
+        assert_eq!((history.first_rev(), history.backlog_len()), (0, 0));
+        for pos in 0..3 {
+            let mut edit = Edit {
+                rev: history.rev(),
+                pos,
+                action: EditAction::Insert("a".to_string()),
+            };
+            history.record(&mut edit, String::new(), false);
+        }
+        assert_eq!((history.first_rev(), history.backlog_len()), (0, 3));
+        history.acknowledge(2);
+        assert_eq!((history.first_rev(), history.backlog_len()), (2, 1));
+        let mut noop = Edit {
+            rev: 3,
+            pos: 0,
+            action: EditAction::Noop,
+        };
+        history.record(&mut noop, String::new(), false);
+        assert_eq!(history.backlog_len(), 1);
+        history.acknowledge(3);
+        assert_eq!((history.first_rev(), history.backlog_len()), (3, 0));
+
+        let editor = Editor::<u32>::from("text ");
+        editor.connect(0);
+        assert_eq!(editor.trim_trailing_whitespace().len(), 1);
+        assert_eq!((editor.first_rev(), editor.backlog_len()), (0, 1));
+        editor.disconnect(&0);
+        assert_eq!((editor.first_rev(), editor.backlog_len()), (1, 0));
+    }
+
+    #[test]
+    fn grapheme_boundaries() {
+        let flags = "\u{1F1E9}\u{1F1EA}\u{1F1EB}\u{1F1F7}";
+        let editor = Editor::<u32>::from(flags);
+        editor.connect(0);
+        let delete = |rev, pos, len| Edit {
+            rev,
+            pos,
+            action: EditAction::Delete(len),
+        };
+        let half_flag = '\u{1F1E9}'.len_utf8();
+        // allowed by default
+        assert_eq!(editor.is_valid_edit(&delete(0, 0, half_flag)), Ok(()));
+
+        editor.set_grapheme_boundaries(true);
+        assert_eq!(
+            editor.edit(0, delete(0, 0, half_flag)),
+            Err(EditError::SplitsGrapheme)
+        );
+        assert_eq!(
+            editor.edit(0, delete(0, 2 * half_flag, half_flag)),
+            Err(EditError::SplitsGrapheme)
+        );
+        let insert = Edit {
+            rev: 0,
+            pos: 3 * half_flag,
+            action: EditAction::Insert("x".to_string()),
+        };
+        assert_eq!(editor.edit(0, insert), Err(EditError::SplitsGrapheme));
+        assert_eq!(editor.buffer(), flags);
+        editor.edit(0, delete(0, 0, 2 * half_flag)).unwrap();
+        assert_eq!(editor.buffer(), "\u{1F1EB}\u{1F1F7}");
+    }
+
+    #[test]
+    fn splice() {
+        let editor = Editor::<u32>::from("hello world");
+        editor.connect(0);
+        editor.connect(1);
+        let insert = Edit {
+            rev: 0,
+            pos: 0,
+            action: EditAction::Insert(">> ".to_string()),
+        };
+        editor.edit(1, insert).unwrap();
+
+        // based on revision 0, so it is rebased over the insert
+        let edit = editor.splice(0, 0, 6, 5, "there").unwrap();
+        assert_eq!(editor.buffer(), ">> hello there");
+        assert_eq!(edit.pos, 9);
+        assert_eq!(edit.action, EditAction::Replace(5, "there".to_string()));
+        assert_eq!(edit.rev, 2);
+        assert_eq!(editor.rev(), 2);
+        // undone as a whole
+        let undo = editor.undo_global().unwrap();
+        assert_eq!(undo.len(), 1);
+        assert_eq!(undo[0].action, EditAction::Replace(5, "world".to_string()));
+        assert_eq!(editor.buffer(), ">> hello world");
+
+        // a failing splice leaves the document alone
+        assert_eq!(editor.splice(0, 3, 12, 10, "x"), Err(EditError::OutOfRange));
+        assert_eq!(editor.buffer(), ">> hello world");
+        let edit = editor.splice(0, 3, 9, 0, "you ").unwrap();
+        assert_eq!(edit.action, EditAction::Insert("you ".to_string()));
+        assert_eq!(editor.buffer(), ">> hello you world");
+        let edit = editor.splice(0, 4, 9, 4, "").unwrap();
+        assert_eq!(edit.rev, 5);
+        assert_eq!(editor.buffer(), ">> hello world");
+    }
+
+    #[test]
+    fn transform_replace() {
+        let editor = Editor::<u32>::from("one two three");
+        editor.connect(0);
+        editor.connect(1);
+        editor.splice(0, 0, 4, 3, "2").unwrap();
+        assert_eq!(editor.buffer(), "one 2 three");
+        let history = &editor.0.borrow().1;
+        assert_eq!(history.current_map(), [(4, 5), (8, 5)]);
+
+        // edits after the replaced range move with it, also right at its end
+        let at = |pos, action| Edit {
+            rev: 0,
+            pos,
+            action,
+        };
+        let moved = history.transform(at(7, EditAction::Insert("!".into())));
+        assert_eq!(moved.unwrap().pos, 5);
+        let moved = history.transform(at(8, EditAction::Delete(5)));
+        assert_eq!(moved.unwrap(), at(6, EditAction::Delete(5)));
+        let before = history.transform(at(0, EditAction::Replace(3, "1".into())));
+        assert_eq!(before.unwrap(), at(0, EditAction::Replace(3, "1".into())));
+        // at the start of the text, like a concurrent insert, is not handled yet
+        let same = history.transform(at(4, EditAction::Replace(3, "deux".into())));
+        assert_eq!(same, Err(EditError::NotImplemented));
+
+        // a replace of text that was deleted already only inserts
+        let mut history = History::new();
+        let mut delete = at(4, EditAction::Delete("two ".len()));
+        history.record(&mut delete, "two ".to_string(), false);
+        let deleted = history.transform(at(4, EditAction::Replace(3, "deux".into())));
+        assert_eq!(deleted.unwrap(), at(4, EditAction::Insert("deux".into())));
+        let deleted = history.transform(at(4, EditAction::Replace(3, String::new())));
+        assert_eq!(deleted.unwrap(), at(4, EditAction::Noop));
+    }
+
+    #[test]
+    fn compact() {
+        let editor = Editor::<u32>::from("start");
+        editor.connect(0);
+        for rev in 0..10 {
+            let (pos, action) = if rev % 2 == 0 {
+                (0, EditAction::Insert(format!("churn {} ", rev)))
+            } else {
+                (0, EditAction::Delete(6))
+            };
+            editor.edit(0, Edit { pos, rev, action }).unwrap();
+        }
+        let buffer = editor.buffer();
+        let clients = editor.clients_at_revision();
+        let before = editor.table_stats();
+        assert!(before.buffer_len > buffer.len());
+
+        assert_eq!(editor.compact(), before.buffer_len - buffer.len());
+        assert_eq!(editor.buffer(), buffer);
+        assert_eq!(editor.table_stats().buffer_len, buffer.len());
+        assert_eq!(editor.table_stats().piece_count, 1);
+        assert_eq!(editor.rev(), 10);
+        assert_eq!(editor.clients_at_revision(), clients);
+        // edits based on the revision before compacting still apply
+        let edit = Edit {
+            pos: END,
+            rev: 10,
+            action: EditAction::Insert("!".to_string()),
+        };
+        editor.edit(0, edit).unwrap();
+        assert_eq!(editor.buffer(), buffer + "!");
+    }
+
+    #[test]
+    fn dump_and_load() {
+        let editor = Editor::<u32>::from("state");
+        editor.connect(0);
+        editor.connect(1);
+        let edit = Edit {
+            pos: 5,
+            rev: 0,
+            action: EditAction::Insert(" dump".to_string()),
+        };
+        editor.edit(0, edit).unwrap();
+        let mut dump = Vec::new();
+        editor.dump(&mut dump).unwrap();
+
+        let loaded = Editor::<u32>::load(dump.as_slice()).unwrap();
+        assert_eq!(loaded.buffer(), editor.buffer());
+        assert_eq!(loaded.rev(), editor.rev());
+        assert_eq!(loaded.clients_at_revision(), editor.clients_at_revision());
+        assert_eq!(loaded.connect(2), editor.connect(2));
+        // client 1 has not seen the insert yet, and its edit is still rebased over it
+        let edit = Edit {
+            pos: 0,
+            rev: 0,
+            action: EditAction::Insert("a ".to_string()),
+        };
+        assert_eq!(loaded.edit(1, edit).unwrap().rev, 2);
+        assert_eq!(loaded.buffer(), "a state dump");
+
+        let broken = String::from_utf8(dump)
+            .unwrap()
+            .replace("\"removed\":[\"\"]", "\"removed\":[]");
+        assert!(Editor::<u32>::load(broken.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn insert_many() {
+        let editor = Editor::<u32>::from("one two three");
+        editor.connect(0);
+        editor.connect(1);
+        let prefix = Edit {
+            pos: 0,
+            rev: 0,
+            action: EditAction::Insert("> ".to_string()),
+        };
+        editor.edit(1, prefix).unwrap();
+
+        // based on revision 0, so every position is rebased over the prefix
+        let cursors = [3, 7, 13]
+            .iter()
+            .map(|&pos| (pos, "!".to_string()))
+            .collect();
+        let edit = Edit {
+            pos: 0,
+            rev: 0,
+            action: EditAction::InsertMany(cursors),
+        };
+        let applied = editor.edit(0, edit).unwrap();
+        assert_eq!(applied.rev, 2);
+        let rebased = vec![
+            (5, "!".to_string()),
+            (9, "!".to_string()),
+            (15, "!".to_string()),
+        ];
+        assert_eq!(applied.action, EditAction::InsertMany(rebased));
+        assert_eq!(editor.buffer(), "> one! two! three!");
+
+        // a concurrent edit is rebased over all inserts
+        let concurrent = Edit {
+            pos: 8,
+            rev: 1,
+            action: EditAction::Insert("?".to_string()),
+        };
+        assert_eq!(editor.edit(1, concurrent).unwrap().pos, 9);
+        assert_eq!(editor.buffer(), "> one! tw?o! three!");
+
+        // one invalid position rejects the whole action
+        let invalid = Edit {
+            pos: 0,
+            rev: 3,
+            action: EditAction::InsertMany(vec![(0, "x".to_string()), (100, "x".to_string())]),
+        };
+        assert_eq!(editor.edit(0, invalid), Err(EditError::OutOfRange));
+        let unsorted = Edit {
+            pos: 0,
+            rev: 3,
+            action: EditAction::InsertMany(vec![(4, "x".to_string()), (0, "x".to_string())]),
+        };
+        assert_eq!(editor.edit(0, unsorted), Err(EditError::InvalidIndex));
+        assert_eq!(editor.rev(), 3);
+        assert_eq!(editor.buffer(), "> one! tw?o! three!");
+
+        // the backlog still trims by revision
+        editor.disconnect(&1);
+        let ack = Edit {
+            pos: 0,
+            rev: 3,
+            action: EditAction::Noop,
+        };
+        editor.edit(0, ack).unwrap();
+        assert_eq!(editor.backlog_map(), (3, Vec::new()));
+    }
+
+    #[test]
+    fn take_buffer() {
+        let editor = Editor::<u32>::from("harvest\nme");
+        editor.connect(0);
+        let (content, edit) = editor.take_buffer();
+        assert_eq!(content, "harvest\nme");
+        assert_eq!(editor.buffer(), "");
+        assert_eq!(editor.rev(), 1);
+        let edit = edit.unwrap();
+        assert_eq!((edit.pos, edit.rev), (0, 1));
+        assert_eq!(edit.action, EditAction::Delete(10));
+
+        // the empty document still takes edits
+        let insert = Edit {
+            pos: 0,
+            rev: 1,
+            action: EditAction::Insert("again".to_string()),
+        };
+        editor.edit(0, insert).unwrap();
+        assert_eq!(editor.take_buffer().0, "again");
+        assert_eq!(editor.take_buffer(), (String::new(), None));
+        assert_eq!(editor.rev(), 3);
+        assert_eq!(editor.len(), 0);
+    }
+
+    #[test]
+    fn undo_insert_many() {
+        let editor = Editor::<u32>::from("one two three");
+        editor.connect(0);
+        let inserts = vec![
+            (3, "!".to_string()),
+            (7, "!!".to_string()),
+            (13, "?".to_string()),
+        ];
+        let edit = Edit {
+            pos: 0,
+            rev: 0,
+            action: EditAction::InsertMany(inserts),
+        };
+        editor.edit(0, edit).unwrap();
+        assert_eq!(editor.buffer(), "one! two!! three?");
+
+        // all texts are deleted in a single revision
+        let undo = editor.undo_global().unwrap();
+        assert_eq!(undo.len(), 1);
+        assert_eq!(undo[0].rev, 2);
+        assert_eq!(undo[0].pos, 3);
+        let between = " two three".to_string();
+        assert_eq!(undo[0].action, EditAction::Replace(14, between));
+        assert_eq!(editor.buffer(), "one two three");
+        editor.undo_global().unwrap();
+        assert_eq!(editor.buffer(), "one! two!! three?");
+
+        // adjacent texts leave nothing in between
+        let editor = Editor::<u32>::from("ab");
+        editor.connect(0);
+        let inserts = vec![(1, "x".to_string()), (2, "y".to_string())];
+        let edit = Edit {
+            pos: 0,
+            rev: 0,
+            action: EditAction::InsertMany(inserts),
+        };
+        editor.edit(0, edit).unwrap();
+        assert_eq!(editor.buffer(), "axby");
+        let undo = editor.undo_global().unwrap();
+        assert_eq!(undo[0].action, EditAction::Replace(3, "b".to_string()));
+        assert_eq!(editor.buffer(), "ab");
+    }
+
+    #[test]
+    fn undo_global() {
+        let editor = Editor::<u32>::from("hello");
+        editor.connect(0);
+        assert_eq!(editor.undo_global(), Err(EditError::OldRevision));
+
+        editor
+            .edit(
+                0,
+                Edit {
+                    rev: 0,
+                    pos: 5,
+                    action: EditAction::Insert(" world".to_string()),
+                },
+            )
+            .unwrap();
+        let undo = editor.undo_global().unwrap();
+        assert_eq!(undo.len(), 1);
+        assert_eq!(undo[0].action, EditAction::Delete(6));
+        assert_eq!(undo[0].rev, 2);
+        assert_eq!(editor.buffer(), "hello");
+
+        editor
+            .edit(
+                0,
+                Edit {
+                    rev: 2,
+                    pos: 1,
+                    action: EditAction::Delete(3),
+                },
+            )
+            .unwrap();
+        assert_eq!(editor.buffer(), "ho");
+        editor.undo_global().unwrap();
+        assert_eq!(editor.buffer(), "hello");
+        // undoing again reverts the undo
+        editor.undo_global().unwrap();
+        assert_eq!(editor.buffer(), "ho");
+
+        // without clients, the whole history is trimmed
+        editor.disconnect(&0);
+        assert_eq!(editor.undo_global(), Err(EditError::OldRevision));
+    }
+
+    #[test]
+    fn connect_status_json() {
+        let status = ConnectStatus {
+            rev: 3,
+            buffer: "text".to_string(),
+            checksum: checksum("text".bytes()),
+            clients: 2,
+        };
+        let json = serde_json::to_string(&status).unwrap();
+        assert_eq!(
+            serde_json::from_str::<ConnectStatus>(&json).unwrap(),
+            status
+        );
+        assert!(json.starts_with("{\"rev\":3,\"buffer\":\"text\","));
+    }
+
+    #[test]
+    fn idle_clients() {
+        let editor = Editor::<u32>::from("text");
+        editor.connect(0);
+        editor.connect(1);
+        let minute = Duration::from_secs(60);
+        assert!(editor.idle_clients(minute).is_empty());
+        for client in editor.0.borrow_mut().2.values_mut() {
+            client.last_seen -= minute;
+        }
+        let mut idle = editor.idle_clients(minute);
+        idle.sort();
+        assert_eq!(idle, [0, 1]);
+
+        let edit = Edit {
+            rev: 0,
+            pos: 0,
+            action: EditAction::Insert("more ".to_string()),
+        };
+        editor.edit(1, edit).unwrap();
+        assert_eq!(editor.idle_clients(minute), [0]);
+    }
+
+    #[test]
+    fn append_at_end() {
+        let editor = Editor::<u32>::from("abc");
+        editor.connect(0);
+        editor.connect(1);
+        let append = |rev, content: &str| Edit {
+            rev,
+            pos: END,
+            action: EditAction::Insert(content.to_string()),
+        };
+        let concurrent = Edit {
+            rev: 0,
+            pos: 3,
+            action: EditAction::Insert("X".to_string()),
+        };
+        editor.edit(1, concurrent).unwrap();
+        // based on revision 0, but still lands after the concurrent insert
+        let edit = editor.edit(0, append(0, "!")).unwrap();
+        assert_eq!(edit.pos, 4);
+        assert_eq!(editor.buffer(), "abcX!");
+        assert_eq!(editor.backlog_map(), (0, vec![(3, 4), (4, 5)]));
+        assert_eq!(
+            editor.is_valid_edit(&append(3, "?")),
+            Err(EditError::FutureRevision)
+        );
+    }
+
+    #[test]
+    fn replica() {
+        use std::rc::Rc;
+
+        let primary = Editor::<u32>::from("shared text");
+        let replica = Editor::<u32>::from("shared text");
+        let broadcast = Rc::new(RefCell::new(Vec::new()));
+        {
+            let broadcast = Rc::clone(&broadcast);
+            primary.on_edit(move |edit| broadcast.borrow_mut().push(edit.clone()));
+        }
+        primary.connect(0);
+        primary.connect(1);
+        let edits = [
+            (0, 0, 0, EditAction::Insert("> ".to_string())),
+            // concurrent with the first, so it is transformed
+            (1, 0, 6, EditAction::Delete(5)),
+            (0, 2, 0, EditAction::DeleteExpecting("> ".to_string())),
+            (1, 2, 8, EditAction::Insert(" plain".to_string())),
+        ];
+        for &(id, rev, pos, ref action) in &edits {
+            let edit = Edit {
+                rev,
+                pos,
+                action: action.clone(),
+            };
+            primary.edit(id, edit).unwrap();
+        }
+        for edit in broadcast.borrow().iter() {
+            replica.apply_remote(edit.clone()).unwrap();
+            assert_eq!(replica.rev(), edit.rev);
+        }
+        assert_eq!(replica.buffer(), "shared plain");
+        assert_eq!(replica.buffer(), primary.buffer());
+        assert_eq!(replica.rev(), primary.rev());
+
+        // only the next revision is accepted
+        let edit = |rev| Edit {
+            rev,
+            pos: 0,
+            action: EditAction::Insert("x".to_string()),
+        };
+        assert_eq!(replica.apply_remote(edit(4)), Err(EditError::OldRevision));
+        assert_eq!(
+            replica.apply_remote(edit(6)),
+            Err(EditError::FutureRevision)
+        );
+        assert_eq!(replica.apply_remote(edit(5)), Ok(()));
+        assert_eq!(replica.buffer(), "xshared plain");
+    }
+
+    #[test]
+    fn edit_callback() {
+        use std::rc::Rc;
+
+        let editor = Rc::new(Editor::<u32>::from("text"));
+        editor.connect(0);
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        {
+            let seen = Rc::clone(&seen);
+            let weak = Rc::downgrade(&editor);
+            editor.on_edit(move |edit| {
+                // the editor can be used from the callback
+                let buffer = weak.upgrade().unwrap().buffer();
+                seen.borrow_mut().push((edit.clone(), buffer));
+            });
+        }
+        let edit = Edit {
+            rev: 0,
+            pos: 4,
+            action: EditAction::Insert("s".to_string()),
+        };
+        let applied = editor.edit(0, edit.clone()).unwrap();
+        // rejected edits are not passed on
+        assert!(editor.edit(0, edit).is_err());
+        assert_eq!(*seen.borrow(), [(applied, "texts".to_string())]);
+    }
+
+    #[test]
+    fn transform_outcomes() {
+        use TransformOutcome::*;
+
+        // "hello world" -> "> hello world" -> "> hello wo"
+        let mut history = History::new();
+        for &(pos, ref action) in &[
+            (0, EditAction::Insert("> ".to_string())),
+            (10, EditAction::Delete(3)),
+        ] {
+            let mut edit = Edit {
+                rev: history.rev(),
+                pos,
+                action: action.clone(),
+            };
+            history.record(&mut edit, String::new(), false);
+        }
+        let at = |pos, action| Edit {
+            rev: 0,
+            pos,
+            action,
+        };
+
+        let (result, outcomes) = history.transform_traced(at(5, EditAction::Insert(",".into())));
+        assert_eq!(result.unwrap().pos, 7);
+        assert_eq!(outcomes, [ShiftedBefore, NoEffect]);
+
+        let (result, outcomes) = history.transform_traced(at(9, EditAction::Delete(2)));
+        assert_eq!(result.unwrap(), at(10, EditAction::Noop));
+        assert_eq!(outcomes, [ShiftedBefore, AlreadyDeleted]);
+
+        let (result, outcomes) = history.transform_traced(at(0, EditAction::Insert("#".into())));
+        assert_eq!(result, Err(EditError::NotImplemented));
+        assert_eq!(outcomes, [Overlap]);
+
+        // edits based on the latest revision are not transformed at all
+        let (result, outcomes) = history.transform_traced(Edit {
+            rev: 2,
+            ..at(3, EditAction::Delete(1))
+        });
+        assert_eq!(result.unwrap().pos, 3);
+        assert!(outcomes.is_empty());
+    }
+
+    #[test]
+    fn transform_deletion_edges() {
+        use TransformOutcome::*;
+
+        // "hello world!" -> "hello!"
+        let mut history = History::new();
+        let delete = Edit {
+            rev: 0,
+            pos: 5,
+            action: EditAction::Delete(" world".len()),
+        };
+        history.record(&mut delete.clone(), " world".to_string(), false);
+        assert_eq!(history.current_map(), [(11, 5)]);
+        let at = |pos, action| Edit {
+            rev: 0,
+            pos,
+            action,
+        };
+        let insert = |pos| at(pos, EditAction::Insert(",".into()));
+
+        // at the new offset, where the deleted range started
+        let (result, outcomes) = history.transform_traced(insert(5));
+        assert_eq!(result.unwrap().pos, 5);
+        assert_eq!(outcomes, [DeletionEdge]);
+        // at the old offset, where the deleted range ended
+        let (result, outcomes) = history.transform_traced(insert(11));
+        assert_eq!(result.unwrap().pos, 5);
+        assert_eq!(outcomes, [DeletionEdge]);
+        let (result, outcomes) = history.transform_traced(at(11, EditAction::Delete(1)));
+        assert_eq!(result.unwrap(), at(5, EditAction::Delete(1)));
+        assert_eq!(outcomes, [DeletionEdge]);
+        // strictly inside is not handled yet
+        assert_eq!(history.transform(insert(8)), Err(EditError::NotImplemented));
+
+        // through the editor, from a client that did not see the delete
+        let editor = Editor::from("hello world!");
+        editor.connect(0);
+        editor.connect(1);
+        editor.edit(0, delete).unwrap();
+        editor.edit(1, insert(11)).unwrap();
+        assert_eq!(editor.buffer(), "hello,!");
+    }
+
+    #[test]
+    fn buffer_range() {
+        let text = "first line\nsëcond line\nthird line";
+        let editor = Editor::<u32>::from(text);
+        editor.connect(0);
+        let edit = Edit {
+            rev: 0,
+            pos: 6,
+            action: EditAction::Insert("long ".to_string()),
+        };
+        editor.edit(0, edit).unwrap();
+        let buffer = editor.buffer();
+        assert_eq!(editor.buffer_range(11, 28), Ok(buffer[11..28].to_string()));
+        assert_eq!(editor.buffer_range(0, buffer.len()), Ok(buffer.clone()));
+        assert_eq!(editor.buffer_range(5, 5), Ok(String::new()));
+
+        // inside the ë, reversed and past the end
+        assert_eq!(editor.buffer_range(18, 20), Err(EditError::InvalidIndex));
+        assert_eq!(editor.buffer_range(5, 4), Err(EditError::InvalidIndex));
+        assert_eq!(
+            editor.buffer_range(0, buffer.len() + 1),
+            Err(EditError::InvalidIndex)
+        );
+    }
+
+    #[test]
+    fn rebase_stacked_edits() {
+        let insert = |pos, content: &str| Edit {
+            rev: 0,
+            pos,
+            action: EditAction::Insert(content.to_string()),
+        };
+        let editor = Editor::<u32>::from("hello world");
+        editor.connect(0);
+        editor.connect(1);
+        editor.edit(1, insert(0, "> ")).unwrap();
+
+        // made locally by client 0 on top of each other: "hello, world" -> "hello, world!"
+        let local = vec![insert(5, ","), insert(12, "!")];
+        let rebased = editor.rebase_all(local).unwrap();
+        assert_eq!(
+            rebased,
+            [
+                Edit {
+                    rev: 1,
+                    ..insert(7, ",")
+                },
+                Edit {
+                    rev: 2,
+                    ..insert(14, "!")
+                }
+            ]
+        );
+        for edit in rebased {
+            editor.edit(0, edit).unwrap();
+        }
+        assert_eq!(editor.buffer(), "> hello, world!");
+
+        // the remote delete of "d" needs to be moved behind the local comma to cancel the
+        // local delete of "d"
+        let editor = Editor::<u32>::from("hello world");
+        editor.connect(0);
+        editor.connect(1);
+        let delete_d = Edit {
+            rev: 0,
+            pos: 10,
+            action: EditAction::Delete(1),
+        };
+        editor.edit(1, delete_d).unwrap();
+        let local = vec![
+            insert(5, ","),
+            Edit {
+                rev: 0,
+                pos: 11,
+                action: EditAction::Delete(1),
+            },
+        ];
+        let rebased = editor.rebase_all(local).unwrap();
+        assert_eq!(rebased[1].action, EditAction::Noop);
+        for edit in rebased {
+            editor.edit(0, edit).unwrap();
+        }
+        assert_eq!(editor.buffer(), "hello, worl");
+    }
+
+    #[test]
+    fn rebase_stacked_multi_edits() {
+        let editor = Editor::<u32>::from("one two three");
+        editor.connect(0);
+        editor.connect(1);
+        let prefix = Edit {
+            rev: 0,
+            pos: 0,
+            action: EditAction::Insert("> ".to_string()),
+        };
+        editor.edit(1, prefix).unwrap();
+
+        // "one! two! three" -> "one! 2! three"
+        let local = vec![
+            Edit {
+                rev: 0,
+                pos: 0,
+                action: EditAction::InsertMany(vec![(3, "!".to_string()), (7, "!".to_string())]),
+            },
+            Edit {
+                rev: 0,
+                pos: 5,
+                action: EditAction::Replace(3, "2".to_string()),
+            },
+        ];
+        let rebased = editor.rebase_all(local).unwrap();
+        assert_eq!(
+            rebased[0].action,
+            EditAction::InsertMany(vec![(5, "!".to_string()), (9, "!".to_string())])
+        );
+        assert_eq!((rebased[1].pos, rebased[1].rev), (7, 2));
+        for edit in rebased {
+            editor.edit(0, edit).unwrap();
+        }
+        assert_eq!(editor.buffer(), "> one! 2! three");
+
+        // replacing text that was deleted concurrently with nothing does nothing
+        let delete_two = Edit {
+            rev: 3,
+            pos: 7,
+            action: EditAction::Delete(2),
+        };
+        editor.edit(1, delete_two).unwrap();
+        let local = vec![Edit {
+            rev: 3,
+            pos: 7,
+            action: EditAction::Replace(1, String::new()),
+        }];
+        let rebased = editor.rebase_all(local).unwrap();
+        assert_eq!(rebased[0].action, EditAction::Noop);
+    }
+
+    #[test]
+    fn delete_expecting() {
+        let editor = Editor::<u32>::from("delete this word");
+        editor.connect(0);
+        editor.connect(1);
+        let delete_this = Edit {
+            rev: 0,
+            pos: 7,
+            action: EditAction::DeleteExpecting("this ".to_string()),
+        };
+        // a concurrent edit changes the text, but keeps its length
+        let race = Edit {
+            rev: 0,
+            pos: 7,
+            action: EditAction::Delete(4),
+        };
+        editor.edit(1, race).unwrap();
+        let race = Edit {
+            rev: 1,
+            pos: 7,
+            action: EditAction::Insert("that".to_string()),
+        };
+        editor.edit(1, race).unwrap();
+        let stale = Edit {
+            rev: 2,
+            ..delete_this.clone()
+        };
+        assert_eq!(editor.edit(0, stale), Err(EditError::Conflict));
+        assert_eq!(editor.buffer(), "delete that word");
+
+        let delete_that = Edit {
+            rev: 2,
+            pos: 7,
+            action: EditAction::DeleteExpecting("that ".to_string()),
+        };
+        let applied = editor.edit(0, delete_that).unwrap();
+        assert_eq!(applied.action, EditAction::Delete(5));
+        assert_eq!(editor.buffer(), "delete word");
+        assert_eq!(
+            delete_this.action.serialized_len(),
+            serde_json::to_string(&delete_this.action).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn clients_at_revision() {
+        let editor = Editor::<u32>::from("text");
+        editor.connect(0);
+        for rev in 0..3 {
+            let edit = Edit {
+                rev,
+                pos: 0,
+                action: EditAction::Insert("a".to_string()),
+            };
+            editor.edit(0, edit).unwrap();
+        }
+        editor.connect(1);
+        let edit = Edit {
+            rev: 3,
+            pos: 0,
+            action: EditAction::Insert("b".to_string()),
+        };
+        editor.edit(1, edit).unwrap();
+        assert_eq!(editor.rev(), 4);
+        let revs = editor.clients_at_revision();
+        assert_eq!(revs.len(), 2);
+        // client 0 has not acknowledged its own last edit yet
+        assert_eq!(revs[&0], 2);
+        assert_eq!(revs[&1], 3);
+        assert_eq!(editor.rev() - revs[&0], 2);
+    }
+
+    #[test]
+    fn remote_edits() {
+        let editor = Editor::<u32>::from("mirrored text");
+        editor.connect(0);
+        let mut mirror = PieceTable::from(editor.connect(1).buffer);
+        let edits = [
+            (0, EditAction::Insert("a ".to_string())),
+            (2, EditAction::Delete(9)),
+            (2, EditAction::DeleteExpecting("text".to_string())),
+        ];
+        for (rev, (pos, action)) in edits.iter().cloned().enumerate() {
+            let edit = Edit {
+                pos,
+                rev: rev as u32,
+                action,
+            };
+            let broadcast = editor.edit(0, edit).unwrap();
+            apply_remote_edit(&mut mirror, &broadcast).unwrap();
+            assert_eq!(mirror.to_string(), editor.buffer());
+        }
+        assert_eq!(mirror.to_string(), "a ");
+        let stale = Edit {
+            pos: 2,
+            rev: 3,
+            action: EditAction::Delete(1),
+        };
+        assert_eq!(
+            apply_remote_edit(&mut mirror, &stale),
+            Err(EditError::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn revision_range() {
+        let editor = Editor::<u32>::from("range");
+        assert_eq!(editor.revision_range(), (0, 0));
+        editor.connect(0);
+        editor.connect(1);
+        for rev in 0..3 {
+            let edit = Edit {
+                rev,
+                pos: 0,
+                action: EditAction::Insert("a".to_string()),
+            };
+            editor.edit(0, edit).unwrap();
+        }
+        // client 1 still needs everything
+        assert_eq!(editor.revision_range(), (0, 3));
+        assert!(editor.pending_edits(0).is_some());
+
+        let ack = Edit {
+            rev: 2,
+            pos: 0,
+            action: EditAction::Noop,
+        };
+        editor.edit(1, ack).unwrap();
+        assert_eq!(editor.revision_range(), (2, 3));
+        assert_eq!(editor.pending_edits(1), None);
+        assert_eq!(editor.pending_edits(2).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn transform_preview() {
+        let editor = Editor::new();
+        editor.connect(0u32);
+        editor.connect(1u32);
+        let edit = Edit {
+            rev: 0,
+            pos: 0,
+            action: EditAction::Insert("world".to_string()),
+        };
+        editor.edit(0, edit).unwrap();
+        let edit = Edit {
+            rev: 1,
+            pos: 0,
+            action: EditAction::Insert("hello ".to_string()),
+        };
+        editor.edit(0, edit).unwrap();
+
+        // client 1 still thinks the buffer is "world"
+        let edit = Edit {
+            rev: 1,
+            pos: "world".len(),
+            action: EditAction::Insert("!".to_string()),
+        };
+        let preview = editor.transform_only(edit.clone()).unwrap();
+        assert_eq!(preview.pos, "hello world".len());
+        assert_eq!(editor.buffer(), "hello world");
+        assert_eq!(editor.edit(1, edit).unwrap().pos, preview.pos);
+        assert_eq!(editor.buffer(), "hello world!");
+
+        let edit = Edit {
+            rev: 7,
+            pos: 0,
+            action: EditAction::Delete(1),
+        };
+        assert_eq!(editor.transform_only(edit), Err(EditError::FutureRevision));
+    }
+
+    #[test]
+    fn trim_whitespace() {
+        let editor = Editor::<u32>::from("first  \nsecond\t\n \t \nthird \r\nlast\t");
+        editor.connect(0);
+        let edits = editor.trim_trailing_whitespace();
+        assert_eq!(editor.buffer(), "first\nsecond\n\nthird\r\nlast");
+        assert_eq!(edits.len(), 5);
+        assert_eq!(
+            edits[0].pos,
+            "first  \nsecond\t\n \t \nthird \r\nlast".len()
+        );
+        assert_eq!(edits.last().unwrap().rev, 5);
+        assert_eq!(editor.0.borrow().1.last_group().unwrap().count(), 5);
+        assert!(editor.trim_trailing_whitespace().is_empty());
+
+        // clients that have not seen the trim still get their edits transformed
+        let edit = Edit {
+            rev: 0,
+            pos: "first  \nsec".len(),
+            action: EditAction::Insert("!".to_string()),
+        };
+        editor.edit(0, edit).unwrap();
+        assert_eq!(editor.buffer(), "first\nsec!ond\n\nthird\r\nlast");
+    }
+
+    #[test]
+    fn expand_tabs() {
+        let editor = Editor::<u32>::from("\tone\n1\ttwo\n1234\tthree\nä\t\tfour\nno tabs");
+        let edits = editor.expand_tabs(4);
+        assert_eq!(
+            editor.buffer(),
+            "    one\n1   two\n1234    three\nä       four\nno tabs"
+        );
+        assert_eq!(edits.len(), 10);
+        editor.undo_global().unwrap();
+        assert_eq!(
+            editor.buffer(),
+            "\tone\n1\ttwo\n1234\tthree\nä\t\tfour\nno tabs"
+        );
+
+        let editor = Editor::<u32>::from("a\tb");
+        editor.expand_tabs(0);
+        assert_eq!(editor.buffer(), "ab");
+    }
+
+    #[test]
+    fn position_delta() {
+        let editor = Editor::<u32>::from("The fox jumps.");
+        editor.connect(0);
+        let edits = [
+            (4, EditAction::Insert("quick ".to_string())),
+            (0, EditAction::Delete("The ".len())),
+            ("quick fox".len(), EditAction::Insert(" never".to_string())),
+        ];
+        for (rev, (pos, action)) in edits.iter().cloned().enumerate() {
+            let rev = rev as u32;
+            editor.edit(1, Edit { pos, rev, action }).unwrap();
+        }
+        assert_eq!(editor.buffer(), "quick fox never jumps.");
+
+        let inner = editor.0.borrow();
+        let history = &inner.1;
+        for &pos in &[5, "The fox jumps".len()] {
+            let insert = Edit {
+                rev: 0,
+                pos,
+                action: EditAction::Insert("x".to_string()),
+            };
+            let transformed = history.transform(insert).unwrap();
+            assert_eq!(history.delta_for(0, pos), Some(transformed.pos));
+        }
+        assert_eq!(
+            history.delta_for(0, "The fox".len()),
+            Some("quick fox".len())
+        );
+        assert_eq!(history.delta_for(1, 2), Some(0));
+        assert_eq!(history.delta_for(3, 2), Some(2));
+        assert_eq!(history.delta_for(4, 2), None);
+        drop(inner);
+        // once all clients are gone, the history is trimmed
+        editor.disconnect(&0);
+        editor.disconnect(&1);
+        assert_eq!(editor.delta_for(0, 2), None);
+        assert_eq!(editor.delta_for(3, 2), Some(2));
+    }
+
+    #[test]
+    fn reconnect_stale_client() {
+        let editor = Editor::new();
+        editor.connect(0u32);
+        editor.connect(1u32);
+        let mut broadcasts = Vec::new();
+        for (rev, word) in ["one", " two", " three"].iter().enumerate() {
+            let edit = Edit {
+                rev: rev as u32,
+                pos: editor.buffer().len(),
+                action: EditAction::Insert(word.to_string()),
+            };
+            broadcasts.push(editor.edit(0, edit).unwrap());
+        }
+
+        // client 1 only saw the first two edits before losing its connection
+        editor.disconnect(&1);
+        assert_eq!(editor.reconnect(1, 2), Some(broadcasts[2..].to_vec()));
+        assert_eq!(editor.pending_edits(3), Some(Vec::new()));
+        assert_eq!(editor.pending_edits(4), None);
+        // client 0 already acknowledged revision 2
+        assert_eq!(editor.pending_edits(1), None);
+
+        // client 1 pins the history while it is connected
+        let edit = Edit {
+            rev: 3,
+            pos: 0,
+            action: EditAction::Delete(3),
+        };
+        editor.edit(0, edit).unwrap();
+        assert_eq!(editor.pending_edits(2).unwrap().len(), 2);
+        editor.disconnect(&1);
+        assert_eq!(editor.pending_edits(2), None);
+        assert_eq!(editor.reconnect(1, 2), None);
+    }
+
+    #[test]
+    fn typing_undo_group() {
+        let editor = Editor::new();
+        editor.connect(0u32);
+        // keeps the backlog from being trimmed
+        editor.connect(1u32);
+        let type_at = |rev: u32, pos: usize, text: &str| {
+            let edit = Edit {
+                rev,
+                pos,
+                action: EditAction::Insert(text.to_string()),
+            };
+            editor.edit(0, edit).unwrap();
+        };
+        for (i, c) in "hello".chars().enumerate() {
+            type_at(i as u32, i, &c.to_string());
+        }
+        assert_eq!(editor.buffer(), "hello");
+
+        // the word is undone in one step, and so is the undo
+        let undo = editor.undo_global().unwrap();
+        assert_eq!(undo.len(), 5);
+        assert_eq!(undo[0].action, EditAction::Delete(1));
+        assert_eq!(undo[0].pos, 4);
+        assert_eq!(editor.buffer(), "");
+        editor.undo_global().unwrap();
+        assert_eq!(editor.buffer(), "hello");
+
+        // moving elsewhere or pasting starts new groups
+        let rev = editor.rev();
+        type_at(rev, 0, "!");
+        type_at(rev + 1, 1, " world");
+        editor.undo_global().unwrap();
+        assert_eq!(editor.buffer(), "!hello");
+        editor.undo_global().unwrap();
+        assert_eq!(editor.buffer(), "! worldhello");
+    }
+
+    #[test]
+    fn rename_client() {
+        let editor = Editor::new();
+        editor.connect(0u32);
+        editor.connect(1u32);
+        for (i, c) in "hi".chars().enumerate() {
+            let edit = Edit {
+                rev: i as u32,
+                pos: i,
+                action: EditAction::Insert(c.to_string()),
+            };
+            editor.edit(0, edit).unwrap();
+        }
+        editor.rename_client(0, 7);
+        {
+            let inner = editor.0.borrow();
+            assert!(!inner.2.contains_key(&0));
+            assert_eq!(inner.2[&7].rev, 1);
+        }
+
+        // the renamed client keeps typing in the same undo group
+        let edit = Edit {
+            rev: 2,
+            pos: 2,
+            action: EditAction::Insert("!".to_string()),
+        };
+        assert_eq!(editor.edit(7, edit).unwrap().rev, 3);
+        assert_eq!(editor.0.borrow().1.last_group().unwrap().count(), 3);
+        assert_eq!(editor.buffer(), "hi!");
+
+        editor.rename_client(0, 8);
+        assert!(!editor.0.borrow().2.contains_key(&8));
+    }
+
     #[test]
     fn two_clients() {
         let editor = Editor::new();
 
-        assert_eq!(editor.connect(0u32), (0, String::new()));
+        assert_eq!(
+            editor.connect(0u32),
+            ConnectStatus {
+                rev: 0,
+                buffer: String::new(),
+                checksum: checksum(vec![]),
+                clients: 1,
+            }
+        );
         let edit = Edit {
             rev: 0,
             pos: 0,
@@ -246,7 +3318,15 @@ mod tests {
         };
         assert_eq!(editor.edit(0, edit).unwrap().rev, 1);
 
-        assert_eq!(editor.connect(1), (1, "This is a test.".to_string()));
+        assert_eq!(
+            editor.connect(1),
+            ConnectStatus {
+                rev: 1,
+                buffer: "This is a test.".to_string(),
+                checksum: editor.checksum(),
+                clients: 2,
+            }
+        );
 
         let edit = Edit {
             rev: 1,
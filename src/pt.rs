@@ -1,5 +1,17 @@
+use std::collections::BTreeMap;
 use std::fmt;
+use std::io;
+use std::iter::FromIterator;
+use std::str;
 
+use serde::de::{self, Deserialize, Deserializer};
+
+use super::{EditAction, EditError};
+
+/// Piece table holding the editor contents. Used by the `Editor`, and by clients that keep a
+/// local mirror of the document.
+/// Serialized with its buffer and pieces, which are validated when deserializing.
+#[derive(Serialize, Clone)]
 pub struct PieceTable {
     /// Editor contents buffer. This only ever grows, unless garbage-collected.
     /// Unlike usual piece-table implementations, this one only uses one buffer.
@@ -14,6 +26,18 @@ pub struct PieceTable {
     pieces: Vec<(usize, usize)>,
 }
 
+/// Statistics about the layout of a piece table, for diagnosing fragmentation.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct TableStats {
+    pub piece_count: usize,
+    /// Size of the buffer in bytes, including text that was deleted again.
+    pub buffer_len: usize,
+    /// Length of the content in bytes.
+    pub logical_len: usize,
+    /// Number of pieces by length, rounded down to a power of two. Empty pieces count as 0.
+    pub piece_lengths: BTreeMap<usize, usize>,
+}
+
 impl PieceTable {
     pub fn new() -> Self {
         let init: &[(usize, usize)] = &[(0, 0)];
@@ -23,13 +47,376 @@ impl PieceTable {
         }
     }
 
+    /// Reads all content from *reader* into a table with a single piece. The bytes are read
+    /// straight into the buffer, without an intermediate `String`. Fails with `InvalidData` if
+    /// the content is not valid UTF-8.
+    pub fn from_reader<R: io::Read>(mut reader: R) -> io::Result<Self> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let buffer =
+            String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(PieceTable::from(buffer))
+    }
+
+    /// Length of the content in bytes.
+    pub fn len(&self) -> usize {
+        self.pieces.iter().map(|&(_, len)| len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of lines, which is one more than the number of newlines. Line numbers as used by
+    /// `line_col_to_byte` range from 0 to `line_count() - 1`.
+    pub fn line_count(&self) -> usize {
+        1 + self
+            .slices()
+            .map(|slice| slice.bytes().filter(|&b| b == b'\n').count())
+            .sum::<usize>()
+    }
+
+    /// Byte range `(start, end)` of every line, *end* excluding the newline. There are always
+    /// `line_count()` ranges; the last one is empty if the content ends with a newline.
+    pub fn line_byte_ranges(&self) -> Vec<(usize, usize)> {
+        let mut ranges = Vec::new();
+        let mut start = 0;
+        let mut offset = 0;
+        for slice in self.slices() {
+            for (i, _) in slice.match_indices('\n') {
+                ranges.push((start, offset + i));
+                start = offset + i + 1;
+            }
+            offset += slice.len();
+        }
+        ranges.push((start, offset));
+        ranges
+    }
+
+    /// Number of pieces the content is split into.
+    pub fn piece_count(&self) -> usize {
+        self.pieces.len()
+    }
+
+    /// Size of the buffer in bytes, including text that was deleted again.
+    pub fn buffer_len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Statistics about the pieces and the buffer.
+    pub fn stats(&self) -> TableStats {
+        let mut piece_lengths = BTreeMap::new();
+        for &(_, len) in &self.pieces {
+            let bucket = if len == 0 {
+                0
+            } else {
+                1 << (usize::BITS - 1 - len.leading_zeros())
+            };
+            *piece_lengths.entry(bucket).or_insert(0) += 1;
+        }
+        TableStats {
+            piece_count: self.piece_count(),
+            buffer_len: self.buffer_len(),
+            logical_len: self.len(),
+            piece_lengths,
+        }
+    }
+
+    /// Iterates over the content of all pieces, in order.
+    fn slices(&self) -> impl Iterator<Item = &str> + '_ {
+        self.pieces
+            .iter()
+            .map(move |&(offset, len)| &self.buffer[offset..offset + len])
+    }
+
+    /// Iterates over the bytes of the content.
+    pub fn bytes(&self) -> impl Iterator<Item = u8> + '_ {
+        self.slices().flat_map(str::bytes)
+    }
+
+    /// Iterates over the characters of the content.
+    pub fn chars(&self) -> impl Iterator<Item = char> + '_ {
+        self.slices().flat_map(str::chars)
+    }
+
+    /// Iterates over the characters of the content and their byte positions.
+    pub fn char_indices(&self) -> impl Iterator<Item = (usize, char)> + '_ {
+        self.chars().scan(0, |pos, c| {
+            let start = *pos;
+            *pos += c.len_utf8();
+            Some((start, c))
+        })
+    }
+
+    /// Byte offset of the character with index *n*, or `len()` if *n* is the number of characters.
+    /// `None` if there are fewer characters.
+    pub fn char_to_byte(&self, n: usize) -> Option<usize> {
+        let mut count = 0;
+        for (pos, _) in self.char_indices() {
+            if count == n {
+                return Some(pos);
+            }
+            count += 1;
+        }
+        if count == n {
+            Some(self.len())
+        } else {
+            None
+        }
+    }
+
+    /// Character index of byte offset *pos*, which counts the characters before it. `None` if
+    /// *pos* is inside a character or after the end.
+    pub fn byte_to_char(&self, pos: usize) -> Option<usize> {
+        let mut count = 0;
+        for (start, _) in self.char_indices() {
+            if start >= pos {
+                return if start == pos { Some(count) } else { None };
+            }
+            count += 1;
+        }
+        if pos == self.len() {
+            Some(count)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the character starting at byte position pos, if pos is on a char boundary and
+    /// not at the end.
+    pub fn char_at(&self, pos: usize) -> Option<char> {
+        let (piece, end) = self.piece_index_del(pos)?;
+        let (offset, len) = self.pieces[piece];
+        let start = offset + len - (end - pos);
+        if !self.buffer.is_char_boundary(start) {
+            return None;
+        }
+        self.buffer[start..offset + len].chars().next()
+    }
+
+    /// Converts a line number and a column, which is a character index within the line, to a
+    /// byte position. Both start at 0. The column may be one past the last character of the
+    /// line, which is the position before the newline.
+    pub fn line_col_to_byte(&self, line: usize, col: usize) -> Option<usize> {
+        let (mut cur_line, mut cur_col) = (0, 0);
+        for (pos, c) in self.char_indices() {
+            if cur_line == line && cur_col == col {
+                return Some(pos);
+            }
+            if c == '\n' {
+                if cur_line == line {
+                    return None;
+                }
+                cur_line += 1;
+                cur_col = 0;
+            } else {
+                cur_col += 1;
+            }
+        }
+        if cur_line == line && cur_col == col {
+            Some(self.len())
+        } else {
+            None
+        }
+    }
+
+    /// Returns the character at a line and column (see line_col_to_byte), if there is one.
+    pub fn get_char_at_line_col(&self, line: usize, col: usize) -> Option<char> {
+        match self.char_at(self.line_col_to_byte(line, col)?) {
+            Some('\n') => None,
+            c => c,
+        }
+    }
+
+    /// Finds whitespace at the end of each line. Returns pairs of (offset, length) in ascending
+    /// order. A `\r\n` line ending is not counted as whitespace.
+    pub fn trailing_whitespace(&self) -> Vec<(usize, usize)> {
+        let mut ranges = Vec::new();
+        let mut run = None;
+        let mut chars = self.char_indices().peekable();
+        while let Some((pos, c)) = chars.next() {
+            let line_end = c == '\n' || (c == '\r' && chars.peek().map(|&(_, c)| c) == Some('\n'));
+            if line_end {
+                if let Some(start) = run.take() {
+                    ranges.push((start, pos - start));
+                }
+            } else if c.is_whitespace() {
+                run = run.or(Some(pos));
+            } else {
+                run = None;
+            }
+        }
+        if let Some(start) = run {
+            ranges.push((start, self.len() - start));
+        }
+        ranges
+    }
+
+    /// Finds all tabs and how many spaces each one is wide, i.e. how many columns (counted in
+    /// characters) it takes to reach the next multiple of *tab_width*. Returns pairs of
+    /// (offset, width) in ascending order. A *tab_width* of 0 makes all tabs 0 wide.
+    pub fn tab_widths(&self, tab_width: usize) -> Vec<(usize, usize)> {
+        let mut tabs = Vec::new();
+        let mut col = 0;
+        for (pos, c) in self.char_indices() {
+            match c {
+                '\n' => col = 0,
+                '\t' if tab_width == 0 => tabs.push((pos, 0)),
+                '\t' => {
+                    let width = tab_width - col % tab_width;
+                    tabs.push((pos, width));
+                    col += width;
+                }
+                _ => col += 1,
+            }
+        }
+        tabs
+    }
+
+    /// Checks whether *needle* occurs in the content, also across piece boundaries. Stops at the
+    /// first match.
+    pub fn contains(&self, needle: &str) -> bool {
+        if needle.is_empty() {
+            return true;
+        }
+        let mut any = false;
+        self.find_each(needle.as_bytes(), |_| {
+            any = true;
+            false
+        });
+        any
+    }
+
+    /// Checks if the content equals *other*, like `to_string() == other` but without allocating.
+    /// Stops at the first piece that differs.
+    pub fn equal_content(&self, other: &str) -> bool {
+        if self.len() != other.len() {
+            return false;
+        }
+        let mut rest = other.as_bytes();
+        self.slices().all(|slice| {
+            let (start, end) = rest.split_at(slice.len());
+            rest = end;
+            start == slice.as_bytes()
+        })
+    }
+
+    /// Replaces every occurrence of *from* with *to*. Returns the number of replacements, 0 if
+    /// *from* is empty.
+    pub fn replace_all(&mut self, from: &str, to: &str) -> usize {
+        let positions = self.find_all(from);
+        // Going backwards keeps the positions of the remaining matches valid.
+        for &pos in positions.iter().rev() {
+            self.delete(pos, from.len());
+            if !to.is_empty() {
+                self.insert(pos, to);
+            }
+        }
+        positions.len()
+    }
+
+    /// Byte positions of the non-overlapping occurrences of *needle*, in order. Empty if *needle*
+    /// is empty.
+    pub fn find_all(&self, needle: &str) -> Vec<usize> {
+        let mut positions = Vec::new();
+        if !needle.is_empty() {
+            self.find_each(needle.as_bytes(), |pos| {
+                positions.push(pos);
+                true
+            });
+        }
+        positions
+    }
+
+    /// Number of non-overlapping occurrences of *needle*, counted like `find_all` but without
+    /// collecting the positions. 0 if *needle* is empty.
+    pub fn count_matches(&self, needle: &str) -> usize {
+        let mut count = 0;
+        if !needle.is_empty() {
+            self.find_each(needle.as_bytes(), |_| {
+                count += 1;
+                true
+            });
+        }
+        count
+    }
+
+    /// Finds the non-overlapping occurrences of *needle*, which must not be empty, and calls
+    /// *found* with the byte position of each. Stops when *found* returns false.
+    fn find_each<F: FnMut(usize) -> bool>(&self, needle: &[u8], mut found: F) {
+        // Knuth-Morris-Pratt, so the content is scanned once without copying it.
+        let mut fallback = vec![0; needle.len()];
+        let mut k = 0;
+        for i in 1..needle.len() {
+            while k > 0 && needle[i] != needle[k] {
+                k = fallback[k - 1];
+            }
+            if needle[i] == needle[k] {
+                k += 1;
+            }
+            fallback[i] = k;
+        }
+        let mut matched = 0;
+        for (i, byte) in self.slices().flat_map(str::bytes).enumerate() {
+            while matched > 0 && byte != needle[matched] {
+                matched = fallback[matched - 1];
+            }
+            if byte == needle[matched] {
+                matched += 1;
+            }
+            if matched == needle.len() {
+                if !found(i + 1 - needle.len()) {
+                    return;
+                }
+                matched = 0;
+            }
+        }
+    }
+
+    /// Returns the content between two byte positions, *end* excluded.
+    /// Both positions should be valid indices.
+    pub fn substring(&self, start: usize, end: usize) -> String {
+        self.char_indices()
+            .skip_while(|&(i, _)| i < start)
+            .take_while(|&(i, _)| i < end)
+            .map(|(_, c)| c)
+            .collect()
+    }
+
+    /// Returns the content between two character (not byte) indices, *char_end* excluded.
+    pub fn substring_chars(&self, char_start: usize, char_end: usize) -> Result<String, EditError> {
+        if char_start > char_end {
+            return Err(EditError::InvalidIndex);
+        }
+        let mut chars = self.chars();
+        let mut result = String::new();
+        for i in 0..char_end {
+            let c = chars.next().ok_or(EditError::InvalidIndex)?;
+            if i >= char_start {
+                result.push(c);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Checksum of the content, see `checksum`. Does not depend on the piece layout.
+    pub fn checksum(&self) -> u64 {
+        super::checksum(self.bytes())
+    }
+
     /// Checks if pos is in range and on a char boundary.
     pub fn valid_index(&self, pos: usize) -> bool {
-        if let Some((piece, len)) = self.piece_index(pos) {
-            let offset = self.pieces[piece].1 - (len - pos);
-            self.buffer.is_char_boundary(self.pieces[piece].0 + offset)
+        self.check_index(pos).is_ok()
+    }
+
+    /// Like `valid_index`, but tells `OutOfRange` and `NotCharBoundary` apart.
+    pub fn check_index(&self, pos: usize) -> Result<(), EditError> {
+        let (piece, len) = self.piece_index(pos).ok_or(EditError::OutOfRange)?;
+        let offset = self.pieces[piece].1 - (len - pos);
+        if self.buffer.is_char_boundary(self.pieces[piece].0 + offset) {
+            Ok(())
         } else {
-            false
+            Err(EditError::NotCharBoundary)
         }
     }
 
@@ -39,8 +426,14 @@ impl PieceTable {
     /// If a piece ends exactly before index pos, it counts as containing it. This is necessary to
     /// ensure the length of the file is a valid index for insertion.
     fn piece_index(&self, pos: usize) -> Option<(usize, usize)> {
-        let mut sum = 0;
-        for (i, (_, len)) in self.pieces.iter().enumerate() {
+        self.piece_index_from((0, 0), pos)
+    }
+
+    /// Like piece_index, but starts searching at a given piece. *start* is a pair of the piece
+    /// index and the total length of all pieces before it.
+    fn piece_index_from(&self, start: (usize, usize), pos: usize) -> Option<(usize, usize)> {
+        let (first, mut sum) = start;
+        for (i, (_, len)) in self.pieces.iter().enumerate().skip(first) {
             sum += len;
             if sum >= pos {
                 return Some((i, sum));
@@ -69,10 +462,75 @@ impl PieceTable {
     ///
     /// Can panic on unwrap if pos is not valid. Use valid_index to check beforehand!
     pub fn insert(&mut self, pos: usize, content: &str) {
+        self.insert_from((0, 0), pos, content);
+    }
+
+    /// Inserts *content* at a line and column as understood by `line_col_to_byte`. Returns the
+    /// byte position it was inserted at, or `EditError::InvalidIndex` if the line or column is
+    /// out of range.
+    pub fn insert_at_line_col(
+        &mut self,
+        line: usize,
+        col: usize,
+        content: &str,
+    ) -> Result<usize, EditError> {
+        let pos = self
+            .line_col_to_byte(line, col)
+            .ok_or(EditError::InvalidIndex)?;
+        self.insert(pos, content);
+        Ok(pos)
+    }
+
+    /// Insert a single character, like `insert` with a one-character string but without
+    /// allocating one.
+    ///
+    /// Can panic on unwrap if pos is not valid. Use valid_index to check beforehand!
+    pub fn insert_char(&mut self, pos: usize, c: char) {
+        self.insert(pos, c.encode_utf8(&mut [0; 4]));
+    }
+
+    /// Append text at the end of the content. Unlike insert, this never needs to look up a piece.
+    pub fn append(&mut self, content: &str) {
+        if content.is_empty() {
+            return;
+        }
+        let offset = self.buffer.len();
+        self.buffer.push_str(content);
+        let last = self.pieces.last_mut().unwrap();
+        if last.0 + last.1 == offset {
+            last.1 += content.len();
+        } else {
+            self.pieces.push((offset, content.len()));
+        }
+    }
+
+    /// Applies a batch of inserts, in order. Each position refers to the content after all
+    /// previous inserts of the batch, so the result is the same as calling insert for each.
+    /// Inserts sorted by position are faster, because the piece lookup continues from the
+    /// previous insert instead of starting over.
+    ///
+    /// Can panic if any position is not valid, see insert.
+    pub fn insert_many(&mut self, ops: &[(usize, &str)]) {
+        let total = ops.iter().map(|&(_, content)| content.len()).sum();
+        self.buffer.reserve(total);
+        let mut hint = (0, 0);
+        for &(pos, content) in ops {
+            if pos <= hint.1 {
+                hint = (0, 0);
+            }
+            hint = self.insert_from(hint, pos, content);
+        }
+    }
+
+    /// Insert text, searching for the piece containing pos from *start* (see piece_index_from).
+    /// Returns the piece the text was inserted at and the total length before it, which stays
+    /// valid as a *start* for positions after pos.
+    fn insert_from(&mut self, start: (usize, usize), pos: usize, content: &str) -> (usize, usize) {
         let offset = self.buffer.len();
         self.buffer.push_str(content);
 
-        let (piece, len) = self.piece_index(pos).unwrap();
+        let (piece, len) = self.piece_index_from(start, pos).unwrap();
+        let hint = (piece, len - self.pieces[piece].1);
 
         let is_end_of_piece = pos == len;
         let is_end_of_buffer = self.pieces[piece].0 + self.pieces[piece].1 == offset;
@@ -81,14 +539,14 @@ impl PieceTable {
         if is_end_of_buffer && is_end_of_piece {
             // just increase the length of the piece
             self.pieces[piece].1 += content.len();
-            return;
+            return hint;
         }
 
         let extra_piece = (offset, content.len());
         // optimized case: if inserting at the end of a piece, only need to insert one extra
         if is_end_of_piece {
             self.pieces.insert(piece + 1, extra_piece);
-            return;
+            return hint;
         }
 
         // otherwise: split the piece
@@ -97,6 +555,181 @@ impl PieceTable {
         let after_piece = (self.pieces[piece].0 + self.pieces[piece].1, overhead);
         self.pieces.insert(piece + 1, extra_piece);
         self.pieces.insert(piece + 2, after_piece);
+        hint
+    }
+
+    /// Removes everything from byte offset *at* to the end and returns it as a new table, with a
+    /// buffer of its own.
+    ///
+    /// Panics if *at* is not a valid index.
+    pub fn split_off(&mut self, at: usize) -> PieceTable {
+        assert!(self.valid_index(at), "split_off at invalid index {}", at);
+        let mut tail = String::with_capacity(self.len() - at);
+        let mut start = 0;
+        for slice in self.slices() {
+            let end = start + slice.len();
+            if end > at {
+                tail.push_str(&slice[at.saturating_sub(start)..]);
+            }
+            start = end;
+        }
+        if !tail.is_empty() {
+            self.delete(at, tail.len());
+        }
+        PieceTable::from(tail)
+    }
+
+    /// Replaces the whole content with *content* in a single piece. Reuses the allocation of the
+    /// buffer, which makes it cheaper than building a new table with `From`.
+    pub fn rebuild_from(&mut self, content: &str) {
+        self.buffer.clear();
+        self.buffer.push_str(content);
+        self.pieces.clear();
+        self.pieces.push((0, content.len()));
+    }
+
+    /// Copies the content into a new buffer of its own, as a single piece, so deleted text and
+    /// the slack of the old allocation no longer take up memory. Returns how many bytes the
+    /// buffer shrank by.
+    pub fn compact(&mut self) -> usize {
+        let before = self.buffer.len();
+        let mut buffer = String::with_capacity(self.len());
+        for slice in self.slices() {
+            buffer.push_str(slice);
+        }
+        self.pieces.clear();
+        self.pieces.push((0, buffer.len()));
+        self.buffer = buffer;
+        self.shrink_to_fit();
+        before - self.buffer.len()
+    }
+
+    /// Releases the spare capacity of the buffer and the pieces. Deleted text stays in the
+    /// buffer, use `compact` to drop it as well.
+    pub fn shrink_to_fit(&mut self) {
+        self.buffer.shrink_to_fit();
+        self.pieces.shrink_to_fit();
+    }
+
+    /// Keeps only the lines for which *f* returns true, and rebuilds the table as a single piece.
+    /// *f* gets each line without its newline. Retained lines stay separated by newlines, and the
+    /// content only ends with a newline if it did before.
+    pub fn retain_lines<F: Fn(&str) -> bool>(&mut self, f: F) {
+        let content = self.to_string();
+        let mut retained = String::with_capacity(content.len());
+        for line in content.split_inclusive('\n') {
+            if f(line.trim_end_matches('\n')) {
+                retained.push_str(line);
+            }
+        }
+        if !content.ends_with('\n') && retained.ends_with('\n') {
+            retained.pop();
+        }
+        self.rebuild_from(&retained);
+    }
+
+    /// Checks whether *pos* is between two grapheme clusters. Covers the rules for extended
+    /// grapheme clusters (Unicode Standard Annex #29) that matter most in practice: CR LF,
+    /// combining marks, variation selectors, emoji modifiers, tags and zero width joiner
+    /// sequences, and flags made of regional indicator pairs. Hangul syllables and Indic
+    /// conjuncts are not handled.
+    pub fn is_grapheme_boundary(&self, pos: usize) -> bool {
+        if !self.valid_index(pos) {
+            return false;
+        }
+        let mut before = None;
+        let mut after = None;
+        let mut regional_run = 0;
+        for (i, c) in self.char_indices() {
+            if i >= pos {
+                after = Some(c);
+                break;
+            }
+            regional_run = if is_regional_indicator(c) {
+                regional_run + 1
+            } else {
+                0
+            };
+            before = Some(c);
+        }
+        match (before, after) {
+            (Some(before), Some(after)) => grapheme_break(before, after, regional_run),
+            _ => true,
+        }
+    }
+
+    /// Checks that an edit action at *pos* starts and ends on grapheme boundaries.
+    pub fn check_graphemes(&self, action: &EditAction, pos: usize) -> Result<(), EditError> {
+        let valid = match *action {
+            EditAction::Insert(_) => self.is_grapheme_boundary(pos),
+            EditAction::InsertMany(ref inserts) => inserts
+                .iter()
+                .all(|&(pos, _)| self.is_grapheme_boundary(pos)),
+            EditAction::Noop => true,
+            ref delete => {
+                let len = delete.deleted_len().unwrap_or(0);
+                self.is_grapheme_boundary(pos) && self.is_grapheme_boundary(pos + len)
+            }
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(EditError::SplitsGrapheme)
+        }
+    }
+
+    /// Checks that an edit action can be applied at *pos*. A `DeleteExpecting` also needs to
+    /// match the text at *pos*. Positions that are out of range or not on a char boundary fail
+    /// with `OutOfRange` or `NotCharBoundary`, empty deletes and unordered inserts with
+    /// `InvalidIndex`.
+    pub fn check_edit(&self, action: &EditAction, pos: usize) -> Result<(), EditError> {
+        match *action {
+            EditAction::Insert(_) => self.check_index(pos)?,
+            EditAction::InsertMany(ref inserts) => {
+                for &(pos, _) in inserts {
+                    self.check_index(pos)?;
+                }
+                if !inserts.windows(2).all(|pair| pair[0].0 < pair[1].0) {
+                    return Err(EditError::InvalidIndex);
+                }
+            }
+            EditAction::Noop => {}
+            ref delete => {
+                let len = delete.deleted_len().unwrap_or(0);
+                if len == 0 {
+                    return Err(EditError::InvalidIndex);
+                }
+                self.check_index(pos)?;
+                self.check_index(pos + len)?;
+            }
+        }
+        match *action {
+            EditAction::DeleteExpecting(ref text)
+                if self.substring(pos, pos + text.len()) != *text =>
+            {
+                Err(EditError::Conflict)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Applies an edit action at *pos*, after checking that the affected range is valid.
+    pub fn apply_edit(&mut self, action: &EditAction, pos: usize) -> Result<(), EditError> {
+        self.check_edit(action, pos)?;
+        match *action {
+            EditAction::Insert(ref content) => self.insert(pos, content),
+            EditAction::Delete(len) => self.delete(pos, len),
+            EditAction::DeleteExpecting(ref text) => self.delete(pos, text.len()),
+            EditAction::InsertMany(ref inserts) => {
+                // Going backwards keeps the offsets of the remaining inserts valid.
+                for &(pos, ref content) in inserts.iter().rev() {
+                    self.insert(pos, content);
+                }
+            }
+            EditAction::Replace(len, ref content) => self.replace(pos, len, content),
+            EditAction::Noop => {}
+        }
+        Ok(())
     }
 
     /// Delete text from the editor
@@ -106,7 +739,11 @@ impl PieceTable {
     /// Use valid_index to check both beforehand!
     /// Can also panic if all pieces have length zero.
     /// Check this with `len > 0 && valid_index(pos + len)`.
+    /// Deleting nothing does nothing, even at the end of the content.
     pub fn delete(&mut self, pos: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
         let (piece, end) = self.piece_index_del(pos).unwrap();
 
         let overlap = pos + len > end;
@@ -152,6 +789,47 @@ impl PieceTable {
         }
     }
 
+    /// Replaces *len* bytes at *pos* with *content*, like a delete followed by an insert.
+    ///
+    /// Can panic like `delete` and `insert`, check with `check_edit` beforehand!
+    pub fn replace(&mut self, pos: usize, len: usize, content: &str) {
+        self.delete(pos, len);
+        self.insert(pos, content);
+    }
+
+    /// Merges neighbouring pieces that also follow each other in the buffer, and drops empty
+    /// pieces. The content stays the same. Returns the number of pieces removed.
+    pub fn merge_adjacent(&mut self) -> usize {
+        let before = self.pieces.len();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(before);
+        for &(offset, len) in self.pieces.iter().filter(|&&(_, len)| len > 0) {
+            match merged.last_mut() {
+                Some(last) if last.0 + last.1 == offset => last.1 += len,
+                _ => merged.push((offset, len)),
+            }
+        }
+        self.pieces = merged;
+        self.empty_check();
+        before - self.pieces.len()
+    }
+
+    /// Checks that every piece lies within the buffer and starts and ends on char boundaries,
+    /// and that there is at least one piece. Tables from outside, e.g. deserialized ones, need
+    /// this to hold, or displaying them panics.
+    pub fn validate_utf8_invariant(&self) -> Result<(), EditError> {
+        let valid = !self.pieces.is_empty()
+            && self.pieces.iter().all(|&(offset, len)| {
+                offset
+                    .checked_add(len)
+                    .is_some_and(|end| self.buffer.get(offset..end).is_some())
+            });
+        if valid {
+            Ok(())
+        } else {
+            Err(EditError::InvalidIndex)
+        }
+    }
+
     /// Checks that self.pieces is not empty. If it is empty, adds a (0, 0) piece.
     fn empty_check(&mut self) {
         if self.pieces.is_empty() {
@@ -160,15 +838,117 @@ impl PieceTable {
     }
 }
 
+fn is_regional_indicator(c: char) -> bool {
+    ('\u{1F1E6}'..='\u{1F1FF}').contains(&c)
+}
+
+/// Characters that belong to the grapheme of the character before them.
+fn is_extend(c: char) -> bool {
+    match c {
+        // combining marks
+        '\u{300}'..='\u{36F}'
+        | '\u{1AB0}'..='\u{1AFF}'
+        | '\u{1DC0}'..='\u{1DFF}'
+        | '\u{20D0}'..='\u{20FF}'
+        | '\u{FE20}'..='\u{FE2F}'
+        // variation selectors, zero width joiner, emoji modifiers, tags
+        | '\u{FE00}'..='\u{FE0F}'
+        | '\u{200D}'
+        | '\u{1F3FB}'..='\u{1F3FF}'
+        | '\u{E0020}'..='\u{E007F}' => true,
+        _ => false,
+    }
+}
+
+/// Whether there is a grapheme boundary between *before* and *after*. *regional_run* is the
+/// number of regional indicators in a row ending with *before*.
+fn grapheme_break(before: char, after: char, regional_run: usize) -> bool {
+    if before == '\r' && after == '\n' || is_extend(after) || before == '\u{200D}' {
+        false
+    } else if is_regional_indicator(before) && is_regional_indicator(after) {
+        // flags are pairs, counted from the start of the run
+        regional_run & 1 == 0
+    } else {
+        true
+    }
+}
+
+/// The serialized form of a piece table, before validating it.
+#[derive(Deserialize)]
+struct RawPieceTable {
+    buffer: String,
+    pieces: Vec<(usize, usize)>,
+}
+
+impl<'de> Deserialize<'de> for PieceTable {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = RawPieceTable::deserialize(deserializer)?;
+        let pt = PieceTable {
+            buffer: raw.buffer,
+            pieces: raw.pieces,
+        };
+        pt.validate_utf8_invariant()
+            .map_err(|e| de::Error::custom(format!("invalid piece table: {}", e)))?;
+        Ok(pt)
+    }
+}
+
+impl Default for PieceTable {
+    fn default() -> Self {
+        PieceTable::new()
+    }
+}
+
 impl fmt::Display for PieceTable {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for (offset, len) in self.pieces.iter() {
-            f.write_str(&self.buffer[*offset..offset + len])?;
+        for slice in self.slices() {
+            f.write_str(slice)?;
         }
         Ok(())
     }
 }
 
+/// Writing to a piece table appends to the end of its content. Only accepts valid UTF-8, a
+/// multi-byte character must not be split across writes.
+impl io::Write for PieceTable {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let content =
+            str::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.append(content);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Builds a piece table with one piece per item.
+impl<'a> FromIterator<&'a str> for PieceTable {
+    fn from_iter<I: IntoIterator<Item = &'a str>>(iter: I) -> Self {
+        let mut pt = PieceTable {
+            buffer: String::new(),
+            pieces: Vec::new(),
+        };
+        // empty pieces would only have to be skipped later
+        for part in iter.into_iter().filter(|part| !part.is_empty()) {
+            pt.pieces.push((pt.buffer.len(), part.len()));
+            pt.buffer.push_str(part);
+        }
+        pt.empty_check();
+        pt
+    }
+}
+
+/// Appends all items at the end.
+impl<'a> Extend<&'a str> for PieceTable {
+    fn extend<I: IntoIterator<Item = &'a str>>(&mut self, iter: I) {
+        for part in iter {
+            self.append(part);
+        }
+    }
+}
+
 impl<T: Into<String>> From<T> for PieceTable {
     fn from(s: T) -> Self {
         let buffer = s.into();
@@ -195,6 +975,122 @@ mod tests {
         assert_eq!("Hello World!", pt.to_string());
     }
 
+    #[test]
+    fn pt_insert_many() {
+        let ops: &[(usize, &str)] = &[
+            (0, "fox"),
+            (0, "the "),
+            (4, "quick "),
+            (10, "brown "),
+            (19, " jumps"),
+            (25, " over the dog"),
+            (34, " lazy"),
+            (0, "ä "),
+            (46, "!"),
+        ];
+        let mut sequential = PieceTable::from("");
+        for &(pos, content) in ops {
+            sequential.insert(pos, content);
+        }
+        let mut batch = PieceTable::from("");
+        batch.insert_many(ops);
+        assert_eq!(
+            sequential.to_string(),
+            "ä the quick brown fox jumps over the lazy dog!"
+        );
+        assert_eq!(batch.to_string(), sequential.to_string());
+    }
+
+    #[test]
+    fn pt_write() {
+        use std::io::Write;
+
+        let mut pt = PieceTable::new();
+        write!(pt, "x = {}", 42).unwrap();
+        assert_eq!("x = 42", pt.to_string());
+        pt.insert(0, "let ");
+        writeln!(pt, ";").unwrap();
+        assert_eq!("let x = 42;\n", pt.to_string());
+        assert_eq!(pt.len(), "let x = 42;\n".len());
+        assert!(pt.write(&[0xc3]).is_err());
+        assert_eq!("let x = 42;\n", pt.to_string());
+    }
+
+    #[test]
+    fn pt_from_iter() {
+        let lines = ["first line\n", "second line\n", "third"];
+        let mut pt: PieceTable = lines.iter().cloned().collect();
+        assert_eq!(pt.to_string(), lines.concat());
+        assert_eq!(pt.piece_count(), 3);
+        pt.extend(vec![" and", " more"]);
+        assert_eq!(pt.to_string(), "first line\nsecond line\nthird and more");
+
+        let empty: PieceTable = Vec::new().into_iter().collect();
+        assert!(empty.valid_index(0));
+        assert_eq!(empty.to_string(), "");
+    }
+
+    #[test]
+    fn pt_substring_chars() {
+        let mut pt = PieceTable::from("aäbü");
+        assert_eq!(pt.substring_chars(1, 3), Ok("äb".to_string()));
+        pt.insert("aä".len(), "ö");
+        assert_eq!(pt.chars().collect::<String>(), "aäöbü");
+        assert_eq!(pt.substring_chars(1, 4), Ok("äöb".to_string()));
+        assert_eq!(pt.substring_chars(0, 5), Ok("aäöbü".to_string()));
+        assert_eq!(pt.substring_chars(5, 5), Ok(String::new()));
+        assert_eq!(pt.substring_chars(4, 6), Err(EditError::InvalidIndex));
+        assert_eq!(pt.substring_chars(6, 6), Err(EditError::InvalidIndex));
+        assert_eq!(pt.substring_chars(3, 2), Err(EditError::InvalidIndex));
+    }
+
+    #[test]
+    fn pt_line_col() {
+        let mut pt = PieceTable::from("first\nzwei ä\n\nlast");
+        pt.insert("first\nzwei".len(), "te");
+        assert_eq!(pt.to_string(), "first\nzweite ä\n\nlast");
+        assert_eq!(pt.line_col_to_byte(0, 0), Some(0));
+        assert_eq!(pt.line_col_to_byte(1, 7), Some("first\nzweite ".len()));
+        assert_eq!(pt.line_col_to_byte(1, 8), Some("first\nzweite ä".len()));
+        assert_eq!(pt.line_col_to_byte(1, 9), None);
+        assert_eq!(pt.line_col_to_byte(3, 4), Some(pt.len()));
+        assert_eq!(pt.line_col_to_byte(4, 0), None);
+
+        assert_eq!(pt.char_at("first\nzwei".len()), Some('t'));
+        assert_eq!(pt.char_at("first\nzweite ".len() + 1), None);
+        assert_eq!(pt.char_at(pt.len()), None);
+
+        assert_eq!(pt.get_char_at_line_col(0, 4), Some('t'));
+        assert_eq!(pt.get_char_at_line_col(1, 4), Some('t'));
+        assert_eq!(pt.get_char_at_line_col(1, 6), Some(' '));
+        assert_eq!(pt.get_char_at_line_col(1, 7), Some('ä'));
+        assert_eq!(pt.get_char_at_line_col(1, 8), None);
+        assert_eq!(pt.get_char_at_line_col(2, 0), None);
+        assert_eq!(pt.get_char_at_line_col(3, 3), Some('t'));
+        assert_eq!(pt.get_char_at_line_col(3, 4), None);
+        assert_eq!(pt.get_char_at_line_col(7, 0), None);
+    }
+
+    #[test]
+    fn pt_insert_at_line_col() {
+        let mut pt = PieceTable::from("first\nzwei ä\nlast");
+        assert_eq!(pt.insert_at_line_col(1, 6, "ö"), Ok("first\nzwei ä".len()));
+        assert_eq!(
+            pt.insert_at_line_col(2, 4, "!"),
+            Ok("first\nzwei äö\nlast".len())
+        );
+        assert_eq!(pt.to_string(), "first\nzwei äö\nlast!");
+        assert_eq!(
+            pt.insert_at_line_col(1, 9, "x"),
+            Err(EditError::InvalidIndex)
+        );
+        assert_eq!(
+            pt.insert_at_line_col(3, 0, "x"),
+            Err(EditError::InvalidIndex)
+        );
+        assert_eq!(pt.to_string(), "first\nzwei äö\nlast!");
+    }
+
     #[test]
     fn pt_delete() {
         let mut pt = PieceTable::from("the quick brown fox jumps over the lazy dog");
@@ -214,6 +1110,17 @@ mod tests {
         assert_eq!("fog", pt.to_string());
     }
 
+    #[test]
+    fn pt_line_count() {
+        assert_eq!(PieceTable::new().line_count(), 1);
+        let mut pt: PieceTable = vec!["one\ntw", "o\n", "\nfour"].into_iter().collect();
+        assert_eq!(pt.line_count(), 4);
+        assert!(pt.line_col_to_byte(3, 0).is_some());
+        assert!(pt.line_col_to_byte(4, 0).is_none());
+        pt.append("\n");
+        assert_eq!(pt.line_count(), 5);
+    }
+
     #[test]
     fn pt_valid_index() {
         assert!(PieceTable::new().valid_index(0));
@@ -228,4 +1135,401 @@ mod tests {
         assert!(pt.valid_index(2));
         assert!(!pt.valid_index(1));
     }
+
+    #[test]
+    fn pt_apply_edit() {
+        let mut pt = PieceTable::from("Hello!");
+        let insert = EditAction::Insert(" World".to_string());
+        assert_eq!(pt.apply_edit(&insert, 5), Ok(()));
+        assert_eq!(pt.to_string(), "Hello World!");
+        assert_eq!(pt.apply_edit(&EditAction::Delete(1), 11), Ok(()));
+        assert_eq!(pt.apply_edit(&EditAction::Noop, 11), Ok(()));
+        assert_eq!(pt.to_string(), "Hello World");
+        let replace = EditAction::Replace(5, "there".to_string());
+        assert_eq!(pt.apply_edit(&replace, 6), Ok(()));
+        assert_eq!(pt.to_string(), "Hello there");
+        let everything = EditAction::Replace(11, "Bye".to_string());
+        assert_eq!(pt.apply_edit(&everything, 0), Ok(()));
+        assert_eq!(pt.to_string(), "Bye");
+
+        assert_eq!(pt.apply_edit(&insert, 4), Err(EditError::OutOfRange));
+        assert_eq!(pt.apply_edit(&replace, 0), Err(EditError::OutOfRange));
+        let empty = EditAction::Replace(0, "x".to_string());
+        assert_eq!(pt.apply_edit(&empty, 0), Err(EditError::InvalidIndex));
+        let mut pt = PieceTable::from("Hello World");
+
+        assert_eq!(pt.apply_edit(&insert, 12), Err(EditError::OutOfRange));
+        assert_eq!(
+            pt.apply_edit(&EditAction::Delete(2), 10),
+            Err(EditError::OutOfRange)
+        );
+        assert_eq!(
+            pt.apply_edit(&EditAction::Delete(0), 0),
+            Err(EditError::InvalidIndex)
+        );
+        let mut pt = PieceTable::from("ä");
+        assert_eq!(
+            pt.apply_edit(&EditAction::Delete(1), 0),
+            Err(EditError::NotCharBoundary)
+        );
+        assert_eq!(pt.to_string(), "ä");
+    }
+
+    #[test]
+    fn pt_rebuild_from() {
+        let mut pt = PieceTable::from("Hello");
+        pt.insert(5, " World");
+        pt.delete(0, 1);
+        pt.rebuild_from("fresh ä content");
+        let fresh = PieceTable::from("fresh ä content");
+        assert_eq!(pt.to_string(), fresh.to_string());
+        assert_eq!(pt.buffer, fresh.buffer);
+        assert_eq!(pt.pieces, fresh.pieces);
+
+        pt.rebuild_from("");
+        assert_eq!(pt.to_string(), "");
+        assert_eq!(pt.piece_count(), 1);
+        assert!(pt.valid_index(0));
+        pt.insert(0, "again");
+        assert_eq!(pt.to_string(), "again");
+    }
+
+    #[test]
+    fn pt_retain_lines() {
+        let not_blank = |line: &str| !line.trim().is_empty();
+        let mut pt = PieceTable::from("first\n\n  \nsecond\n");
+        pt.insert(6, "\n");
+        pt.retain_lines(not_blank);
+        assert_eq!(pt.to_string(), "first\nsecond\n");
+        assert_eq!(pt.piece_count(), 1);
+
+        // no newline is added at the end, or left when the last line is removed
+        let mut pt = PieceTable::from("\nfirst\n\nlast");
+        pt.retain_lines(not_blank);
+        assert_eq!(pt.to_string(), "first\nlast");
+        pt.retain_lines(|line| line != "last");
+        assert_eq!(pt.to_string(), "first");
+        pt.retain_lines(|_| false);
+        assert_eq!(pt.to_string(), "");
+        assert!(pt.valid_index(0));
+    }
+
+    #[test]
+    fn pt_char_byte_conversion() {
+        let mut pt = PieceTable::from("aä€");
+        pt.insert(3, "\u{1F600}b");
+        // a ä 😀 b €, with the emoji in a piece of its own
+        let offsets = [0, 1, 3, 7, 8, 11];
+        for (n, &pos) in offsets.iter().enumerate() {
+            assert_eq!(pt.char_to_byte(n), Some(pos));
+            assert_eq!(pt.byte_to_char(pos), Some(n));
+        }
+        assert_eq!(pt.char_to_byte(6), None);
+        assert_eq!(pt.byte_to_char(2), None);
+        assert_eq!(pt.byte_to_char(5), None);
+        assert_eq!(pt.byte_to_char(12), None);
+        assert_eq!(PieceTable::new().char_to_byte(0), Some(0));
+        assert_eq!(PieceTable::new().byte_to_char(0), Some(0));
+    }
+
+    #[test]
+    fn pt_line_byte_ranges() {
+        let mut pt = PieceTable::from("first\nsecond ä\n");
+        pt.insert(3, "\nin");
+        pt.insert(pt.len(), "\nlast");
+        let text = pt.to_string();
+        let ranges = pt.line_byte_ranges();
+        assert_eq!(ranges.len(), pt.line_count());
+        let lines: Vec<_> = ranges
+            .iter()
+            .map(|&(start, end)| pt.substring(start, end))
+            .collect();
+        assert_eq!(lines, text.split('\n').collect::<Vec<_>>());
+        assert_eq!(ranges[0], (0, 3));
+
+        assert_eq!(PieceTable::new().line_byte_ranges(), vec![(0, 0)]);
+        assert_eq!(
+            PieceTable::from("a\n").line_byte_ranges(),
+            vec![(0, 1), (2, 2)]
+        );
+    }
+
+    #[test]
+    fn pt_merge_adjacent() {
+        let mut pt = PieceTable::from("one two three four");
+        // inserting and deleting again leaves the halves of the split piece behind
+        for &pos in &[3, 7, 13] {
+            pt.insert(pos, "!");
+            pt.delete(pos, 1);
+        }
+        pt.insert(pt.len(), " five");
+        assert_eq!(pt.to_string(), "one two three four five");
+        let pieces = pt.piece_count();
+        assert!(pieces > 4);
+
+        assert_eq!(pt.merge_adjacent(), pieces - 2);
+        assert_eq!(pt.pieces, [(0, 18), (21, 5)]);
+        assert_eq!(pt.to_string(), "one two three four five");
+        assert_eq!(pt.merge_adjacent(), 0);
+
+        let mut empty: PieceTable = vec!["", ""].into_iter().collect();
+        empty.merge_adjacent();
+        assert!(empty.valid_index(0));
+    }
+
+    #[test]
+    fn pt_deserialize() {
+        use serde_json;
+
+        let mut pt = PieceTable::from("ünïcode");
+        pt.insert(3, "x");
+        let json = serde_json::to_string(&pt).unwrap();
+        let restored: PieceTable = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.to_string(), pt.to_string());
+        assert_eq!(restored.pieces, pt.pieces);
+
+        // inside the ü, past the end, overflowing and without pieces
+        for pieces in &["[[1,2]]", "[[0,10]]", "[[1,18446744073709551615]]", "[]"] {
+            let json = format!("{{\"buffer\":\"ünïcode\",\"pieces\":{}}}", pieces);
+            let err = serde_json::from_str::<PieceTable>(&json).err().unwrap();
+            assert!(err
+                .to_string()
+                .starts_with("invalid piece table: invalid index"));
+        }
+    }
+
+    #[test]
+    fn pt_split_off() {
+        let mut pt: PieceTable = vec!["first ", "sëcond ", "third"].into_iter().collect();
+        let tail = pt.split_off("first së".len());
+        assert_eq!(pt.to_string(), "first së");
+        assert_eq!(tail.to_string(), "cond third");
+        assert_eq!(tail.buffer, "cond third");
+        assert_eq!(tail.piece_count(), 1);
+
+        let rest = pt.split_off(0);
+        assert_eq!(rest.to_string(), "first së");
+        assert_eq!(pt.to_string(), "");
+        assert_eq!(pt.split_off(0).to_string(), "");
+        pt.insert(0, "new");
+        assert_eq!(pt.to_string(), "new");
+    }
+
+    #[test]
+    #[should_panic]
+    fn pt_split_off_invalid() {
+        PieceTable::from("ä").split_off(1);
+    }
+
+    #[test]
+    fn pt_contains() {
+        let pt: PieceTable = vec!["hel", "lo wo", "rld", " aaab"].into_iter().collect();
+        assert!(pt.contains("lo w"));
+        assert!(pt.contains("o wor"));
+        assert!(pt.contains("hello world aaab"));
+        assert!(pt.contains("aab"));
+        assert!(pt.contains(""));
+        assert!(!pt.contains("worlds"));
+        assert!(!pt.contains("aaaa"));
+        assert!(!PieceTable::new().contains("a"));
+    }
+
+    #[test]
+    fn pt_count_matches() {
+        let pt: PieceTable = vec!["ab", "cab", "c", "a", "bca", "bc"]
+            .into_iter()
+            .collect();
+        assert_eq!(pt.to_string(), "abcabcabcabc");
+        assert_eq!(pt.count_matches("abc"), 4);
+        assert_eq!(pt.count_matches("cab"), 3);
+        assert_eq!(pt.count_matches("abcabc"), 2);
+        assert_eq!(pt.count_matches("abcd"), 0);
+        assert_eq!(pt.count_matches(""), 0);
+        let pt: PieceTable = vec!["a", "aa", "a", "a"].into_iter().collect();
+        assert_eq!(pt.count_matches("aa"), pt.find_all("aa").len());
+        assert_eq!(pt.count_matches("aa"), 2);
+    }
+
+    #[test]
+    fn pt_equal_content() {
+        let pt: PieceTable = vec!["hä", "llo", "", " wörld"].into_iter().collect();
+        let cases = [
+            "hällo wörld",
+            "hällo wörlds",
+            "hällo wörl",
+            "hallo wörld",
+            "hällo wörle",
+            "",
+        ];
+        for expected in &cases {
+            assert_eq!(
+                pt.equal_content(expected),
+                pt.to_string() == *expected,
+                "{}",
+                expected
+            );
+        }
+        assert!(pt.equal_content("hällo wörld"));
+        assert!(PieceTable::new().equal_content(""));
+        assert!(!PieceTable::new().equal_content("a"));
+    }
+
+    #[test]
+    fn pt_shrink_to_fit() {
+        let mut pt = PieceTable::from("churn");
+        for i in 0..200 {
+            pt.insert(i % 5, "some text ");
+            pt.delete(0, 3);
+        }
+        let content = pt.to_string();
+        let (buffer_capacity, piece_capacity) = (pt.buffer.capacity(), pt.pieces.capacity());
+        assert!(piece_capacity > 100);
+
+        pt.shrink_to_fit();
+        assert_eq!(pt.buffer.capacity(), pt.buffer.len());
+        assert_eq!(pt.pieces.capacity(), pt.pieces.len());
+        assert!(pt.buffer.capacity() <= buffer_capacity);
+        assert_eq!(pt.to_string(), content);
+
+        pt.compact();
+        assert_eq!(pt.buffer.capacity(), content.len());
+        assert_eq!(pt.pieces.capacity(), 1);
+        assert!(pt.buffer.capacity() < buffer_capacity);
+        assert_eq!(pt.to_string(), content);
+    }
+
+    #[test]
+    fn pt_replace_all() {
+        let parts = vec!["The quick f", "ox jumps over the lazy fox. ", "foxfo", "x"];
+        let mut pt: PieceTable = parts.into_iter().collect();
+        assert_eq!(pt.replace_all("fox", "cat"), 4);
+        assert_eq!(
+            pt.to_string(),
+            "The quick cat jumps over the lazy cat. catcat"
+        );
+        assert_eq!(pt.replace_all("cat", "wolf"), 4);
+        assert_eq!(
+            pt.to_string(),
+            "The quick wolf jumps over the lazy wolf. wolfwolf"
+        );
+        assert_eq!(pt.replace_all("wolf", ""), 4);
+        assert_eq!(pt.to_string(), "The quick  jumps over the lazy . ");
+        assert_eq!(pt.replace_all("fox", "cat"), 0);
+        assert_eq!(pt.replace_all("", "cat"), 0);
+
+        let mut pt = PieceTable::from("aaaa");
+        assert_eq!(pt.replace_all("aa", "b"), 2);
+        assert_eq!(pt.to_string(), "bb");
+        let mut pt = PieceTable::from("ä");
+        assert_eq!(pt.replace_all("ä", "ae"), 1);
+        assert_eq!(pt.to_string(), "ae");
+    }
+
+    #[test]
+    fn pt_stats() {
+        let mut pt = PieceTable::from("0123456789");
+        // alternating inserts split the table into tiny pieces
+        for i in 0..5 {
+            pt.insert(2 * i + 1, "x");
+        }
+        pt.delete(0, 1);
+        let stats = pt.stats();
+        assert_eq!(stats.piece_count, pt.piece_count());
+        assert_eq!(stats.buffer_len, 15);
+        assert_eq!(stats.logical_len, 14);
+        let expected: BTreeMap<_, _> = vec![(1, 9), (4, 1)].into_iter().collect();
+        assert_eq!(stats.piece_lengths, expected);
+        assert_eq!(PieceTable::new().stats().piece_lengths[&0], 1);
+    }
+
+    #[test]
+    fn pt_delete_to_end() {
+        let parts = ["abc", "", "def", "ghi", ""];
+        let text = parts.concat();
+        for pos in 0..=text.len() {
+            let mut pt: PieceTable = parts.iter().cloned().collect();
+            pt.delete(pos, text.len() - pos);
+            assert_eq!(pt.to_string(), &text[..pos]);
+            assert!(pt.valid_index(pos));
+            assert!(
+                pt.pieces.iter().all(|&(_, len)| len > 0) || pt.pieces == [(0, 0)],
+                "{:?}",
+                pt.pieces
+            );
+            pt.insert(pos, "z");
+            assert_eq!(pt.to_string(), text[..pos].to_string() + "z");
+        }
+
+        let mut pt = PieceTable::from("xyz");
+        pt.append("");
+        pt.insert(3, "!");
+        pt.delete(0, 4);
+        assert_eq!(pt.pieces, [(0, 0)]);
+        pt.delete(0, 0);
+        assert_eq!(pt.to_string(), "");
+    }
+
+    #[test]
+    fn pt_insert_char() {
+        let mut by_char = PieceTable::from("a piece table");
+        let mut by_str = PieceTable::from("a piece table");
+        for &(pos, c) in &[(1, 'x'), (0, 'ä'), (15, '\u{1F600}'), (3, '\n'), (2, 'é')] {
+            by_char.insert_char(pos, c);
+            by_str.insert(pos, &c.to_string());
+            assert_eq!(by_char.to_string(), by_str.to_string());
+        }
+        assert_eq!(by_char.pieces, by_str.pieces);
+        assert_eq!(by_char.buffer, by_str.buffer);
+    }
+
+    #[test]
+    fn pt_bytes() {
+        let mut pt = PieceTable::from("bytes of a piece table");
+        pt.insert(9, "ä ");
+        pt.delete(0, 2);
+        pt.insert(pt.len(), "\u{1F600}");
+        assert!(pt.piece_count() > 1);
+        assert_eq!(pt.bytes().collect::<Vec<u8>>(), pt.to_string().into_bytes());
+    }
+
+    #[test]
+    fn pt_from_reader() {
+        let content = "line one\nlïne two\n";
+        let pt = PieceTable::from_reader(content.as_bytes()).unwrap();
+        let expected = PieceTable::from(content);
+        assert_eq!(pt.to_string(), expected.to_string());
+        assert_eq!(pt.pieces, expected.pieces);
+        assert_eq!(PieceTable::from_reader(&b""[..]).unwrap().pieces, [(0, 0)]);
+
+        let err = PieceTable::from_reader(&b"invalid \xff"[..]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn pt_grapheme_boundary() {
+        let text = "e\u{301}\r\n\u{1F44D}\u{1F3FD}\u{1F468}\u{200D}\u{1F469}\u{1F1E9}\u{1F1EA}\u{1F1EB}\u{1F1F7}x";
+        let pt = PieceTable::from(text);
+        let boundaries: Vec<_> = (0..=text.len())
+            .filter(|&pos| pt.is_grapheme_boundary(pos))
+            .collect();
+        let mut expected = vec![0];
+        for grapheme in &[
+            "e\u{301}",
+            "\r\n",
+            "\u{1F44D}\u{1F3FD}",
+            "\u{1F468}\u{200D}\u{1F469}",
+            "\u{1F1E9}\u{1F1EA}",
+            "\u{1F1EB}\u{1F1F7}",
+            "x",
+        ] {
+            let last = *expected.last().unwrap();
+            expected.push(last + grapheme.len());
+        }
+        assert_eq!(boundaries, expected);
+        assert_eq!(
+            pt.check_graphemes(&EditAction::Delete(1), 0),
+            Err(EditError::SplitsGrapheme)
+        );
+        assert_eq!(pt.check_graphemes(&EditAction::Delete(3), 0), Ok(()));
+    }
 }